@@ -1,8 +1,15 @@
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fmt::{Display, Formatter};
 use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::thread;
+use std::time::{Duration, SystemTime};
 
 use dirs;
 use lazy_static::lazy_static;
@@ -11,28 +18,130 @@ use crate::nix;
 use crate::proc::CommandError;
 
 /// This uniquely identifies this program (nix-query) so that our cache files
-/// don't conflict with anything else.
+/// don't conflict with anything else. Older versions kept the cache in a
+/// single file named with this in the cache root; see `migrate_legacy_cache`.
 const UUID: &str = "bfe01d7a-c700-4529-acf1-88065df2cd25";
 
+/// Environment variable naming the cache directory.
+pub const CACHE_DIR_VAR: &str = "NIX_QUERY_CACHE";
+
+/// File name of the attribute list within the cache directory.
+const ATTRS_FILE: &str = "attrs";
+
 lazy_static! {
-    static ref CACHE_PATH: Option<PathBuf> = {
-        Some(
-            [
-                dirs::cache_dir()?,
-                format!("nix-query-{}.cache", UUID).into(),
-            ]
-            .iter()
-            .collect(),
-        )
-    };
+    static ref CACHE_DIR_OVERRIDE: RwLock<Option<PathBuf>> = RwLock::new(None);
 }
 
 pub const NIX_ATTRS_COUNT_ESTIMATE: usize = 100_000;
 /// Bytes.
 pub const NIX_ATTRS_FILE_SIZE_ESTIMATE: usize = 5_000_000;
 
+/// Sets the cache directory, taking precedence over the environment. This is
+/// how `--cache-path` is implemented.
+pub fn set_cache_dir(dir: PathBuf) {
+    *CACHE_DIR_OVERRIDE.write().unwrap() = Some(dir);
+}
+
+/// Picks the cache directory from, in order of precedence: an explicit
+/// override, `$NIX_QUERY_CACHE`, `$XDG_CACHE_HOME/nix-query`, and the
+/// platform's cache directory (`dirs::cache_dir()`) joined with `nix-query`.
+fn resolve_cache_dir(
+    explicit: Option<PathBuf>,
+    nix_query_cache: Option<OsString>,
+    xdg_cache_home: Option<OsString>,
+    platform_cache_dir: Option<PathBuf>,
+) -> Option<PathBuf> {
+    let non_empty = |s: &OsString| !s.is_empty();
+    explicit
+        .or_else(|| nix_query_cache.filter(non_empty).map(PathBuf::from))
+        .or_else(|| {
+            xdg_cache_home
+                .filter(non_empty)
+                .map(|dir| PathBuf::from(dir).join("nix-query"))
+        })
+        .or_else(|| platform_cache_dir.map(|dir| dir.join("nix-query")))
+}
+
+/// The directory all of nix-query's cache files live in.
+pub fn cache_dir() -> Option<PathBuf> {
+    resolve_cache_dir(
+        CACHE_DIR_OVERRIDE.read().unwrap().clone(),
+        env::var_os(CACHE_DIR_VAR),
+        env::var_os("XDG_CACHE_HOME"),
+        dirs::cache_dir(),
+    )
+}
+
+fn cache_path() -> Result<PathBuf, CacheIoError> {
+    cache_dir()
+        .map(|dir| dir.join(ATTRS_FILE))
+        .ok_or(CacheIoError::NoCachePath)
+}
+
+fn legacy_cache_path() -> Option<PathBuf> {
+    Some(dirs::cache_dir()?.join(format!("nix-query-{}.cache", UUID)))
+}
+
+/// Moves a cache file left by an older version of nix-query into the cache
+/// directory, unless a cache already exists there. Returns true if a cache
+/// was migrated.
+pub fn migrate_legacy_cache() -> Result<bool, CacheIoError> {
+    let legacy = match legacy_cache_path() {
+        Some(path) if path.is_file() => path,
+        _ => return Ok(false),
+    };
+    let path = cache_path()?;
+    if path.is_file() {
+        return Ok(false);
+    }
+    fs::create_dir_all(path.parent().ok_or(CacheIoError::NoCachePath)?)?;
+    fs::rename(legacy, path)?;
+    Ok(true)
+}
+
 pub fn cache_exists() -> bool {
-    CACHE_PATH.as_ref().map(|p| p.is_file()).unwrap_or(false)
+    cache_path().map(|p| p.is_file()).unwrap_or(false)
+}
+
+/// How long ago the cache was written, or `None` if there's no cache yet.
+pub fn cache_age() -> Result<Option<Duration>, CacheIoError> {
+    // A modification time in the future means the cache is brand new.
+    Ok(cache_mtime()?.map(|modified| modified.elapsed().unwrap_or_default()))
+}
+
+/// When the cache was last written, or `None` if there's no cache yet.
+pub fn cache_mtime() -> Result<Option<SystemTime>, CacheIoError> {
+    file_mtime(&cache_path()?)
+}
+
+/// When the file at `path` was last modified, or `None` if it doesn't exist.
+fn file_mtime(path: &Path) -> Result<Option<SystemTime>, CacheIoError> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(Some(metadata.modified()?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Calls `on_change` now and then every time the cache is written, checking
+/// every `interval`. Only returns if `on_change` fails.
+pub fn watch_cache<E>(
+    interval: Duration,
+    mut on_change: impl FnMut() -> Result<(), E>,
+) -> Result<(), E>
+where
+    E: From<CacheIoError>,
+{
+    let mut last = cache_mtime()?;
+    on_change()?;
+    loop {
+        thread::sleep(interval);
+        let modified = cache_mtime()?;
+        if modified != last {
+            last = modified;
+            on_change()?;
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -40,6 +149,36 @@ pub enum CacheIoError {
     NoCachePath,
     Command(CommandError),
     Io(Box<io::Error>),
+    /// Populating the cache found no packages; nothing was written.
+    EmptyPopulation,
+    /// With `PopulateOptions::strict`, these attributes' metadata didn't
+    /// match our schema; nothing was written.
+    Unparseable(Vec<(String, String)>),
+}
+
+impl Display for CacheIoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheIoError::NoCachePath => write!(
+                f,
+                "Couldn't find a directory for the cache; set ${}",
+                CACHE_DIR_VAR
+            ),
+            CacheIoError::Command(e) => write!(f, "{}", e),
+            CacheIoError::Io(e) => write!(f, "Couldn't read or write the cache: {}", e),
+            CacheIoError::EmptyPopulation => {
+                write!(
+                    f,
+                    "Nix didn't list any packages, so the cache wasn't written"
+                )
+            }
+            CacheIoError::Unparseable(unparsed) => write!(
+                f,
+                "{} packages don't match the expected schema",
+                unparsed.len()
+            ),
+        }
+    }
 }
 
 impl From<io::Error> for CacheIoError {
@@ -48,8 +187,9 @@ impl From<io::Error> for CacheIoError {
     }
 }
 
-pub fn clear_cache() -> Result<(), CacheIoError> {
-    match fs::remove_file(CACHE_PATH.as_ref().ok_or(CacheIoError::NoCachePath)?) {
+/// Removes a file, treating a file that doesn't exist as success.
+fn remove_file_if_exists(path: &Path) -> Result<(), CacheIoError> {
+    match fs::remove_file(path) {
         Ok(()) => Ok(()),
         Err(io_err) =>
         // If we try to remove the cache file but it doesn't exist yet, that's OK.
@@ -63,25 +203,857 @@ pub fn clear_cache() -> Result<(), CacheIoError> {
     }
 }
 
-pub fn write_cache(nix_attrs: &[u8]) -> Result<(), CacheIoError> {
-    File::create(CACHE_PATH.as_ref().ok_or(CacheIoError::NoCachePath)?)?
-        .write_all(nix_attrs)
-        .map_err(Into::into)
+/// Whether `name` is one of the files in the cache directory that
+/// `clear_cache` removes: the attribute list, its temporary files, and the
+/// remembered backend.
+fn is_cache_file(name: &str) -> bool {
+    name == ATTRS_FILE
+        || name == BACKEND_FILE
+        || (name.starts_with(ATTRS_FILE) && name.ends_with(".tmp"))
 }
 
-pub fn read_cache() -> Result<String, CacheIoError> {
-    let mut cache_file = File::open(CACHE_PATH.as_ref().ok_or(CacheIoError::NoCachePath)?)?;
+/// Removes the files nix-query keeps in the cache directory, except the
+/// favorites (which `forget_favorites` is for). Anything else is left alone,
+/// as is the directory itself, which may be shared with other programs.
+pub fn clear_cache() -> Result<(), CacheIoError> {
+    clear_cache_dir(&cache_dir().ok_or(CacheIoError::NoCachePath)?)
+}
+
+fn clear_cache_dir(dir: &Path) -> Result<(), CacheIoError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        if is_cache_file(name) && !entry.file_type()?.is_dir() {
+            remove_file_if_exists(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// File name of the remembered backend within the cache directory.
+const BACKEND_FILE: &str = "backend";
+
+/// How long to remember the detected backend before detecting it again.
+const BACKEND_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn backend_path() -> Result<PathBuf, CacheIoError> {
+    cache_dir()
+        .map(|dir| dir.join(BACKEND_FILE))
+        .ok_or(CacheIoError::NoCachePath)
+}
+
+/// Reads the remembered backend, if it was detected less than `BACKEND_TTL`
+/// ago. The file contains the backend and Nix version, like `nix 2.18.1`.
+fn read_backend_info() -> Option<nix::BackendInfo> {
+    let path = backend_path().ok()?;
+    let age = fs::metadata(&path).ok()?.modified().ok()?.elapsed().ok()?;
+    if age > BACKEND_TTL {
+        return None;
+    }
+    let contents = fs::read_to_string(path).ok()?;
+    let mut words = contents.split_whitespace();
+    Some(nix::BackendInfo {
+        backend: words.next()?.parse().ok()?,
+        version: words.next().unwrap_or_default().to_string(),
+    })
+}
+
+/// The backend to query Nix with, detected at most once every `BACKEND_TTL`.
+pub fn backend_info() -> Result<nix::BackendInfo, CacheIoError> {
+    if let Some(info) = read_backend_info() {
+        return Ok(info);
+    }
+    let info = nix::detect_backend().map_err(CacheIoError::Command)?;
+    let path = backend_path()?;
+    fs::create_dir_all(path.parent().ok_or(CacheIoError::NoCachePath)?)?;
+    fs::write(path, format!("{} {}\n", info.backend.name(), info.version))?;
+    Ok(info)
+}
+
+/// File name of the list of frequently selected attributes within the cache
+/// directory.
+const FAVORITES_FILE: &str = "favorites";
+
+/// How many favorites to remember.
+const MAX_FAVORITES: usize = 100;
+
+/// An attribute the user has selected in the fuzzy-finder.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Favorite {
+    pub attr: String,
+    /// How many times it's been selected.
+    pub count: usize,
+}
+
+fn favorites_path() -> Result<PathBuf, CacheIoError> {
+    cache_dir()
+        .map(|dir| dir.join(FAVORITES_FILE))
+        .ok_or(CacheIoError::NoCachePath)
+}
+
+/// Parses the favorites file, which has one `<count> <attr>` line per
+/// favorite, best first. Malformed lines are skipped.
+fn parse_favorites(contents: &str) -> Vec<Favorite> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (count, attr) = line.split_once(' ')?;
+            Some(Favorite {
+                attr: attr.to_string(),
+                count: count.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// Moves the given attributes to the front of `favorites` (adding them if
+/// needed) and bumps their counts, then ranks by count. Among favorites with
+/// the same count, the most recently selected comes first.
+fn bump_favorites(mut favorites: Vec<Favorite>, attrs: &[String]) -> Vec<Favorite> {
+    for attr in attrs {
+        let count = match favorites.iter().position(|fav| &fav.attr == attr) {
+            Some(i) => favorites.remove(i).count,
+            None => 0,
+        };
+        favorites.insert(
+            0,
+            Favorite {
+                attr: attr.clone(),
+                count: count + 1,
+            },
+        );
+    }
+    favorites.sort_by_key(|fav| std::cmp::Reverse(fav.count));
+    favorites.truncate(MAX_FAVORITES);
+    favorites
+}
+
+/// The user's favorite attributes, best first.
+pub fn read_favorites() -> Result<Vec<Favorite>, CacheIoError> {
+    match fs::read_to_string(favorites_path()?) {
+        Ok(contents) => Ok(parse_favorites(&contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Remembers that the given attributes were selected.
+pub fn record_favorites(attrs: &[String]) -> Result<(), CacheIoError> {
+    if attrs.is_empty() {
+        return Ok(());
+    }
+    let favorites = bump_favorites(read_favorites()?, attrs);
+    let path = favorites_path()?;
+    fs::create_dir_all(path.parent().ok_or(CacheIoError::NoCachePath)?)?;
+    let contents: String = favorites
+        .iter()
+        .map(|fav| format!("{} {}\n", fav.count, fav.attr))
+        .collect();
+    fs::write(path, contents).map_err(Into::into)
+}
+
+/// Forgets every favorite.
+pub fn forget_favorites() -> Result<(), CacheIoError> {
+    remove_file_if_exists(&favorites_path()?)
+}
+
+/// Prefix of the cache's first line, which records how the cache was built.
+const HEADER_PREFIX: &str = "#nix-query";
+
+/// Version of the cache's format. Bump this whenever the cache's layout
+/// changes, so caches written by other versions of nix-query are rebuilt
+/// rather than misread.
+pub const CACHE_FORMAT_VERSION: u32 = 1;
+
+/// Records how the cache was built, so we can tell whether it satisfies a
+/// request.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CacheHeader {
+    /// `CACHE_FORMAT_VERSION` of the nix-query that wrote the cache, or 0 if
+    /// it predates format versions.
+    pub version: u32,
+    /// The cache has the extra columns written by `PopulateOptions::rich`.
+    pub rich: bool,
+    /// The cache leaves out `nix::EXTRA_PACKAGE_SETS`.
+    pub skip_extra_attrs: bool,
+    /// The cache includes "private" attributes, like `nixpkgs._sources`.
+    pub include_private: bool,
+    /// Where the packages came from, from `nix::source_descriptor`, if known.
+    pub source: Option<String>,
+}
+
+impl CacheHeader {
+    pub fn new(opts: &nix::PopulateOptions) -> Self {
+        CacheHeader {
+            version: CACHE_FORMAT_VERSION,
+            rich: opts.rich,
+            skip_extra_attrs: opts.skip_extra_attrs,
+            include_private: opts.include_private,
+            source: None,
+        }
+    }
+
+    pub fn with_source(self, source: Option<String>) -> Self {
+        CacheHeader { source, ..self }
+    }
+
+    /// Parses a header line. Unknown keys are ignored.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut ret = CacheHeader::default();
+        for pair in line.strip_prefix(HEADER_PREFIX)?.split_whitespace() {
+            match pair.split_once('=') {
+                Some(("version", version)) => ret.version = version.parse().unwrap_or(0),
+                Some(("mode", mode)) => ret.rich = mode == "rich",
+                Some(("extras", extras)) => ret.skip_extra_attrs = extras == "no",
+                Some(("private", private)) => ret.include_private = private == "yes",
+                Some(("source", source)) => ret.source = Some(source.to_string()),
+                _ => {}
+            }
+        }
+        Some(ret)
+    }
+
+    /// Whether the cache was written in the format this version of
+    /// nix-query reads. Older and newer formats both need a rebuild.
+    pub fn is_current_format(&self) -> bool {
+        self.version == CACHE_FORMAT_VERSION
+    }
+
+    /// Whether a cache built like this can be used to answer a request with
+    /// the given options. A rich cache can stand in for a plain one, but not
+    /// the other way around. The extra and private attributes must be
+    /// included (or not) as requested.
+    pub fn satisfies(&self, opts: &nix::PopulateOptions) -> bool {
+        self.is_current_format()
+            && (self.rich || !opts.rich)
+            && self.skip_extra_attrs == opts.skip_extra_attrs
+            && self.include_private == opts.include_private
+    }
+
+    /// Options that would populate a cache like this one.
+    pub fn populate_options(&self) -> nix::PopulateOptions {
+        nix::PopulateOptions {
+            rich: self.rich,
+            skip_extra_attrs: self.skip_extra_attrs,
+            include_private: self.include_private,
+            ..Default::default()
+        }
+    }
+
+    /// Whether the cache was built from the `current` source. If either
+    /// source is unknown, we can't tell, so the cache is assumed to match.
+    pub fn source_matches(&self, current: Option<&str>) -> bool {
+        match (&self.source, current) {
+            (Some(recorded), Some(current)) => recorded == current,
+            _ => true,
+        }
+    }
+}
+
+impl Display for CacheHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} version={} mode={} extras={}",
+            HEADER_PREFIX,
+            self.version,
+            if self.rich { "rich" } else { "plain" },
+            if self.skip_extra_attrs { "no" } else { "yes" },
+        )?;
+        if self.include_private {
+            write!(f, " private=yes")?;
+        }
+        if let Some(source) = &self.source {
+            write!(f, " source={}", source)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the cache to a temporary file and then moves it into place, so a
+/// concurrent reader never sees a half-written cache. The temporary file is
+/// named for this process, so concurrent writers don't clobber each other's.
+pub fn write_cache(header: &CacheHeader, nix_attrs: &[u8]) -> Result<(), CacheIoError> {
+    let path = cache_path()?;
+    fs::create_dir_all(path.parent().ok_or(CacheIoError::NoCachePath)?)?;
+    let tmp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+    let mut cache_file = File::create(&tmp_path)?;
+    writeln!(cache_file, "{}", header)?;
+    cache_file.write_all(nix_attrs)?;
+    drop(cache_file);
+    fs::rename(tmp_path, path).map_err(Into::into)
+}
+
+/// Splits the header line off of the cache's contents. Caches written before
+/// headers were introduced get the default header.
+fn split_header(mut contents: String) -> (CacheHeader, String) {
+    if !contents.starts_with(HEADER_PREFIX) {
+        return (CacheHeader::default(), contents);
+    }
+    let header_end = contents.find('\n').map(|i| i + 1).unwrap_or(contents.len());
+    let header = CacheHeader::parse(contents[..header_end].trim_end()).unwrap_or_default();
+    contents.drain(..header_end);
+    (header, contents)
+}
+
+/// Reads the cache and its header.
+pub fn read_cache_with_header() -> Result<(CacheHeader, String), CacheIoError> {
+    let mut cache_file = File::open(cache_path()?)?;
     let mut ret = String::with_capacity(NIX_ATTRS_FILE_SIZE_ESTIMATE);
     cache_file.read_to_string(&mut ret)?;
-    Ok(ret)
+    Ok(split_header(ret))
 }
 
-pub fn ensure_cache() -> Result<String, CacheIoError> {
-    if !cache_exists() {
-        let attrs = nix::nix_query_all().map_err(CacheIoError::Command)?;
-        write_cache(attrs.as_bytes())?;
-        Ok(attrs)
+/// Reads the cache's attribute lines, without the header.
+pub fn read_cache() -> Result<String, CacheIoError> {
+    read_cache_with_header().map(|(_, attrs)| attrs)
+}
+
+/// The attribute lines read from `reader`, skipping the header if there is
+/// one.
+fn cache_lines<R: BufRead>(reader: R) -> impl Iterator<Item = io::Result<String>> {
+    reader
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| match line {
+            Ok(line) if i == 0 && line.starts_with(HEADER_PREFIX) => None,
+            line => Some(line),
+        })
+}
+
+/// Reads the cache's attribute lines one at a time, without the header. For
+/// callers that don't need the whole cache at once, this avoids reading it
+/// into one big `String`.
+pub fn read_cache_lines() -> Result<impl Iterator<Item = io::Result<String>>, CacheIoError> {
+    Ok(cache_lines(BufReader::new(File::open(cache_path()?)?)))
+}
+
+/// Reads just the cache's header.
+pub fn read_cache_header() -> Result<CacheHeader, CacheIoError> {
+    let mut first_line = String::new();
+    BufReader::new(File::open(cache_path()?)?).read_line(&mut first_line)?;
+    Ok(CacheHeader::parse(first_line.trim_end()).unwrap_or_default())
+}
+
+/// Copies the cache file to `path`, to be compared against later with
+/// `read_snapshot`.
+pub fn write_snapshot(path: &Path) -> Result<(), CacheIoError> {
+    fs::copy(cache_path()?, path)?;
+    Ok(())
+}
+
+/// Reads the attribute lines of a snapshot written by `write_snapshot`.
+pub fn read_snapshot(path: &Path) -> Result<String, CacheIoError> {
+    Ok(split_header(fs::read_to_string(path)?).1)
+}
+
+/// Whether the cache exists and can answer a request with the given options,
+/// and was built from the packages Nix would query now.
+pub fn cache_satisfies(opts: &nix::PopulateOptions) -> bool {
+    cache_exists()
+        && read_cache_header()
+            .map(|header| {
+                header.satisfies(opts) && header.source_matches(nix::source_descriptor().as_deref())
+            })
+            .unwrap_or(false)
+}
+
+/// Queries Nix for all attributes without writing them to the cache.
+pub fn query_attrs(opts: &nix::PopulateOptions) -> Result<nix::AllAttrs, CacheIoError> {
+    let all = nix::nix_query_all(opts).map_err(CacheIoError::Command)?;
+    check_population(all, opts.strict)
+}
+
+/// Rejects a query result that shouldn't be written to the cache: one with no
+/// attributes, or, if `strict`, one with unparseable metadata.
+fn check_population(all: nix::AllAttrs, strict: bool) -> Result<nix::AllAttrs, CacheIoError> {
+    if all.is_empty() {
+        return Err(CacheIoError::EmptyPopulation);
+    }
+    if strict && !all.unparsed.is_empty() {
+        return Err(CacheIoError::Unparseable(all.unparsed));
+    }
+    Ok(all)
+}
+
+/// Queries Nix for all attributes and writes them to the cache.
+pub fn populate_cache(opts: &nix::PopulateOptions) -> Result<nix::AllAttrs, CacheIoError> {
+    // Writing an empty cache would serve an empty picker on every future run,
+    // so `query_attrs` fails instead.
+    let all = query_attrs(opts)?;
+    write_cache(
+        &CacheHeader::new(opts).with_source(nix::source_descriptor()),
+        all.attrs.as_bytes(),
+    )?;
+    Ok(all)
+}
+
+/// Replaces the lines for attributes under `base_attr` in `attrs` with the
+/// lines in `fresh`, which are added at the end.
+fn splice_lines(attrs: &str, base_attr: &str, fresh: &str) -> String {
+    let prefix = format!("{}.", base_attr);
+    let mut ret = String::with_capacity(attrs.len() + fresh.len());
+    for line in attrs.lines() {
+        if !line.trim_start().starts_with(&prefix) {
+            ret.push_str(line);
+            ret.push('\n');
+        }
+    }
+    ret.push_str(fresh);
+    ret
+}
+
+/// Whether `attrs` has lines under each of the package sets in `sets`.
+fn has_package_sets(attrs: &str, sets: &[String]) -> bool {
+    sets.iter().all(|set| {
+        let prefix = format!("{}.", set);
+        attrs
+            .lines()
+            .any(|line| line.trim_start().starts_with(&prefix))
+    })
+}
+
+/// Queries Nix for the attributes under `base_attr` (like
+/// `nixpkgs.haskellPackages`) and replaces just their lines in the cache. If
+/// Nix finds nothing, the cache is left alone rather than losing the set.
+pub fn refresh_extra(base_attr: &str) -> Result<nix::AllAttrs, CacheIoError> {
+    let (header, attrs) = read_cache_with_header()?;
+    let fresh = nix::nix_query_attr_set(base_attr, &header.populate_options())
+        .map_err(CacheIoError::Command)?;
+    if fresh.attrs.trim().is_empty() {
+        return Err(CacheIoError::EmptyPopulation);
+    }
+    let attrs = splice_lines(&attrs, base_attr, &fresh.attrs);
+    // A cache built without the extra package sets has them once each one
+    // has been refreshed.
+    let header = CacheHeader {
+        skip_extra_attrs: header.skip_extra_attrs && !has_package_sets(&attrs, &nix::extra_attrs()),
+        ..header
+    };
+    write_cache(&header, attrs.as_bytes())?;
+    Ok(fresh)
+}
+
+/// What `migrate_cache` did.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Migration {
+    /// The cache was already in the current format.
+    Current,
+    /// The cache was rewritten in the current format from the given version.
+    Migrated(u32),
+    /// There's no cache, so it needs to be built.
+    Missing,
+    /// The cache can't be migrated, so it needs to be rebuilt.
+    NeedsRebuild,
+}
+
+/// Rewrites a cache in an older format in the current format, if it can be.
+/// Format 0 predates format versions but has the same columns, so its lines
+/// are kept; a plain cache's lines are re-split into columns, in case it
+/// predates `nix::FIELD_DELIMITER`.
+fn migrate_contents(header: &CacheHeader, attrs: String) -> Option<(CacheHeader, String)> {
+    if header.version != 0 {
+        return None;
+    }
+    let attrs = if header.rich {
+        attrs
     } else {
+        nix::rewrite_attr_lines(attrs, true)
+    };
+    Some((
+        CacheHeader {
+            version: CACHE_FORMAT_VERSION,
+            ..header.clone()
+        },
+        attrs,
+    ))
+}
+
+/// Brings the cache up to the current format without querying Nix, if
+/// possible. A cache left by an older version of nix-query outside the cache
+/// directory is moved into it first.
+pub fn migrate_cache() -> Result<Migration, CacheIoError> {
+    migrate_legacy_cache()?;
+    if !cache_exists() {
+        return Ok(Migration::Missing);
+    }
+    let (header, attrs) = read_cache_with_header()?;
+    if header.is_current_format() {
+        return Ok(Migration::Current);
+    }
+    match migrate_contents(&header, attrs) {
+        Some((migrated, attrs)) if !attrs.trim().is_empty() => {
+            write_cache(&migrated, attrs.as_bytes())?;
+            Ok(Migration::Migrated(header.version))
+        }
+        _ => Ok(Migration::NeedsRebuild),
+    }
+}
+
+pub fn ensure_cache(opts: &nix::PopulateOptions) -> Result<String, CacheIoError> {
+    if cache_satisfies(opts) {
         read_cache()
+    } else {
+        populate_cache(opts).map(|all| all.attrs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_population() {
+        let all = |attrs: &str, unparsed: Vec<(String, String)>| nix::AllAttrs {
+            attrs: attrs.to_string(),
+            collisions: Vec::new(),
+            duplicates: 0,
+            unparsed,
+        };
+        let unparsed = || vec![("nixpkgs.bad".to_string(), "missing field".to_string())];
+
+        assert!(matches!(
+            check_population(all("", Vec::new()), false),
+            Err(CacheIoError::EmptyPopulation)
+        ));
+        assert!(matches!(
+            check_population(all("\n  \n", Vec::new()), true),
+            Err(CacheIoError::EmptyPopulation)
+        ));
+        assert!(matches!(
+            check_population(all("nixpkgs.hello    hello-2.10", unparsed()), true),
+            Err(CacheIoError::Unparseable(_))
+        ));
+        let ok = check_population(all("nixpkgs.hello    hello-2.10", unparsed()), false).unwrap();
+        assert_eq!(ok.attrs, "nixpkgs.hello    hello-2.10");
+    }
+
+    #[test]
+    fn test_resolve_cache_dir() {
+        let resolve = |explicit: Option<&str>, nqc: Option<&str>, xdg: Option<&str>| {
+            resolve_cache_dir(
+                explicit.map(PathBuf::from),
+                nqc.map(OsString::from),
+                xdg.map(OsString::from),
+                Some(PathBuf::from("/home/user/.cache")),
+            )
+            .unwrap()
+        };
+
+        assert_eq!(
+            PathBuf::from("/explicit"),
+            resolve(Some("/explicit"), Some("/nqc"), Some("/xdg"))
+        );
+        assert_eq!(
+            PathBuf::from("/nqc"),
+            resolve(None, Some("/nqc"), Some("/xdg"))
+        );
+        assert_eq!(
+            PathBuf::from("/xdg/nix-query"),
+            resolve(None, None, Some("/xdg"))
+        );
+        assert_eq!(
+            PathBuf::from("/xdg/nix-query"),
+            resolve(None, Some(""), Some("/xdg"))
+        );
+        assert_eq!(
+            PathBuf::from("/home/user/.cache/nix-query"),
+            resolve(None, None, None)
+        );
+        assert_eq!(None, resolve_cache_dir(None, None, None, None));
+    }
+
+    #[test]
+    fn test_file_mtime() {
+        let path = env::temp_dir().join(format!("nix-query-test-mtime-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert!(file_mtime(&path).unwrap().is_none());
+
+        let before = SystemTime::now() - Duration::from_secs(60);
+        fs::write(&path, "#nix-query version=1\n").unwrap();
+        let mtime = file_mtime(&path).unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(mtime > before);
+        assert!(mtime < SystemTime::now() + Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_cache_header() {
+        let plain_opts = nix::PopulateOptions::default();
+        let rich_opts = nix::PopulateOptions {
+            rich: true,
+            ..Default::default()
+        };
+        let plain = CacheHeader::new(&plain_opts);
+        let rich = CacheHeader::new(&rich_opts);
+        assert_eq!(
+            format!(
+                "#nix-query version={} mode=rich extras=yes",
+                CACHE_FORMAT_VERSION
+            ),
+            rich.to_string()
+        );
+        assert_eq!(Some(rich.clone()), CacheHeader::parse(&rich.to_string()));
+        assert_eq!(
+            Some(CacheHeader::default()),
+            CacheHeader::parse("#nix-query mode=plain future=1")
+        );
+        assert_eq!(None, CacheHeader::parse("nixpkgs.gzip    gzip-1.10"));
+
+        assert!(rich.satisfies(&plain_opts));
+        assert!(rich.satisfies(&rich_opts));
+        assert!(plain.satisfies(&plain_opts));
+        assert!(!plain.satisfies(&rich_opts));
+
+        let no_extras = CacheHeader::parse(&format!(
+            "#nix-query version={} mode=plain extras=no",
+            CACHE_FORMAT_VERSION
+        ))
+        .unwrap();
+        assert!(no_extras.skip_extra_attrs);
+        assert!(!no_extras.satisfies(&plain_opts));
+        assert!(no_extras.satisfies(&nix::PopulateOptions {
+            skip_extra_attrs: true,
+            ..Default::default()
+        }));
+
+        let private_opts = nix::PopulateOptions {
+            include_private: true,
+            ..Default::default()
+        };
+        let private = CacheHeader::new(&private_opts);
+        assert_eq!(
+            format!(
+                "#nix-query version={} mode=plain extras=yes private=yes",
+                CACHE_FORMAT_VERSION
+            ),
+            private.to_string()
+        );
+        assert_eq!(
+            Some(private.clone()),
+            CacheHeader::parse(&private.to_string())
+        );
+        assert!(private.satisfies(&private_opts));
+        assert!(!private.satisfies(&plain_opts));
+        assert!(!plain.satisfies(&private_opts));
+    }
+
+    #[test]
+    fn test_migrate_contents() {
+        let (header, attrs) = split_header(
+            "#nix-query mode=plain extras=no\nnixpkgs.gzip  gzip-1.10   GNU zip\n".to_string(),
+        );
+        let (migrated, attrs) = migrate_contents(&header, attrs).unwrap();
+        assert!(migrated.is_current_format());
+        assert!(migrated.skip_extra_attrs);
+        assert_eq!("nixpkgs.gzip    gzip-1.10    GNU zip\n", attrs);
+
+        // Empty rich columns must survive.
+        let rich_line = ["nixpkgs.a", "a-1", "", "false", "true", ""].join(nix::FIELD_DELIMITER);
+        let (header, attrs) =
+            split_header(format!("#nix-query mode=rich extras=yes\n{}\n", rich_line));
+        let (migrated, attrs) = migrate_contents(&header, attrs).unwrap();
+        assert!(migrated.rich);
+        assert_eq!(format!("{}\n", rich_line), attrs);
+
+        let newer = CacheHeader {
+            version: CACHE_FORMAT_VERSION + 1,
+            ..Default::default()
+        };
+        assert_eq!(None, migrate_contents(&newer, String::new()));
+        let current = CacheHeader::new(&nix::PopulateOptions::default());
+        assert_eq!(None, migrate_contents(&current, String::new()));
+    }
+
+    #[test]
+    fn test_cache_header_source() {
+        let header = CacheHeader::new(&nix::PopulateOptions::default())
+            .with_source(Some("channels:nixos,nixpkgs".to_string()));
+        assert_eq!(
+            format!(
+                "#nix-query version={} mode=plain extras=yes source=channels:nixos,nixpkgs",
+                CACHE_FORMAT_VERSION
+            ),
+            header.to_string()
+        );
+        assert_eq!(
+            Some(header.clone()),
+            CacheHeader::parse(&header.to_string())
+        );
+
+        assert!(header.source_matches(Some("channels:nixos,nixpkgs")));
+        assert!(!header.source_matches(Some("channels:nixpkgs")));
+        assert!(header.source_matches(None));
+        assert!(CacheHeader::default().source_matches(Some("channels:nixpkgs")));
+    }
+
+    #[test]
+    fn test_cache_format_version() {
+        let opts = nix::PopulateOptions::default();
+
+        // Too old: written before format versions, or by an older version.
+        for line in &["#nix-query mode=plain extras=yes", "#nix-query version=0"] {
+            let header = CacheHeader::parse(line).unwrap();
+            assert!(!header.is_current_format());
+            assert!(!header.satisfies(&opts));
+        }
+        let (header, _) = split_header("nixpkgs.gzip    gzip-1.10\n".to_string());
+        assert!(!header.satisfies(&opts));
+
+        // Too new.
+        let header = CacheHeader::parse(&format!(
+            "#nix-query version={} mode=plain extras=yes",
+            CACHE_FORMAT_VERSION + 1
+        ))
+        .unwrap();
+        assert!(!header.is_current_format());
+        assert!(!header.satisfies(&opts));
+
+        let header = CacheHeader::parse("#nix-query version=banana").unwrap();
+        assert!(!header.is_current_format());
+    }
+
+    #[test]
+    fn test_cache_lines() {
+        let read = |contents: &str| {
+            cache_lines(io::Cursor::new(contents.to_string()))
+                .collect::<io::Result<Vec<_>>>()
+                .unwrap()
+        };
+        assert_eq!(vec!["a", "b"], read("#nix-query version=1\na\nb\n"));
+        assert_eq!(vec!["a", "b"], read("a\nb"));
+        assert!(read("").is_empty());
+    }
+
+    #[test]
+    fn test_favorites() {
+        let favorites = parse_favorites("3 nixpkgs.gzip\nbogus\n1 nixpkgs.hello\n");
+        assert_eq!(
+            vec![("nixpkgs.gzip", 3), ("nixpkgs.hello", 1)],
+            favorites
+                .iter()
+                .map(|fav| (fav.attr.as_str(), fav.count))
+                .collect::<Vec<_>>()
+        );
+
+        let bumped = bump_favorites(
+            favorites,
+            &["nixpkgs.ripgrep".to_string(), "nixpkgs.hello".to_string()],
+        );
+        assert_eq!(
+            vec![
+                ("nixpkgs.gzip", 3),
+                ("nixpkgs.hello", 2),
+                ("nixpkgs.ripgrep", 1)
+            ],
+            bumped
+                .iter()
+                .map(|fav| (fav.attr.as_str(), fav.count))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_clear_cache_dir() {
+        let dir = env::temp_dir().join(format!("nix-query-test-clear-{}", std::process::id()));
+        let foreign_dir = dir.join("other-program");
+        fs::create_dir_all(&foreign_dir).unwrap();
+        for path in &[
+            dir.join(ATTRS_FILE),
+            dir.join(format!("{}.1234.tmp", ATTRS_FILE)),
+            dir.join(BACKEND_FILE),
+            dir.join(FAVORITES_FILE),
+            dir.join("unrelated.txt"),
+            foreign_dir.join("data"),
+        ] {
+            fs::write(path, "contents").unwrap();
+        }
+
+        clear_cache_dir(&dir).unwrap();
+        let mut left: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        left.sort();
+        assert_eq!(
+            vec![
+                OsString::from(FAVORITES_FILE),
+                OsString::from("other-program"),
+                OsString::from("unrelated.txt"),
+            ],
+            left
+        );
+        assert!(foreign_dir.join("data").exists());
+
+        // Already clear.
+        clear_cache_dir(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        clear_cache_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_splice_lines() {
+        let attrs = "nixpkgs.a    a-1\n\
+                     nixpkgs.haskellPackages.old    old-1\n\
+                     nixpkgs.haskellPackagesExtra    extra-1\n\
+                     nixpkgs.b    b-1\n";
+        assert_eq!(
+            "nixpkgs.a    a-1\n\
+             nixpkgs.haskellPackagesExtra    extra-1\n\
+             nixpkgs.b    b-1\n\
+             nixpkgs.haskellPackages.new    new-1\n",
+            splice_lines(
+                attrs,
+                "nixpkgs.haskellPackages",
+                "nixpkgs.haskellPackages.new    new-1\n"
+            )
+        );
+        assert_eq!(attrs, splice_lines(attrs, "nixpkgs.nodePackages", ""));
+    }
+
+    #[test]
+    fn test_has_package_sets() {
+        let sets = vec![
+            "nixpkgs.nodePackages".to_string(),
+            "nixpkgs.haskellPackages".to_string(),
+        ];
+        let attrs = "nixpkgs.a    a-1\n\
+                     nixpkgs.haskellPackages.b    b-1\n";
+        assert!(!has_package_sets(attrs, &sets));
+        assert!(has_package_sets(
+            &format!("{}nixpkgs.nodePackages.c    c-1\n", attrs),
+            &sets
+        ));
+        assert!(!has_package_sets(
+            "nixpkgs.haskellPackagesExtra    x-1\nnixpkgs.nodePackages.c    c-1\n",
+            &sets
+        ));
+    }
+
+    #[test]
+    fn test_split_header() {
+        assert_eq!(
+            (
+                CacheHeader {
+                    rich: true,
+                    ..Default::default()
+                },
+                "a\nb\n".to_string()
+            ),
+            split_header("#nix-query mode=rich\na\nb\n".to_string())
+        );
+        assert_eq!(
+            (CacheHeader::default(), "a\nb\n".to_string()),
+            split_header("a\nb\n".to_string())
+        );
     }
 }
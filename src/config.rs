@@ -0,0 +1,306 @@
+use std::env;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use dirs;
+
+/// Environment variable naming the config file.
+pub const CONFIG_FILE_VAR: &str = "NIX_QUERY_CONFIG";
+
+/// The config file's location: `$NIX_QUERY_CONFIG`, or `config.toml` in a
+/// `nix-query` directory in the platform's config directory (`~/.config` on
+/// Linux).
+pub fn config_path() -> Option<PathBuf> {
+    env::var_os(CONFIG_FILE_VAR)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| Some(dirs::config_dir()?.join("nix-query").join("config.toml")))
+}
+
+/// Defaults for the fuzzy-finder, from the config file's `[picker]` section.
+/// Command-line flags take precedence.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PickerConfig {
+    /// Like `--height`.
+    pub height: Option<String>,
+    /// Like `--layout`.
+    pub layout: Option<String>,
+    /// Where the preview goes and how big it is, like `down:wrap:50%`.
+    pub preview_window: Option<String>,
+    /// How to sort matches that score the same, like `score,end`.
+    pub tiebreak: Option<String>,
+    /// Match the query exactly rather than fuzzily.
+    pub exact: Option<bool>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Config {
+    pub picker: PickerConfig,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(Box<io::Error>),
+    /// A line of the config file couldn't be parsed.
+    Syntax {
+        line: usize,
+        message: String,
+    },
+    /// A setting has a value it can't have.
+    Invalid {
+        key: String,
+        message: String,
+    },
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> Self {
+        ConfigError::Io(Box::new(e))
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "Couldn't read the config file: {}", e),
+            ConfigError::Syntax { line, message } => {
+                write!(f, "Config file line {}: {}", line, message)
+            }
+            ConfigError::Invalid { key, message } => {
+                write!(f, "Config file setting {}: {}", key, message)
+            }
+        }
+    }
+}
+
+/// A value in the config file: a quoted string, `true`, `false`, or a bare
+/// word or number (which is treated like a string).
+fn parse_value(value: &str) -> Option<String> {
+    if let Some(quoted) = value.strip_prefix('"') {
+        let unquoted = quoted.strip_suffix('"')?;
+        if unquoted.contains('"') {
+            return None;
+        }
+        Some(unquoted.to_string())
+    } else if value.is_empty() || value.contains(char::is_whitespace) {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+fn invalid(key: &str, message: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid {
+        key: key.to_string(),
+        message: message.into(),
+    }
+}
+
+/// Checks a height like `40` or `40%`.
+pub fn check_height(value: &str) -> Result<(), String> {
+    if value
+        .strip_suffix('%')
+        .unwrap_or(value)
+        .parse::<u16>()
+        .is_ok()
+    {
+        Ok(())
+    } else {
+        Err(format!(
+            "{:?} isn't a number of lines or a percentage",
+            value
+        ))
+    }
+}
+
+/// Checks a preview window like `down:wrap:50%`.
+fn check_preview_window(key: &str, value: &str) -> Result<(), ConfigError> {
+    for part in value.split(':') {
+        let valid = match part {
+            "up" | "down" | "left" | "right" | "wrap" | "hidden" => true,
+            size => check_height(size).is_ok(),
+        };
+        if !valid {
+            return Err(invalid(
+                key,
+                format!(
+                    "{:?} isn't up, down, left, right, wrap, hidden, or a size",
+                    part
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks a tiebreak like `score,end`.
+fn check_tiebreak(key: &str, value: &str) -> Result<(), ConfigError> {
+    for criterion in value.split(',') {
+        match criterion.strip_prefix('-').unwrap_or(criterion) {
+            "score" | "index" | "begin" | "end" => {}
+            _ => {
+                return Err(invalid(
+                    key,
+                    format!(
+                        "{:?} isn't score, index, begin, or end (optionally negated with -)",
+                        criterion
+                    ),
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+impl PickerConfig {
+    fn set(&mut self, key: &str, value: String) -> Result<(), ConfigError> {
+        match key {
+            "height" => {
+                check_height(&value).map_err(|message| invalid(key, message))?;
+                self.height = Some(value);
+            }
+            "layout" => match value.as_str() {
+                "default" | "reverse" | "reverse-list" => self.layout = Some(value),
+                _ => {
+                    return Err(invalid(
+                        key,
+                        format!("{:?} isn't default, reverse, or reverse-list", value),
+                    ))
+                }
+            },
+            "preview-window" => {
+                check_preview_window(key, &value)?;
+                self.preview_window = Some(value);
+            }
+            "tiebreak" => {
+                check_tiebreak(key, &value)?;
+                self.tiebreak = Some(value);
+            }
+            "exact" => {
+                self.exact = Some(match value.as_str() {
+                    "true" => true,
+                    "false" => false,
+                    _ => return Err(invalid(key, format!("{:?} isn't true or false", value))),
+                })
+            }
+            _ => return Err(invalid(key, "there's no such setting")),
+        }
+        Ok(())
+    }
+}
+
+/// Parses a config file. The format is a small subset of TOML: `[section]`
+/// headers, `key = value` lines, and `#` comments. Sections other than
+/// `[picker]` are ignored.
+pub fn parse_config(text: &str) -> Result<Config, ConfigError> {
+    let mut config = Config::default();
+    let mut section = String::new();
+    for (inx, line) in text.lines().enumerate() {
+        let syntax = |message: &str| ConfigError::Syntax {
+            line: inx + 1,
+            message: message.to_string(),
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[') {
+            section = name
+                .strip_suffix(']')
+                .ok_or_else(|| syntax("expected a closing ]"))?
+                .trim()
+                .to_string();
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| syntax("expected `key = value`"))?;
+        let key = key.trim();
+        let value = parse_value(value.trim()).ok_or_else(|| syntax("expected a value"))?;
+        if section == "picker" {
+            config.picker.set(key, value)?;
+        }
+    }
+    Ok(config)
+}
+
+/// Reads the config file. A missing config file is the same as an empty one.
+pub fn read_config() -> Result<Config, ConfigError> {
+    let path = match config_path() {
+        Some(path) => path,
+        None => return Ok(Config::default()),
+    };
+    match fs::read_to_string(path) {
+        Ok(text) => parse_config(&text),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        assert_eq!(Config::default(), parse_config("").unwrap());
+        assert_eq!(
+            PickerConfig {
+                height: Some("40%".to_string()),
+                layout: Some("reverse".to_string()),
+                preview_window: Some("right:wrap:60%".to_string()),
+                tiebreak: Some("score,-begin".to_string()),
+                exact: Some(true),
+            },
+            parse_config(
+                r#"
+                # Picker defaults.
+                [picker]
+                height = "40%"
+                layout = reverse
+                preview-window = "right:wrap:60%"
+                tiebreak = "score,-begin"
+                exact = true
+
+                [other]
+                height = "whatever"
+                "#
+            )
+            .unwrap()
+            .picker
+        );
+    }
+
+    #[test]
+    fn test_parse_config_errors() {
+        let error = |text: &str| parse_config(text).unwrap_err().to_string();
+        assert_eq!(
+            "Config file line 2: expected `key = value`",
+            error("[picker]\nheight")
+        );
+        assert_eq!("Config file line 1: expected a closing ]", error("[picker"));
+        assert_eq!(
+            "Config file line 2: expected a value",
+            error("[picker]\nheight = \"40%")
+        );
+        assert_eq!(
+            "Config file setting height: \"tall\" isn't a number of lines or a percentage",
+            error("[picker]\nheight = tall")
+        );
+        assert_eq!(
+            "Config file setting preview-window: \"middle\" isn't up, down, left, right, wrap, hidden, or a size",
+            error("[picker]\npreview-window = \"middle:50%\"")
+        );
+        assert_eq!(
+            "Config file setting tiebreak: \"length\" isn't score, index, begin, or end (optionally negated with -)",
+            error("[picker]\ntiebreak = \"score,length\"")
+        );
+        assert_eq!(
+            "Config file setting colour: there's no such setting",
+            error("[picker]\ncolour = red")
+        );
+    }
+}
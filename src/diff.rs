@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+
+use crate::nix::FIELD_DELIMITER;
+
+/// What changed between two versions of the attribute list.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CacheDiff {
+    /// Attributes only in the new list.
+    pub added: Vec<String>,
+    /// Attributes only in the old list.
+    pub removed: Vec<String>,
+    /// Attributes whose name (and therefore usually version) changed, as
+    /// `(attr, old name, new name)`.
+    pub changed: Vec<(String, String, String)>,
+}
+
+impl CacheDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// A one-line summary, like `412 added, 37 removed, 1,203 version
+    /// changes`.
+    pub fn summary(&self) -> String {
+        let changes = if self.changed.len() == 1 {
+            "version change"
+        } else {
+            "version changes"
+        };
+        format!(
+            "{} added, {} removed, {} {}",
+            with_commas(self.added.len()),
+            with_commas(self.removed.len()),
+            with_commas(self.changed.len()),
+            changes
+        )
+    }
+}
+
+/// Formats a count with commas between groups of three digits, like `1,203`.
+fn with_commas(n: usize) -> String {
+    let digits = n.to_string();
+    let mut ret = String::with_capacity(digits.len() + digits.len() / 3);
+    // A comma goes before each digit with a multiple of three digits after it.
+    let first_group = digits.len() % 3;
+    for (inx, digit) in digits.chars().enumerate() {
+        if inx > 0 && inx % 3 == first_group {
+            ret.push(',');
+        }
+        ret.push(digit);
+    }
+    ret
+}
+
+/// Maps each attribute to its name column, e.g. `gzip-1.10`.
+fn attr_names(attrs: &str) -> BTreeMap<&str, &str> {
+    attrs
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(FIELD_DELIMITER);
+            let attr = fields.next().filter(|attr| !attr.is_empty())?;
+            Some((attr, fields.next().unwrap_or_default()))
+        })
+        .collect()
+}
+
+/// Compares two attribute lists (as stored in the cache), matching lines by
+/// attribute and comparing their name columns. Results are sorted by
+/// attribute.
+pub fn diff_attrs(old: &str, new: &str) -> CacheDiff {
+    let old = attr_names(old);
+    let new = attr_names(new);
+    let mut ret = CacheDiff::default();
+
+    for (attr, new_name) in &new {
+        match old.get(attr) {
+            None => ret.added.push(attr.to_string()),
+            Some(old_name) if old_name != new_name => {
+                ret.changed
+                    .push((attr.to_string(), old_name.to_string(), new_name.to_string()))
+            }
+            Some(_) => {}
+        }
+    }
+
+    ret.removed = old
+        .keys()
+        .filter(|attr| !new.contains_key(*attr))
+        .map(|attr| attr.to_string())
+        .collect();
+
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_diff_attrs() {
+        let old = "nixpkgs.gzip    gzip-1.10    GNU zip\n\
+                   nixpkgs.old    old-1.0    Going away\n\
+                   nixpkgs.same    same-2.0    Unchanged\n";
+        let new = "nixpkgs.same    same-2.0    Unchanged, new description\n\
+                   nixpkgs.new    new-0.1    Brand new\n\
+                   nixpkgs.gzip    gzip-1.11    GNU zip\n";
+        assert_eq!(
+            CacheDiff {
+                added: vec!["nixpkgs.new".to_string()],
+                removed: vec!["nixpkgs.old".to_string()],
+                changed: vec![(
+                    "nixpkgs.gzip".to_string(),
+                    "gzip-1.10".to_string(),
+                    "gzip-1.11".to_string()
+                )],
+            },
+            diff_attrs(old, new)
+        );
+        assert!(diff_attrs(new, new).is_empty());
+    }
+
+    #[test]
+    fn test_summary() {
+        assert_eq!("0", with_commas(0));
+        assert_eq!("999", with_commas(999));
+        assert_eq!("1,203", with_commas(1203));
+        assert_eq!("1,234,567", with_commas(1_234_567));
+
+        let diff = CacheDiff {
+            added: vec!["nixpkgs.new".to_string()],
+            removed: vec![],
+            changed: vec![(
+                "nixpkgs.gzip".to_string(),
+                "gzip-1.10".to_string(),
+                "gzip-1.11".to_string(),
+            )],
+        };
+        assert_eq!("1 added, 0 removed, 1 version change", diff.summary());
+        assert_eq!(
+            "0 added, 0 removed, 0 version changes",
+            CacheDiff::default().summary()
+        );
+    }
+}
@@ -1,3 +1,6 @@
 pub mod cache;
+pub mod config;
+pub mod diff;
 pub mod nix;
 pub mod proc;
+pub mod regexes;
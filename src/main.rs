@@ -1,11 +1,22 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::env;
 use std::io;
-use std::io::Write;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::process::Command;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use console::{style, Term};
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
 use skim::{Skim, SkimOptionsBuilder};
 use structopt::StructOpt;
 
-use nix_query::{cache, cache::CacheIoError, nix, proc::CommandError};
+use nix_query::{
+    cache, cache::CacheIoError, config, config::ConfigError, diff, nix, proc, proc::CommandError,
+};
 
 #[derive(Debug)]
 enum MainErr {
@@ -13,6 +24,103 @@ enum MainErr {
     Command(CommandError),
     NixQuery(nix::NixQueryError),
     Io(io::Error),
+    Config(ConfigError),
+    /// The fuzzy-finder's options are invalid, for example from the config
+    /// file. The message names the option.
+    Skim(String),
+    /// There's no terminal to show the fuzzy-finder on.
+    NoTerminal,
+    /// The package doesn't say where it's defined.
+    NoPosition(String),
+    /// A bare attribute name matches several attributes in the cache.
+    Ambiguous(String),
+    /// The package doesn't have a description.
+    NoDescription(String),
+    /// The package doesn't list the platforms it supports.
+    NoPlatforms(String),
+    /// Nothing matches the --first-match query.
+    NoMatch(String),
+    /// The --check attribute can't be installed. This is only reported by
+    /// the exit status (and the status printed with --json).
+    CheckFailed(String),
+    /// Some of the attributes given to --attr-exists or --info-file don't
+    /// exist.
+    MissingAttrs(Vec<String>),
+    /// The --first-match query matches several attributes about equally
+    /// well; the best of them are given.
+    AmbiguousMatch(String, Vec<String>),
+}
+
+impl MainErr {
+    /// A stable name for the kind of error, for --json.
+    fn kind(&self) -> &'static str {
+        match self {
+            MainErr::Cache(CacheIoError::Unparseable(_)) => "invalid_json",
+            MainErr::Cache(_) => "cache",
+            MainErr::Command(CommandError::Timeout(_))
+            | MainErr::NixQuery(nix::NixQueryError::Command(CommandError::Timeout(_))) => "timeout",
+            MainErr::Command(_) => "command",
+            MainErr::NixQuery(nix::NixQueryError::Empty)
+            | MainErr::NixQuery(nix::NixQueryError::Missing(_))
+            | MainErr::NoMatch(_) => "not_found",
+            MainErr::CheckFailed(_) => "check_failed",
+            MainErr::MissingAttrs(_) => "missing_attrs",
+            MainErr::NixQuery(nix::NixQueryError::Command(CommandError::De(_))) => "invalid_json",
+            MainErr::NixQuery(nix::NixQueryError::Command(_)) => "nix_failed",
+            MainErr::Io(_) => "io",
+            MainErr::Config(_) => "config",
+            MainErr::Skim(_) => "skim",
+            MainErr::NoTerminal => "no_terminal",
+            MainErr::NoPosition(_) => "no_position",
+            MainErr::Ambiguous(_) | MainErr::AmbiguousMatch(_, _) => "ambiguous",
+            MainErr::NoDescription(_) => "no_description",
+            MainErr::NoPlatforms(_) => "no_platforms",
+        }
+    }
+
+    /// A description of the error for people.
+    fn message(&self) -> String {
+        match self {
+            MainErr::NoPosition(attr) => format!("{} doesn't say where it's defined", attr),
+            MainErr::Ambiguous(attr) => format!("{} matches several attributes", attr),
+            MainErr::NoDescription(attr) => format!("{} doesn't have a description", attr),
+            MainErr::NoPlatforms(attr) => format!("{} doesn't list its platforms", attr),
+            MainErr::NoMatch(query) => format!("Nothing matches {}", query),
+            MainErr::CheckFailed(attr) => format!("{} can't be installed", attr),
+            MainErr::MissingAttrs(attrs) => format!("Missing: {}", attrs.join(", ")),
+            MainErr::Config(e) => e.to_string(),
+            MainErr::Io(e) => e.to_string(),
+            MainErr::Skim(e) => format!("Invalid fuzzy-finder option: {}", e),
+            MainErr::NoTerminal => "There's no terminal to show the fuzzy-finder on; \
+                                    use --first-match QUERY --no-tui to pick an attribute \
+                                    or --print-cache to list them"
+                .to_string(),
+            MainErr::AmbiguousMatch(query, best) => {
+                format!("{} matches several attributes: {}", query, best.join(", "))
+            }
+            MainErr::Cache(CacheIoError::Unparseable(unparsed)) => unparsed.iter().fold(
+                format!(
+                    "{} packages don't match the expected schema:",
+                    unparsed.len()
+                ),
+                |mut acc, (attr, e)| {
+                    acc.push_str(&format!("\n    {}: {}", attr, e));
+                    acc
+                },
+            ),
+            MainErr::Cache(e) => e.to_string(),
+            MainErr::Command(e) => e.to_string(),
+            MainErr::NixQuery(e) => e.to_string(),
+        }
+    }
+
+    /// The error as a JSON object, like `{"error": "...", "kind": "not_found"}`.
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "error": self.message(),
+            "kind": self.kind(),
+        })
+    }
 }
 
 impl From<io::Error> for MainErr {
@@ -21,6 +129,19 @@ impl From<io::Error> for MainErr {
     }
 }
 
+impl From<ConfigError> for MainErr {
+    fn from(e: ConfigError) -> Self {
+        MainErr::Config(e)
+    }
+}
+
+/// `SkimOptionsBuilder::build`'s error.
+impl From<String> for MainErr {
+    fn from(e: String) -> Self {
+        MainErr::Skim(e)
+    }
+}
+
 impl From<CacheIoError> for MainErr {
     fn from(e: CacheIoError) -> Self {
         MainErr::Cache(e)
@@ -39,179 +160,3290 @@ impl From<nix::NixQueryError> for MainErr {
     }
 }
 
+/// How --info and the selected attributes are printed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    /// Styled for the terminal.
+    Console,
+    /// Like `Console`, but without colors.
+    Plain,
+    Json,
+    /// Tab-separated values, with a header row.
+    Tsv,
+}
+
+impl OutputFormat {
+    /// Whether the output is meant for people rather than programs.
+    fn is_human(self) -> bool {
+        matches!(self, OutputFormat::Console | OutputFormat::Plain)
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "console" => Ok(OutputFormat::Console),
+            "plain" => Ok(OutputFormat::Plain),
+            "json" => Ok(OutputFormat::Json),
+            "tsv" => Ok(OutputFormat::Tsv),
+            _ => Err(format!("unknown output format '{}'", s)),
+        }
+    }
+}
+
 #[derive(Debug, StructOpt)]
-#[structopt(
-    name = "nix-query",
-)]
+#[structopt(name = "nix-query")]
 struct Opt {
-    /// Clear and recalculate the cache.
+    /// Clear and recalculate the cache, removing everything nix-query keeps
+    /// in the cache directory except the favorites; see --forget for those.
     #[structopt(long)]
     clear_cache: bool,
 
-    /// Print all attributes in the cache.
+    /// Print how the cache was built and how many attributes it has from each
+    /// channel, and then quit.
+    #[structopt(long)]
+    stats: bool,
+
+    /// Print nix-query's version and cache format version, where the cache's
+    /// packages came from, and where they'd come from now as JSON, then quit.
+    #[structopt(long)]
+    version_json: bool,
+
+    /// Rewrite a cache left by an older version of nix-query in the current
+    /// format, then quit. If it can't be migrated, it's rebuilt, and if
+    /// there's no cache, it's built.
+    #[structopt(long, visible_alias = "self-update-cache-format")]
+    migrate_cache: bool,
+
+    /// List your and root's Nix channels with the version of nixpkgs each one
+    /// has, then quit.
+    #[structopt(long)]
+    list_channels: bool,
+
+    /// Query Nix for packages without reading or writing the cache, or
+    /// remembering favorites. Slow, but leaves no files behind.
+    #[structopt(long, conflicts_with = "DUR")]
+    no_cache: bool,
+
+    /// Directory to keep the cache in. Defaults to $NIX_QUERY_CACHE, then
+    /// $XDG_CACHE_HOME/nix-query, then the platform cache directory.
+    #[structopt(long, parse(from_os_str))]
+    cache_path: Option<PathBuf>,
+
+    /// Directory to run Nix's programs (nix, nix-env, and so on) from, for
+    /// when they aren't on $PATH or to use a particular version of Nix.
+    /// Defaults to $NIX_QUERY_NIX_BIN, then $PATH.
+    #[structopt(long, name = "BIN_DIR", parse(from_os_str))]
+    nix_bin: Option<PathBuf>,
+
+    /// Print extra diagnostic information to stderr.
+    #[structopt(short, long)]
+    verbose: bool,
+
+    /// Don't print informational messages to stderr. Errors are still
+    /// printed.
+    #[structopt(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Populate the cache with full package metadata. This is much slower, but
+    /// records whether each package is broken or free and the platforms it
+    /// supports.
+    #[structopt(long)]
+    rich: bool,
+
+    /// When populating the cache, fail if any package's metadata doesn't match
+    /// what nix-query expects, listing them, rather than leaving them out.
+    /// Implies --rich.
+    #[structopt(long)]
+    strict: bool,
+
+    /// Don't scan nodePackages and haskellPackages when populating the cache.
+    /// This is much faster, but leaves out their packages.
+    #[structopt(long)]
+    no_extra_attrs: bool,
+
+    /// Include "private" attributes, whose names start with `_`, like
+    /// `nixpkgs._sources`. These are left out by default.
+    #[structopt(long)]
+    include_private: bool,
+
+    /// Only show packages that can be built on this system. Implies --rich.
+    /// Packages are shown if Nix says they're available, or, in caches that
+    /// don't record that, if they list this system among their platforms.
+    #[structopt(long)]
+    supported_only: bool,
+
+    /// Only show packages with `passthru.tests`. Implies --rich.
+    #[structopt(long)]
+    has_tests: bool,
+
+    /// Hide broken packages in the fuzzy-finder; toggle this with ctrl-b.
+    /// Implies --rich.
+    #[structopt(long)]
+    hide_broken: bool,
+
+    /// Hide unfree packages in the fuzzy-finder; toggle this with ctrl-u.
+    /// Implies --rich.
+    #[structopt(long)]
+    hide_unfree: bool,
+
+    /// Time each stage of populating the cache, without writing the cache,
+    /// then quit.
+    #[structopt(long, hidden = true)]
+    benchmark: bool,
+
+    /// Show --info for made-up packages which use every kind of styling, to
+    /// check how a terminal renders them. Respects --color, --format plain,
+    /// --ascii, and --width.
+    #[structopt(long, hidden = true)]
+    color_test: bool,
+
+    /// Only show attributes from the given channel, like `nixos`. `auto`
+    /// picks `nixos` on NixOS and `nixpkgs` elsewhere, falling back to every
+    /// channel if the cache has no attributes from it; `all` shows every
+    /// channel.
+    #[structopt(long, default_value = "auto")]
+    channel: String,
+
+    /// Only show attributes starting with the given prefix, like
+    /// `nixpkgs.python3Packages.`.
+    #[structopt(long)]
+    prefix: Option<String>,
+
+    /// With --prefix, don't show the prefix in the fuzzy-finder.
+    #[structopt(long, requires = "prefix")]
+    strip_prefix: bool,
+
+    /// Sort attributes by name, ignoring their channel. The same attribute
+    /// from several channels is ordered by --channel-priority.
+    #[structopt(long)]
+    sort: bool,
+
+    /// Sort attributes by `attr`, like --sort, or by package name and then
+    /// `version`, oldest first, so 1.9 comes before 1.10.
+    #[structopt(long, name = "SORT_KEY", possible_values = &["attr", "version"])]
+    sort_by: Option<String>,
+
+    /// Comma-separated channels, most preferred first. Used to order the same
+    /// attribute from several channels.
+    #[structopt(long, use_delimiter = true, default_value = "nixpkgs,nixos,unstable")]
+    channel_priority: Vec<String>,
+
+    /// Print all attributes in the cache, or only the first N if a count is
+    /// given. Unless they're sorted, the attributes are read from the cache a
+    /// line at a time.
+    #[structopt(long, name = "N")]
+    print_cache: Option<Option<usize>>,
+
+    /// With --print-cache N, print the last N attributes instead of the first.
+    #[structopt(long, requires = "N")]
+    tail: bool,
+
+    /// Query Nix about every attribute in the cache, in parallel, and report
+    /// the ones whose information doesn't match nix-query's schema.
+    #[structopt(long)]
+    audit_schemas: bool,
+
+    /// With --audit-schemas, start at the given attribute (in sorted order),
+    /// to resume an earlier audit.
+    #[structopt(long, name = "START_ATTR", requires = "audit-schemas")]
+    start_at: Option<String>,
+
+    /// With --print-cache or --audit-schemas, print a JSON object per line as
+    /// each attribute is processed, instead of collecting the output first.
+    #[structopt(long, conflicts_with = "json")]
+    json_lines: bool,
+
+    /// Save a copy of the cache to the given file, for use with --diff.
+    #[structopt(long, parse(from_os_str))]
+    snapshot: Option<PathBuf>,
+
+    /// Print the attributes added, removed, and changed since the given
+    /// snapshot was saved with --snapshot.
+    #[structopt(long, parse(from_os_str))]
+    diff: Option<PathBuf>,
+
+    /// Open the file defining the given attribute in an editor, at the line
+    /// it's defined on. The editor command is taken from $NIX_QUERY_EDITOR,
+    /// where {file} and {line} are replaced by the file and line (e.g.
+    /// `code -g {file}:{line}`), or else `$VISUAL +{line} {file}` or
+    /// `$EDITOR +{line} {file}`.
+    #[structopt(long, visible_alias = "open-source", name = "ATTR")]
+    edit: Option<String>,
+
+    /// Height of the fuzzy-finder, either as a number of lines or a
+    /// percentage of the terminal. Defaults to the config file's picker
+    /// height, or 100%.
+    #[structopt(long, validator = validate_height)]
+    height: Option<String>,
+
+    /// Layout of the fuzzy-finder. Defaults to the config file's picker
+    /// layout, or default.
+    #[structopt(long, possible_values = &["default", "reverse", "reverse-list"])]
+    layout: Option<String>,
+
+    /// Regenerate the cache if it's older than the given duration (like
+    /// `30m`, `2h`, or `7d`), then quit. Useful for keeping the cache warm
+    /// from a timer.
+    #[structopt(long, name = "DUR", parse(try_from_str = parse_duration))]
+    refresh_if_older_than: Option<Duration>,
+
+    /// Query Nix again for just the packages under the given attribute, like
+    /// `nixpkgs.haskellPackages`, and update them in the cache, then quit.
+    #[structopt(long, name = "EXTRA_ATTR")]
+    refresh_extra: Option<String>,
+
+    /// Only show attributes you've selected before, most frequently selected
+    /// first. Otherwise, they're shown before other attributes.
+    #[structopt(long)]
+    favorites: bool,
+
+    /// Forget which attributes you've selected before.
+    #[structopt(long)]
+    forget: bool,
+
+    /// List the packages in the Nix store that depend on the given
+    /// attribute, then quit. Only packages already in the store are
+    /// considered.
+    #[structopt(long, name = "DEP_ATTR")]
+    reverse_deps: Option<String>,
+
+    /// Print the cached attributes starting with the given prefix, one per
+    /// line, then quit. For shell completion.
+    #[structopt(long, name = "PREFIX")]
+    complete_attr: Option<String>,
+
+    /// With --complete-attr, print `attr:description` pairs, which zsh's
+    /// `_describe` can show alongside completions.
+    #[structopt(long, requires = "PREFIX")]
+    completions_preview: bool,
+
+    /// After picking packages, choose what to do with each of them: install
+    /// it, open a shell with it, run it, open its homepage, or show its
+    /// information.
+    #[structopt(long)]
+    menu: bool,
+
+    /// With --menu, give installed packages this priority (lower numbers win;
+    /// the default is 5), so their files take precedence over (or give way
+    /// to) other packages'.
+    #[structopt(long, name = "PRIORITY")]
+    install_priority: Option<i32>,
+
+    /// Only give the fuzzy-finder the first N attributes (after filtering),
+    /// to bound its memory use.
+    #[structopt(long, name = "MAX")]
+    max_input: Option<usize>,
+
+    /// Shorten attributes longer than WIDTH characters in the fuzzy-finder by
+    /// cutting out their middle. The full attribute is still printed, but
+    /// only the shortened one is searched.
+    #[structopt(long, name = "WIDTH")]
+    max_attr_width: Option<usize>,
+
+    /// Sort attributes by channel in the fuzzy-finder, with a header line
+    /// before each channel's attributes.
+    #[structopt(long)]
+    group_by_channel: bool,
+
+    /// Start the fuzzy-finder with the given query.
+    #[structopt(long, name = "INITIAL_QUERY", parse(from_str = sanitize_query))]
+    query: Option<String>,
+
+    /// When the fuzzy-finder starts with a query, only give it the lines which
+    /// could match the query. This makes it start faster on a large cache, but
+    /// clearing the query won't bring the rest back.
+    #[structopt(long)]
+    prefilter: bool,
+
+    /// Pick the attribute best matching the given query without showing the
+    /// fuzzy-finder. If several attributes match about equally well, the
+    /// fuzzy-finder is shown with the query filled in.
+    #[structopt(long, name = "QUERY", parse(from_str = sanitize_query))]
+    first_match: Option<String>,
+
+    /// With --first-match, fail rather than showing the fuzzy-finder if the
+    /// query is ambiguous or matches nothing.
+    #[structopt(long, requires = "QUERY")]
+    no_tui: bool,
+
+    /// Print the attributes matching the given query, best first, and print
+    /// them again whenever the cache changes, such as after a refresh in the
+    /// background. Quit with ctrl-c.
+    #[structopt(long, name = "WATCH_QUERY")]
+    watch: Option<String>,
+
+    /// Written after each selected attribute. Supports the escapes `\0` (for
+    /// `xargs -0`), `\n`, `\t`, and `\\`.
+    #[structopt(long, name = "SEP", default_value = "\\n", parse(from_str = unescape))]
+    output_separator: String,
+
+    /// What separates the attributes given to --attr-exists and --info-file:
+    /// `newline`, or `nul` for the output of `find -print0` and the like.
+    #[structopt(
+        long,
+        default_value = "newline",
+        possible_values = &["newline", "nul"],
+    )]
+    input_delimiter: String,
+
+    /// Which column of each selected line to print, counting from 1: 1 is the
+    /// attribute, 2 the package name, and 3 the description.
+    #[structopt(long, name = "FIELD", default_value = "1", parse(try_from_str = parse_field_number))]
+    select_field: usize,
+
+    /// When to use colors. --info uses colors unless this is `never`, so that
+    /// the preview pane is styled.
+    #[structopt(
+        long,
+        default_value = "auto",
+        possible_values = &["auto", "always", "never"],
+    )]
+    color: String,
+
+    /// What the fuzzy-finder matches against. It shows each package's
+    /// attribute, name, and description; `name-first` only matches against
+    /// the attribute and name, so searching for a tool's name isn't flooded by
+    /// packages mentioning it in their descriptions, and `even` matches
+    /// against the description too.
+    #[structopt(
+        long,
+        default_value = "even",
+        possible_values = &["name-first", "even"],
+    )]
+    rank: String,
+
+    /// What the fuzzy-finder's preview pane shows: `info` is the full --info
+    /// output, `plain` is the same without colors, `raw` is --info as JSON,
+    /// and `describe` is just the package's description, which is much faster
+    /// when it's cached.
+    #[structopt(
+        long,
+        default_value = "info",
+        possible_values = &["info", "plain", "raw", "describe"],
+    )]
+    preview_format: String,
+
+    /// Check that the given attribute exists and can be installed, exiting
+    /// with a non-zero status if it can't.
+    #[structopt(long, name = "CHECK_ATTR")]
+    check: Option<String>,
+
+    /// How to print --info and the selected attributes: `console` (styled),
+    /// `plain` (without colors), `json`, or `tsv` (with a header row). With
+    /// `json` or `tsv`, selected attributes are printed with their names and
+    /// descriptions.
+    #[structopt(
+        long,
+        default_value = "console",
+        possible_values = &["console", "plain", "json", "tsv"],
+    )]
+    format: OutputFormat,
+
+    /// Short for `--format json`. Also makes --check print the attribute's
+    /// exists, available, broken, and version, and prints errors like
+    /// `{"error": "...", "kind": "not_found"}`.
     #[structopt(long)]
-    print_cache: bool,
+    json: bool,
+
+    /// Print the one-line description of the given attribute and then quit.
+    /// The description is taken from the cache if possible.
+    #[structopt(long, name = "DESCRIBE_ATTR")]
+    describe: Option<String>,
+
+    /// Print the platforms the given attribute supports, comma-separated, and
+    /// then quit.
+    #[structopt(long, name = "PLATFORMS_ATTR")]
+    platforms: Option<String>,
 
     /// Prints the information for a given Nix attribute and then quit.
+    /// `@FILE` reads the attributes from a file, like --info-file.
     #[structopt(long)]
     info: Option<String>,
+
+    /// Prints the information for each attribute listed in the file, one per
+    /// line (see --input-delimiter), and then quit. Blank lines and lines
+    /// starting with `#` are skipped. The attributes are queried all at once;
+    /// --size and --all-systems only apply to a single --info attribute.
+    #[structopt(long, parse(from_os_str), conflicts_with = "info")]
+    info_file: Option<PathBuf>,
+
+    /// Read attributes from stdin, one per line (see --input-delimiter), and
+    /// print each followed by a tab and `OK` or `MISSING`, exiting with a
+    /// non-zero status if any are missing. Blank lines and lines starting with
+    /// `#` are skipped.
+    /// Attributes are looked up in the cache unless --authoritative is given.
+    #[structopt(long, conflicts_with_all = &["info", "info-file"])]
+    attr_exists: bool,
+
+    /// Check --attr-exists attributes by asking Nix rather than the cache.
+    /// Slower, but it doesn't matter how old the cache is.
+    #[structopt(long, requires = "attr-exists")]
+    authoritative: bool,
+
+    /// Use the attribute given to --info (and the like) exactly as written,
+    /// rather than resolving a bare name like `gzip` against the cache.
+    #[structopt(long)]
+    exact_attr: bool,
+
+    /// Show --info using only what the cache knows about the attribute, which
+    /// is much faster than asking Nix. Attributes that aren't cached, and
+    /// --format json and tsv, are looked up as usual.
+    #[structopt(long)]
+    fast: bool,
+
+    /// If Nix can't give --info for the attribute, try again with `nix-env
+    /// --json`, skipping entries that don't parse, before giving up.
+    #[structopt(long)]
+    complete_info: bool,
+
+    /// With --info, also show the closure size of the package's `out` output,
+    /// if it's in the Nix store. This runs two more Nix commands.
+    #[structopt(long)]
+    size: bool,
+
+    /// Render long descriptions in --info as Markdown.
+    #[structopt(long)]
+    markdown: bool,
+
+    /// Only print ASCII characters, for terminals which render Unicode
+    /// poorly.
+    #[structopt(long)]
+    ascii: bool,
+
+    /// Show --info for every entry Nix has for the attribute, such as one per
+    /// system, rather than just the first.
+    #[structopt(long)]
+    all_systems: bool,
+
+    /// Wrap descriptions in --info to this many columns. The fuzzy-finder
+    /// sets $COLUMNS to the width of the preview pane. Defaults to the
+    /// terminal's width, or no wrapping if output isn't a terminal. 0 also
+    /// turns wrapping off, and widths under 40 are treated as 40.
+    #[structopt(long, name = "COLS", env = "COLUMNS")]
+    width: Option<usize>,
+
+    /// Give up on --info (and so the preview pane) if Nix takes longer than
+    /// this many seconds, counting --complete-info's retry and --size.
+    #[structopt(long, name = "SECS", default_value = "10")]
+    timeout_info: u64,
+
+    /// Comma-separated list of fields to print with --info, in order. Valid
+    /// fields are attr, name, system, broken, available, priority, homepage,
+    /// download-page, description, long-description, license, maintainers,
+    /// teams, tests, and position.
+    #[structopt(long, use_delimiter = true)]
+    fields: Vec<nix::InfoField>,
 }
 
-fn main() -> Result<(), MainErr> {
+impl Opt {
+    /// The output format, taking --json into account.
+    fn output_format(&self) -> OutputFormat {
+        if self.json {
+            OutputFormat::Json
+        } else {
+            self.format
+        }
+    }
+}
+
+fn main() {
     let opt = Opt::from_args();
+    let json = opt.output_format() == OutputFormat::Json;
+    if let Err(e) = run(opt) {
+        if let MainErr::CheckFailed(_) = e {
+            // --check reports failure by its exit status alone.
+        } else if json {
+            // JSON consumers get the error on stdout, like the rest of the
+            // output.
+            println!("{}", e.to_json());
+        } else {
+            eprintln!("Error: {}", e.message());
+        }
+        std::process::exit(1);
+    }
+}
 
+fn run(opt: Opt) -> Result<(), MainErr> {
     let mut term = Term::stdout();
     let mut eterm = Term::stderr();
 
+    match opt.color.as_str() {
+        "always" => console::set_colors_enabled(true),
+        "never" => console::set_colors_enabled(false),
+        _ => {}
+    }
+    if opt.format == OutputFormat::Plain {
+        console::set_colors_enabled(false);
+    }
+    // --info is styled even when it's piped; see `info_styling`.
+    let shows_info = opt.info.is_some() || opt.info_file.is_some() || opt.color_test;
+    if shows_info && info_styling(&opt) == nix::Styling::Auto {
+        console::set_colors_enabled(true);
+    }
+
+    if let Some(dir) = &opt.cache_path {
+        cache::set_cache_dir(dir.clone());
+    }
+    if let Some(dir) = &opt.nix_bin {
+        nix::set_nix_bin(dir.clone());
+    }
+    if !opt.no_cache {
+        cache::migrate_legacy_cache()?;
+    }
+
     if opt.clear_cache {
-        term.write_line("Clearing the Nix package name cache.")?;
+        if !opt.quiet {
+            term.write_line("Clearing the Nix package name cache.")?;
+        }
         cache::clear_cache()?;
         return Ok(());
     }
 
-    if let Some(attr) = opt.info {
-        let was_using_colors = console::colors_enabled();
-        console::set_colors_enabled(true);
+    if opt.stats {
+        return print_stats(&mut term);
+    }
 
-        // write!(
-        //     term,
-        //     "{}",
-        //     style(format!("(Querying Nix for information about {})", attr)).dim()
-        // )?;
+    if opt.version_json {
+        return print_version_json(&mut term);
+    }
+
+    if opt.list_channels {
+        return list_channels(&mut term);
+    }
+
+    if opt.color_test {
+        return color_test(&opt, &mut term);
+    }
+
+    if let Some(base_attr) = &opt.refresh_extra {
+        let before = attrs_before_refresh();
+        let fresh = cache::refresh_extra(base_attr)?;
+        if !opt.quiet {
+            writeln!(
+                term,
+                "Refreshed {} attributes under {}.",
+                fresh.attrs.lines().count(),
+                base_attr
+            )?;
+            write_refresh_summary(&mut eterm, before, &cache::read_cache()?)?;
+        }
+        return Ok(());
+    }
+
+    if opt.forget {
+        if !opt.quiet {
+            term.write_line("Forgetting favorite attributes.")?;
+        }
+        cache::forget_favorites()?;
+        return Ok(());
+    }
+
+    if let Some(attr) = &opt.edit {
+        let attr = resolve_attr(attr, &opt, &mut eterm)?;
         let info = nix::nix_query(&attr)?;
+        let pos = info.position().ok_or(MainErr::NoPosition(attr))?;
+        proc::run_cmd_interactive(&mut editor_command(pos.path(), pos.line()))?;
+        return Ok(());
+    }
 
-        // term.clear_line()?;
-        write!(term, "{}", info.console_fmt())?;
+    if let Some(prefix) = &opt.complete_attr {
+        // Completion should never block on populating the cache.
+        if !cache::cache_exists() {
+            return Ok(());
+        }
+        let rich = cache::read_cache_header()?.rich;
+        let mut out = BufWriter::new(io::stdout().lock());
+        for line in cache::read_cache_lines()? {
+            let line = line?;
+            if !line.starts_with(prefix.as_str()) {
+                continue;
+            }
+            let line = nix::AttrLine::parse(&line, rich);
+            if opt.completions_preview {
+                writeln!(out, "{}:{}", line.attr, line.description)?;
+            } else {
+                writeln!(out, "{}", line.attr)?;
+            }
+        }
+        return Ok(());
+    }
 
-        console::set_colors_enabled(was_using_colors);
+    if let Some(attr) = &opt.check {
+        let attr = resolve_attr(attr, &opt, &mut eterm)?;
+        let status = nix::package_status(&attr)?;
+        if opt.output_format() == OutputFormat::Json {
+            writeln!(
+                term,
+                "{}",
+                serde_json::to_string(&status).map_err(CommandError::De)?
+            )?;
+        }
+        if !status.ok() {
+            return Err(MainErr::CheckFailed(attr));
+        }
         return Ok(());
     }
 
-    if !cache::cache_exists() {
-        // Let the user know we need to populate the cache.
-        writeln!(
-            eterm,
-            "{}",
-            style("Populating the Nix package name cache (this may take a minute or two)...")
-                .bold()
-                .green(),
-        )?;
+    if let Some(attr) = &opt.describe {
+        let attr = resolve_attr(attr, &opt, &mut eterm)?;
+        let description = match cached_description(&attr)? {
+            Some(description) => Some(description),
+            None => nix::nix_query(&attr)?.description().map(str::to_string),
+        };
+        match description {
+            Some(description) if !description.is_empty() => {
+                writeln!(term, "{}", description)?;
+                return Ok(());
+            }
+            _ => return Err(MainErr::NoDescription(attr)),
+        }
     }
 
-    let all_attrs = cache::ensure_cache()?;
+    if let Some(query) = &opt.watch {
+        return watch(query, &opt, &mut term);
+    }
 
-    if opt.print_cache {
-        term.write_str(&all_attrs)?;
+    if let Some(attr) = &opt.platforms {
+        let attr = resolve_attr(attr, &opt, &mut eterm)?;
+        let info = nix::nix_query(&attr)?;
+        if info.platforms().is_empty() {
+            return Err(MainErr::NoPlatforms(attr));
+        }
+        writeln!(term, "{}", info.platforms().join(","))?;
         return Ok(());
     }
 
-    for attr in skim_attrs()? {
-        writeln!(term, "{}", first_field(&attr).unwrap_or(&attr))?;
+    if let Some(attr) = &opt.reverse_deps {
+        let attr = resolve_attr(attr, &opt, &mut eterm)?;
+        let cache = if cache::cache_exists() {
+            cache::read_cache()?
+        } else {
+            String::new()
+        };
+        write!(
+            term,
+            "{}",
+            nix::nix_reverse_deps(&attr, &cache)?.console_fmt()
+        )?;
+        return Ok(());
     }
 
-    Ok(())
-}
+    let info_file = opt.info_file.clone().or_else(|| {
+        opt.info
+            .as_deref()
+            .and_then(|attr| attr.strip_prefix('@'))
+            .map(PathBuf::from)
+    });
+    if let Some(path) = info_file {
+        return info_batch(&path, &opt, &mut term, &mut eterm);
+    }
 
-fn first_field(s: &str) -> Option<&str> {
-    s.split(' ').next()
-}
+    if let Some(attr) = &opt.info {
+        let format = opt.output_format();
+        let styling = info_styling(&opt);
 
-fn skim_attrs() -> Result<Vec<String>, MainErr> {
-    use std::env;
-    use std::io::Cursor;
+        // write!(
+        //     term,
+        //     "{}",
+        //     style(format!("(Querying Nix for information about {})", attr)).dim()
+        // )?;
+        let attr = resolve_attr(attr, &opt, &mut eterm)?;
+        if opt.fast && format.is_human() {
+            if let Some((rich, line)) = cached_line(&attr)? {
+                write!(
+                    term,
+                    "{}",
+                    nix::AttrLine::parse(&line, rich).console_fmt_with(styling)
+                )?;
+                return Ok(());
+            }
+        }
+        let deadline = Instant::now() + Duration::from_secs(opt.timeout_info);
+        let remaining = || deadline.saturating_duration_since(Instant::now());
+        let timeout = remaining();
+        let infos = if opt.all_systems {
+            nix::nix_query_all_systems(&attr, timeout)
+        } else {
+            let info = match nix::nix_query_timeout(&attr, timeout) {
+                Err(e) if opt.complete_info && !is_timeout(&e) => {
+                    if opt.verbose {
+                        writeln!(
+                            eterm,
+                            "Couldn't query {} with `nix eval`; retrying with `nix-env --json`.",
+                            attr,
+                        )?;
+                    }
+                    nix::nix_query_lenient(&attr, remaining())
+                }
+                result => result,
+            };
+            info.map(|info| vec![info])
+        };
+        let infos = match infos {
+            Err(nix::NixQueryError::Command(CommandError::Timeout(_))) if format.is_human() => {
+                writeln!(
+                    term,
+                    "Preview timed out after {} seconds.",
+                    opt.timeout_info
+                )?;
+                return Ok(());
+            }
+            result => result?,
+        };
 
-    let preview_cmd = format!(
-        "{exe} --info {{1}}",
-        exe = env::current_exe()
-            .map(|p| p.to_string_lossy().into_owned())
-            .unwrap_or_else(|_| "nix-query".to_string()),
-    );
+        let width = output_width(&opt, &term);
 
-    let options = SkimOptionsBuilder::default()
-        .height(Some("100%"))
-        .multi(true)
-        .preview(Some(&preview_cmd))
-        .preview_window(Some("down:wrap:50%"))
-        .tiebreak(Some("score,end".to_string()))
-        .no_hscroll(true)
-        .delimiter(Some(nix::FIELD_DELIMITER))
-        .nth(None) // fields to search
-        .with_nth(Some("1")) // fields to show
-        .build()
-        .unwrap();
+        // term.clear_line()?;
+        let fields = if opt.fields.is_empty() {
+            nix::InfoField::ALL
+        } else {
+            &opt.fields
+        };
+        if !format.is_human() {
+            let size = if opt.size {
+                Some(nix::nix_closure_size(&attr, remaining())?)
+            } else {
+                None
+            };
+            return write_info_records(&mut term, &infos, fields, size, format, opt.all_systems);
+        }
+        for (inx, info) in infos.iter().enumerate() {
+            if inx > 0 {
+                writeln!(term)?;
+            }
+            write!(
+                term,
+                "{}",
+                info.console_fmt_fields(fields)
+                    .with_styling(styling)
+                    .with_markdown(opt.markdown)
+                    .with_width(width)
+                    .with_charset(charset(&opt))
+            )?;
+        }
+        if opt.size {
+            let size = nix::nix_closure_size(&attr, remaining())?;
+            write!(term, "{}", nix::ConsoleFormatSize::new(size, styling))?;
+        }
+        return Ok(());
+    }
 
-    let input = cache::ensure_cache()?;
+    let populate_opts = nix::PopulateOptions {
+        rich: opt.rich
+            || opt.supported_only
+            || opt.has_tests
+            || opt.hide_broken
+            || opt.hide_unfree
+            || opt.strict,
+        skip_extra_attrs: opt.no_extra_attrs,
+        strict: opt.strict,
+        include_private: opt.include_private,
+    };
 
-    Ok(Skim::run_with(&options, Some(Box::new(Cursor::new(input))))
-        .map(|out| out.selected_items)
-        .map(|items| {
-            items
-                .iter()
-                .map(|i| i.get_text())
-                .map(str::to_string)
-                .collect()
-        })
-        .unwrap_or_else(Vec::new))
-}
+    if opt.benchmark {
+        return benchmark(&populate_opts, &mut term);
+    }
 
-pub fn check_pkg_schemas() {
-    use std::process::Command;
+    if opt.migrate_cache {
+        match cache::migrate_cache()? {
+            cache::Migration::Current => {
+                if !opt.quiet {
+                    writeln!(term, "The cache is already in the current format.")?;
+                }
+            }
+            cache::Migration::Migrated(version) => {
+                if !opt.quiet {
+                    writeln!(
+                        term,
+                        "Migrated the cache from format version {} to {}.",
+                        version,
+                        cache::CACHE_FORMAT_VERSION
+                    )?;
+                }
+            }
+            cache::Migration::Missing => {
+                if !opt.quiet {
+                    writeln!(
+                        eterm,
+                        "{}",
+                        style("There's no cache to migrate; building it (this may take a minute or two)...")
+                            .bold()
+                            .green()
+                    )?;
+                }
+                cache::populate_cache(&populate_opts)?;
+            }
+            cache::Migration::NeedsRebuild => {
+                if !opt.quiet {
+                    writeln!(
+                        eterm,
+                        "{}",
+                        style("The cache can't be migrated; rebuilding it (this may take a minute or two)...")
+                            .bold()
+                            .green()
+                    )?;
+                }
+                cache::populate_cache(&populate_opts)?;
+            }
+        }
+        return Ok(());
+    }
 
-    use nix_query::proc;
+    if opt.attr_exists && opt.authoritative {
+        return attr_exists(None, &opt, &mut term, &mut eterm);
+    }
 
-    println!("Reading cache.");
-    let mut lines: Vec<String> = cache::ensure_cache()
-        .expect("Can read from cache")
-        .lines()
-        .by_ref()
-        .map(|s| s.to_string())
-        .collect();
+    if let Some(max_age) = opt.refresh_if_older_than {
+        let fresh = match cache::cache_age()? {
+            Some(age) => age <= max_age && cache::cache_satisfies(&populate_opts),
+            None => false,
+        };
+        if fresh {
+            if !opt.quiet {
+                writeln!(term, "The cache is up to date; not refreshing it.")?;
+            }
+        } else {
+            let before = attrs_before_refresh();
+            let all = cache::populate_cache(&populate_opts)?;
+            if !opt.quiet {
+                writeln!(term, "Refreshed the cache.")?;
+                write_refresh_summary(&mut eterm, before, &all.attrs)?;
+            }
+        }
+        return Ok(());
+    }
 
-    println!("Sorting cache.");
-    lines.sort_unstable();
+    if let Some(count) = opt.print_cache {
+        if streams_print_cache(&opt) && cache::cache_satisfies(&populate_opts) {
+            return print_cache_lines(&opt, count);
+        }
+    }
 
-    println!("Checking.");
-    let mut skipping = true;
-    for (inx, attr) in lines.iter().enumerate() {
-        if attr.starts_with("nixpkgs._")
-            || attr.starts_with("nixos._")
-            || attr.starts_with("unstable._")
-        {
-            continue;
+    let all_attrs = if !opt.no_cache && cache::cache_satisfies(&populate_opts) {
+        cache::read_cache()?
+    } else {
+        if !opt.quiet && !opt.no_cache && cache::cache_exists() {
+            let header = cache::read_cache_header()?;
+            if !header.is_current_format() {
+                writeln!(eterm, "The cache format changed; rebuilding the cache.")?;
+            } else if !header.source_matches(nix::source_descriptor().as_deref()) {
+                writeln!(eterm, "Your Nix channels changed; rebuilding the cache.")?;
+            }
+        }
+
+        if !opt.quiet && opt.no_cache {
+            writeln!(
+                eterm,
+                "{}",
+                style("Querying Nix for packages (this may take a minute or two)...")
+                    .bold()
+                    .green(),
+            )?;
+        } else if !opt.quiet {
+            // Let the user know we need to populate the cache.
+            writeln!(
+                eterm,
+                "{}",
+                style("Populating the Nix package name cache (this may take a minute or two)...")
+                    .bold()
+                    .green(),
+            )?;
         }
 
-        if skipping {
-            if attr.starts_with("nixpkgs.lzip") {
-                skipping = false;
+        if opt.verbose {
+            let backend = if opt.no_cache {
+                nix::detect_backend().map_err(MainErr::from)
             } else {
-                continue;
+                cache::backend_info().map_err(MainErr::from)
+            };
+            // Population doesn't depend on the backend, so failing to detect
+            // it isn't fatal.
+            match backend {
+                Ok(backend) => writeln!(
+                    eterm,
+                    "Using the {} backend (Nix {}).",
+                    backend.backend.name(),
+                    backend.version
+                )?,
+                Err(e) => writeln!(eterm, "Couldn't detect the backend: {}", e.message())?,
             }
         }
 
-        let json = proc::run_cmd_stdout(Command::new("nix-env").args(&[
-            "--query",
-            "--available",
-            "--json",
-            "--attr",
-            &attr,
-        ]))
-        .unwrap_or_else(|_| panic!("Can query Nix for information about attribute {}", attr));
-
-        match serde_json::from_str::<nix::AllNixInfo>(&json) {
-            Err(d) => {
-                println!("{} | {}\n\t{}", attr, d, json);
+        let before = if opt.no_cache {
+            None
+        } else {
+            attrs_before_refresh()
+        };
+        let populated = if opt.no_cache {
+            cache::query_attrs(&populate_opts)
+        } else {
+            cache::populate_cache(&populate_opts)
+        };
+        let all = match populated {
+            Err(CacheIoError::EmptyPopulation) => {
+                writeln!(
+                    eterm,
+                    "{}\nAre your channels set up? Try running `nix-channel --update`.",
+                    style("nix-env didn't find any packages, so the cache wasn't written.")
+                        .bold()
+                        .red(),
+                )?;
+                return Err(CacheIoError::EmptyPopulation.into());
             }
-            Ok(i) => {
-                println!(
-                    "OK: {attr} [{inx}/{len}]",
-                    attr = attr,
-                    inx = inx,
-                    len = lines.len(),
-                );
-                i.attrs.values().next().unwrap_or_else(|| {
-                    panic!("String -> NixInfo map for {} has at least one value.", attr)
-                });
+            result => result?,
+        };
+        if opt.verbose && !all.collisions.is_empty() {
+            writeln!(
+                eterm,
+                "{} attribute collisions detected:",
+                all.collisions.len()
+            )?;
+            for name in &all.collisions {
+                writeln!(eterm, "    {}", name)?;
             }
         }
+        if opt.verbose && all.duplicates > 0 {
+            writeln!(
+                eterm,
+                "Removed {} duplicate attribute lines.",
+                all.duplicates
+            )?;
+        }
+        if opt.verbose && !all.unparsed.is_empty() {
+            writeln!(
+                eterm,
+                "Left out {} packages whose metadata didn't parse (see --strict).",
+                all.unparsed.len()
+            )?;
+        }
+        if !opt.quiet {
+            write_refresh_summary(&mut eterm, before, &all.attrs)?;
+        }
+        all.attrs
+    };
+
+    if opt.attr_exists {
+        return attr_exists(Some(&all_attrs), &opt, &mut term, &mut eterm);
+    }
+
+    let mut selection = LineSelection::new(&opt);
+    if selection.auto_channel && !all_attrs.lines().any(|line| selection.in_channel(line)) {
+        selection.channel = None;
+    }
+    let all_attrs = if selection.keeps_all() {
+        all_attrs
+    } else {
+        filter_lines(&all_attrs, |line| selection.keeps(line))
+    };
+
+    let all_attrs = match opt.sort_by.as_deref() {
+        Some("version") => nix::sort_attr_lines_by_version(&all_attrs, &opt.channel_priority),
+        Some(_) => nix::sort_attr_lines(&all_attrs, &opt.channel_priority),
+        None if opt.sort => nix::sort_attr_lines(&all_attrs, &opt.channel_priority),
+        None => all_attrs,
+    };
+
+    if opt.audit_schemas {
+        return audit_schemas(&all_attrs, opt.start_at.as_deref(), &opt, &mut term);
+    }
+
+    if let Some(path) = &opt.snapshot {
+        cache::write_snapshot(path)?;
+        if !opt.quiet {
+            writeln!(
+                eterm,
+                "Saved a snapshot of the cache to {}.",
+                path.display()
+            )?;
+        }
+        return Ok(());
+    }
+
+    if let Some(path) = &opt.diff {
+        let old = cache::read_snapshot(path)?;
+        write_diff(&mut term, &diff::diff_attrs(&old, &all_attrs))?;
+        return Ok(());
+    }
+
+    let mut filter = nix::LineFilter {
+        hide_broken: opt.hide_broken,
+        hide_unfree: opt.hide_unfree,
+    };
+
+    // Filtering and structured output need the broken and free columns of a rich cache.
+    let rich_cache = if opt.no_cache {
+        populate_opts.rich
+    } else {
+        cache::read_cache_header().is_ok_and(|header| header.rich)
+    };
+
+    if let Some(count) = opt.print_cache {
+        // Term is meant for interactive output; for bulk output, a buffered
+        // stdout is much faster.
+        let stdout = io::stdout();
+        let mut out = BufWriter::new(stdout.lock());
+        let format = opt.output_format();
+        if opt.json_lines || !format.is_human() {
+            let lines: Vec<&str> = match count {
+                None => all_attrs.lines().collect(),
+                Some(n) => slice_lines(&all_attrs, n, opt.tail),
+            };
+            let records = lines.into_iter().map(|line| {
+                let line = nix::AttrLine::parse(line, rich_cache);
+                (line.attr.trim(), line)
+            });
+            if opt.json_lines {
+                write_attr_json_lines(&mut out, records)?;
+            } else {
+                write_attr_records(&mut out, records, format)?;
+            }
+        } else {
+            match count {
+                None => out.write_all(all_attrs.as_bytes())?,
+                Some(n) => {
+                    for line in slice_lines(&all_attrs, n, opt.tail) {
+                        writeln!(out, "{}", line)?;
+                    }
+                }
+            }
+        }
+        out.flush()?;
+        return Ok(());
+    }
+
+    let favorites = cache::read_favorites()?;
+    let all_attrs = favorites_first(&all_attrs, &favorites, opt.favorites);
+
+    let stripped_prefix = shown_prefix(&opt);
+    let all_attrs = if stripped_prefix.is_empty() {
+        all_attrs
+    } else {
+        map_lines(&all_attrs, |line| {
+            line.strip_prefix(stripped_prefix).unwrap_or(line)
+        })
+    };
+
+    let mut query = opt.query.clone().unwrap_or_default();
+    let mut first = None;
+    if let Some(first_query) = &opt.first_match {
+        query = first_query.clone();
+        let input = if rich_cache {
+            filter.apply(&all_attrs)
+        } else {
+            all_attrs.clone()
+        };
+        match first_match(&input, first_query, opt.rank == "name-first", rich_cache) {
+            FirstMatch::Found(attr) => first = Some(vec![attr.to_string()]),
+            FirstMatch::Ambiguous(best) if opt.no_tui => {
+                return Err(MainErr::AmbiguousMatch(
+                    first_query.clone(),
+                    best.into_iter().map(str::to_string).collect(),
+                ));
+            }
+            FirstMatch::NotFound if opt.no_tui => {
+                return Err(MainErr::NoMatch(first_query.clone()));
+            }
+            _ => {}
+        }
+    }
+
+    let picked = match first {
+        Some(picked) => picked,
+        None => {
+            if !has_terminal() {
+                return Err(MainErr::NoTerminal);
+            }
+            let picker = config::read_config()?.picker;
+            loop {
+                let (mut input, toggles) = if rich_cache {
+                    (filter.apply(&all_attrs), Some(filter))
+                } else {
+                    (all_attrs.clone(), None)
+                };
+                if opt.prefilter {
+                    if let Some(filtered) = prefilter(&input, &query) {
+                        input = filtered;
+                    }
+                }
+                if let Some(max) = opt.max_input {
+                    if truncate_lines(&mut input, max) && !opt.quiet {
+                        writeln!(
+                            eterm,
+                            "Only showing the first {} attributes (see --max-input).",
+                            max
+                        )?;
+                    }
+                }
+                input = picker_lines(&input, rich_cache);
+                if let Some(width) = opt.max_attr_width {
+                    input = with_short_attrs(&input, width, charset(&opt).ellipsis());
+                }
+                if opt.group_by_channel {
+                    input = group_by_channel(&input, charset(&opt));
+                }
+                let pick = skim_attrs(input, &opt, &picker, &query, toggles)?;
+                query = pick.query;
+                match pick.action {
+                    PickAction::Accept => break pick.selections,
+                    PickAction::Abort => break Vec::new(),
+                    PickAction::ToggleBroken => filter.hide_broken = !filter.hide_broken,
+                    PickAction::ToggleUnfree => filter.hide_unfree = !filter.hide_unfree,
+                }
+            }
+        }
+    };
+
+    let selected: Vec<String> = picked
+        .iter()
+        .map(|attr| format!("{}{}", stripped_prefix, attr))
+        .collect();
+    if !opt.no_cache {
+        cache::record_favorites(&selected)?;
+    }
+    if opt.menu {
+        for attr in &selected {
+            run_menu(attr, &opt, &mut term, &mut eterm)?;
+        }
+    } else {
+        let lines = attr_lines(&all_attrs, &picked);
+        write_selections(&mut term, &selected, &picked, &lines, rich_cache, &opt)?;
+    }
+
+    Ok(())
+}
+
+fn is_timeout(e: &nix::NixQueryError) -> bool {
+    matches!(e, nix::NixQueryError::Command(CommandError::Timeout(_)))
+}
+
+/// The lines of `all_attrs` for the given attributes, keyed by attribute.
+fn attr_lines<'a>(all_attrs: &'a str, attrs: &[String]) -> HashMap<&'a str, &'a str> {
+    all_attrs
+        .lines()
+        .map(|line| (line_attr(line), line))
+        .filter(|(attr, _)| attrs.iter().any(|wanted| wanted == attr))
+        .collect()
+}
+
+/// Prints the selected attributes in the --format. `picked` are the
+/// attributes as shown in the fuzzy-finder, which `lines` is keyed by, and
+/// `selected` are the same attributes with any stripped prefix put back.
+fn write_selections(
+    term: &mut Term,
+    selected: &[String],
+    picked: &[String],
+    lines: &HashMap<&str, &str>,
+    rich: bool,
+    opt: &Opt,
+) -> Result<(), MainErr> {
+    let format = opt.output_format();
+    if format.is_human() {
+        for (attr, shown) in selected.iter().zip(picked) {
+            let field = if opt.select_field == 1 {
+                attr
+            } else {
+                lines
+                    .get(shown.as_str())
+                    .map_or("", |line| line_field(line, opt.select_field, rich))
+            };
+            write!(term, "{}{}", field, opt.output_separator)?;
+        }
+        return Ok(());
+    }
+
+    let records = selected.iter().zip(picked).map(|(attr, shown)| {
+        let line = lines
+            .get(shown.as_str())
+            .map(|line| nix::AttrLine::parse(line, rich))
+            .unwrap_or_default();
+        (attr.as_str(), line)
+    });
+    write_attr_records(term, records, format)?;
+    Ok(())
+}
+
+/// Writes attributes with their names and descriptions as JSON (an array of
+/// objects) or TSV (with a header row).
+fn write_attr_records<'a>(
+    out: &mut impl Write,
+    records: impl Iterator<Item = (&'a str, nix::AttrLine<'a>)>,
+    format: OutputFormat,
+) -> io::Result<()> {
+    if format == OutputFormat::Json {
+        let records: Vec<_> = records
+            .map(|(attr, line)| attr_record(attr, &line))
+            .collect();
+        writeln!(out, "{}", serde_json::Value::from(records))
+    } else {
+        writeln!(out, "{}", TSV_HEADER)?;
+        for (attr, line) in records {
+            write_attr_tsv(out, attr, &line)?;
+        }
+        Ok(())
+    }
+}
+
+/// The header row of `write_attr_records`'s TSV output.
+const TSV_HEADER: &str = "attr\tname\tdescription";
+
+/// Writes an attribute's row of `write_attr_records`'s TSV output.
+fn write_attr_tsv(out: &mut impl Write, attr: &str, line: &nix::AttrLine) -> io::Result<()> {
+    writeln!(
+        out,
+        "{}\t{}\t{}",
+        attr,
+        line.name.trim(),
+        line.description.trim()
+    )
+}
+
+/// Whether --print-cache can be printed straight from the cache file, a line
+/// at a time: the lines aren't sorted, and no option that's handled before
+/// --print-cache is given.
+fn streams_print_cache(opt: &Opt) -> bool {
+    !opt.no_cache
+        && !opt.sort
+        && opt.sort_by.is_none()
+        && !opt.attr_exists
+        && !opt.audit_schemas
+        && opt.snapshot.is_none()
+        && opt.diff.is_none()
+}
+
+/// Prints --print-cache from the cache file a line at a time, so memory use
+/// doesn't grow with the number of packages. With --tail, only the last
+/// `count` lines are held.
+fn print_cache_lines(opt: &Opt, count: Option<usize>) -> Result<(), MainErr> {
+    let rich = cache::read_cache_header()?.rich;
+    let mut selection = LineSelection::new(opt);
+    if selection.auto_channel {
+        // Finding out whether to fall back to every channel takes a pass of
+        // its own.
+        let mut in_channel = false;
+        for line in cache::read_cache_lines()? {
+            if selection.in_channel(&line?) {
+                in_channel = true;
+                break;
+            }
+        }
+        if !in_channel {
+            selection.channel = None;
+        }
+    }
+    let lines = cache::read_cache_lines()?.filter(|line| match line {
+        Ok(line) => selection.keeps(line),
+        Err(_) => true,
+    });
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let format = opt.output_format();
+    // A JSON array is written an element at a time, like `write_attr_records`
+    // would write it all at once.
+    let json_array = format == OutputFormat::Json && !opt.json_lines;
+    if json_array {
+        write!(out, "[")?;
+    } else if format == OutputFormat::Tsv && !opt.json_lines {
+        writeln!(out, "{}", TSV_HEADER)?;
+    }
+    let mut first = true;
+    let mut write_line = |line: &str| {
+        if format.is_human() && !opt.json_lines {
+            return writeln!(out, "{}", line);
+        }
+        let line = nix::AttrLine::parse(line, rich);
+        let attr = line.attr.trim();
+        if opt.json_lines {
+            writeln!(out, "{}", attr_record(attr, &line))
+        } else if json_array {
+            if !first {
+                write!(out, ",")?;
+            }
+            first = false;
+            write!(out, "{}", attr_record(attr, &line))
+        } else {
+            write_attr_tsv(&mut out, attr, &line)
+        }
+    };
+    match count {
+        Some(n) if opt.tail => {
+            let mut last = VecDeque::with_capacity(n);
+            for line in lines {
+                last.push_back(line?);
+                if last.len() > n {
+                    last.pop_front();
+                }
+            }
+            for line in &last {
+                write_line(line)?;
+            }
+        }
+        Some(n) => {
+            for line in lines.take(n) {
+                write_line(&line?)?;
+            }
+        }
+        None => {
+            for line in lines {
+                write_line(&line?)?;
+            }
+        }
+    }
+    if json_array {
+        writeln!(out, "]")?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Writes each attribute as a JSON object on its own line, as it's read, for
+/// --json-lines.
+fn write_attr_json_lines<'a>(
+    out: &mut impl Write,
+    records: impl Iterator<Item = (&'a str, nix::AttrLine<'a>)>,
+) -> io::Result<()> {
+    for (attr, line) in records {
+        writeln!(out, "{}", attr_record(attr, &line))?;
+    }
+    Ok(())
+}
+
+/// An attribute with its name and description, for JSON output.
+fn attr_record(attr: &str, line: &nix::AttrLine) -> serde_json::Value {
+    serde_json::json!({
+        "attr": attr,
+        "name": line.name.trim(),
+        "description": line.description.trim(),
+    })
+}
+
+/// Prints --info for `infos` as JSON or TSV. `size` is the closure size, if
+/// --size was given. With --all-systems, JSON output is an array with an
+/// object for each system; otherwise it's a single object.
+fn write_info_records(
+    term: &mut Term,
+    infos: &[nix::NixInfo],
+    fields: &[nix::InfoField],
+    size: Option<Option<u64>>,
+    format: OutputFormat,
+    all_systems: bool,
+) -> Result<(), MainErr> {
+    if format == OutputFormat::Json {
+        let mut records: Vec<_> = infos
+            .iter()
+            .map(|info| {
+                let mut record = info.json_fields(fields);
+                if let Some(size) = size {
+                    record.insert("closure-size".to_string(), size.into());
+                }
+                serde_json::Value::Object(record)
+            })
+            .collect();
+        let json = if all_systems || records.len() != 1 {
+            serde_json::Value::from(records)
+        } else {
+            records.remove(0)
+        };
+        writeln!(term, "{}", json)?;
+    } else {
+        let mut header: Vec<_> = fields.iter().map(|field| field.name()).collect();
+        if size.is_some() {
+            header.push("closure-size");
+        }
+        writeln!(term, "{}", header.join("\t"))?;
+        for info in infos {
+            write!(term, "{}", info.tsv_fields(fields))?;
+            match size {
+                Some(Some(size)) => writeln!(term, "\t{}", size)?,
+                Some(None) => writeln!(term, "\t")?,
+                None => writeln!(term)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How to style --info. Unlike other output, --info is styled even when
+/// stdout isn't a terminal (unless `--color never`), so the preview pane is
+/// styled; `run` enables colors for it.
+fn info_styling(opt: &Opt) -> nix::Styling {
+    if opt.color == "never" || opt.output_format() != OutputFormat::Console {
+        nix::Styling::Plain
+    } else {
+        nix::Styling::Auto
+    }
+}
+
+/// The attributes listed in an --info-file or given to --attr-exists,
+/// separated by `delimiter`, skipping blank lines and `#` comments.
+fn parse_attr_list(text: &str, delimiter: char) -> Vec<&str> {
+    text.split(delimiter)
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect()
+}
+
+/// The character --input-delimiter names.
+fn input_delimiter(opt: &Opt) -> char {
+    match opt.input_delimiter.as_str() {
+        "nul" => '\0',
+        _ => '\n',
+    }
+}
+
+/// Prints whether each attribute given on stdin exists, for --attr-exists.
+/// Attributes are looked up in `all_attrs` if given, and otherwise in Nix.
+/// Fails with `MainErr::MissingAttrs` if any are missing.
+fn attr_exists(
+    all_attrs: Option<&str>,
+    opt: &Opt,
+    term: &mut Term,
+    eterm: &mut Term,
+) -> Result<(), MainErr> {
+    let text = io::read_to_string(io::stdin())?;
+    let attrs = parse_attr_list(&text, input_delimiter(opt));
+    let exists = match all_attrs {
+        Some(all_attrs) => {
+            let known: HashSet<&str> = all_attrs.lines().map(line_attr).collect();
+            attrs
+                .iter()
+                .map(|attr| {
+                    let resolved = if opt.exact_attr {
+                        attr.to_string()
+                    } else {
+                        resolve_attr_in(all_attrs, attr, eterm)?
+                    };
+                    Ok(known.contains(resolved.as_str()))
+                })
+                .collect::<Result<Vec<_>, MainErr>>()?
+        }
+        None => {
+            let resolved = attrs
+                .iter()
+                .map(|attr| resolve_attr(attr, opt, eterm))
+                .collect::<Result<Vec<_>, _>>()?;
+            let resolved: Vec<&str> = resolved.iter().map(String::as_str).collect();
+            nix::nix_attrs_exist(&resolved, Duration::from_secs(opt.timeout_info))?
+        }
+    };
+
+    let mut missing = Vec::new();
+    for (attr, exists) in attrs.iter().zip(&exists) {
+        writeln!(term, "{}\t{}", attr, if *exists { "OK" } else { "MISSING" })?;
+        if !exists {
+            missing.push(attr.to_string());
+        }
+    }
+    if !missing.is_empty() {
+        return Err(MainErr::MissingAttrs(missing));
+    }
+    Ok(())
+}
+
+/// Prints --info for each attribute listed in the file at `path`. Fails with
+/// `MainErr::MissingAttrs` afterwards if Nix doesn't have some of them.
+fn info_batch(
+    path: &std::path::Path,
+    opt: &Opt,
+    term: &mut Term,
+    eterm: &mut Term,
+) -> Result<(), MainErr> {
+    let text = std::fs::read_to_string(path)?;
+    let attrs = parse_attr_list(&text, input_delimiter(opt))
+        .into_iter()
+        .map(|attr| resolve_attr(attr, opt, eterm))
+        .collect::<Result<Vec<_>, _>>()?;
+    if attrs.is_empty() {
+        return Ok(());
+    }
+    let attrs: Vec<&str> = attrs.iter().map(String::as_str).collect();
+    let batch = nix::nix_query_many_timeout(&attrs, Duration::from_secs(opt.timeout_info))?;
+
+    let format = opt.output_format();
+    let fields = if opt.fields.is_empty() {
+        nix::InfoField::ALL
+    } else {
+        &opt.fields
+    };
+    if !format.is_human() {
+        write_info_records(term, &batch.found, fields, None, format, true)?;
+    } else {
+        let styling = info_styling(opt);
+        let width = output_width(opt, term);
+        for (inx, info) in batch.found.iter().enumerate() {
+            if inx > 0 {
+                writeln!(term)?;
+            }
+            write!(
+                term,
+                "{}",
+                info.console_fmt_fields(fields)
+                    .with_styling(styling)
+                    .with_markdown(opt.markdown)
+                    .with_width(width)
+                    .with_charset(charset(opt))
+            )?;
+        }
+    }
+
+    // The rest of the output is still useful, so missing attributes are
+    // only reported once it's all printed.
+    if !batch.missing.is_empty() {
+        return Err(MainErr::MissingAttrs(batch.missing));
+    }
+    Ok(())
+}
+
+/// Prints `nix::color_test_infos` like --info, for --color-test.
+fn color_test(opt: &Opt, term: &mut Term) -> Result<(), MainErr> {
+    let styling = info_styling(opt);
+    let width = output_width(opt, term);
+    for (inx, info) in nix::color_test_infos().iter().enumerate() {
+        if inx > 0 {
+            writeln!(term)?;
+        }
+        write!(
+            term,
+            "{}",
+            info.console_fmt()
+                .with_styling(styling)
+                .with_markdown(true)
+                .with_width(width)
+                .with_charset(charset(opt))
+        )?;
+    }
+    Ok(())
+}
+
+/// Prints each channel's name, version, and URL, for --list-channels.
+fn list_channels(term: &mut Term) -> Result<(), MainErr> {
+    let channels = nix::list_channels()?;
+    if channels.is_empty() {
+        writeln!(
+            term,
+            "You don't have any channels; add one with `nix-channel --add URL`."
+        )?;
+        return Ok(());
+    }
+    for channel in channels {
+        let version = nix::channel_version(&channel.name);
+        writeln!(
+            term,
+            "{} {} {}",
+            style(&channel.name).bold(),
+            version.as_deref().unwrap_or("(unknown version)"),
+            style(channel.url.as_deref().unwrap_or("(root's channel)")).dim()
+        )?;
+    }
+    Ok(())
+}
+
+/// An action offered by `--menu`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MenuAction {
+    Install,
+    Shell,
+    Run,
+    OpenHomepage,
+    ShowInfo,
+}
+
+impl MenuAction {
+    const ALL: &'static [MenuAction] = &[
+        MenuAction::Install,
+        MenuAction::Shell,
+        MenuAction::Run,
+        MenuAction::OpenHomepage,
+        MenuAction::ShowInfo,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            MenuAction::Install => "Install",
+            MenuAction::Shell => "Shell",
+            MenuAction::Run => "Run",
+            MenuAction::OpenHomepage => "Open homepage",
+            MenuAction::ShowInfo => "Show info",
+        }
+    }
+}
+
+/// Asks which `MenuAction` to take on `attr`, then takes it.
+fn run_menu(attr: &str, opt: &Opt, term: &mut Term, eterm: &mut Term) -> Result<(), MainErr> {
+    writeln!(eterm, "{}", style(attr).bold().green())?;
+    for (inx, action) in MenuAction::ALL.iter().enumerate() {
+        writeln!(eterm, "  {}) {}", inx + 1, action.label())?;
+    }
+    write!(eterm, "Action (blank to skip): ")?;
+    let choice = eterm.read_line()?;
+    let choice = match choice.trim().parse::<usize>() {
+        Ok(n) if (1..=MenuAction::ALL.len()).contains(&n) => MenuAction::ALL[n - 1],
+        _ => return Ok(()),
+    };
+
+    // nix-shell wants attributes relative to <nixpkgs>, without a channel.
+    let bare_attr = attr.split_once('.').map_or(attr, |(_, rest)| rest);
+    match choice {
+        MenuAction::Install => install(attr, opt.install_priority, eterm)?,
+        MenuAction::Shell => proc::run_cmd_interactive(
+            nix::nix_command("nix-shell").args(["--packages", bare_attr]),
+        )?,
+        MenuAction::Run => {
+            let info = nix::nix_query(attr)?;
+            proc::run_cmd_interactive(nix::nix_command("nix-shell").args([
+                "--packages",
+                bare_attr,
+                "--run",
+                info.pname(),
+            ]))?
+        }
+        MenuAction::OpenHomepage => match nix::nix_query(attr)?.homepage() {
+            Some(url) => proc::run_cmd_interactive(Command::new(OPEN_COMMAND).arg(url))?,
+            None => writeln!(eterm, "{} doesn't have a homepage.", attr)?,
+        },
+        MenuAction::ShowInfo => write!(
+            term,
+            "{}",
+            nix::nix_query(attr)?
+                .console_fmt()
+                .with_charset(charset(opt))
+        )?,
+    }
+    Ok(())
+}
+
+/// Installs `attr` with nix-env, then sets its priority, if given. If it
+/// collides with an installed package, offers to give that package a lower
+/// priority and try again.
+fn install(attr: &str, priority: Option<i32>, eterm: &mut Term) -> Result<(), MainErr> {
+    let install_cmd = || {
+        let mut cmd = nix::nix_command("nix-env");
+        cmd.args(["--install", "--attr", attr]);
+        cmd
+    };
+    // nix-env's output is shown as it runs; it's captured to look for
+    // collisions.
+    match proc::run_cmd_interactive_stderr(&mut install_cmd()) {
+        Err(CommandError::Stderr(stderr)) => {
+            let collision = match nix::parse_priority_collision(&stderr) {
+                Some(collision) => collision,
+                None => return Err(CommandError::Stderr(stderr).into()),
+            };
+            let name = nix::nix_query(attr)?.name().to_string();
+            let installed = match collision.names.iter().find(|n| **n != name) {
+                Some(installed) => installed.clone(),
+                None => return Err(CommandError::Stderr(stderr).into()),
+            };
+            // Lower the installed package below the new one.
+            let lower = priority.unwrap_or(collision.priority) + 1;
+            write!(
+                eterm,
+                "{} collides with {}. Give {} priority {} and try again? [y/N] ",
+                style(attr).bold(),
+                style(&installed).bold(),
+                installed,
+                lower
+            )?;
+            if !eterm.read_line()?.trim().eq_ignore_ascii_case("y") {
+                return Err(CommandError::Stderr(stderr).into());
+            }
+            set_priority(&installed, lower)?;
+            proc::run_cmd_interactive_stderr(&mut install_cmd())?;
+        }
+        result => {
+            result?;
+        }
+    }
+
+    if let Some(priority) = priority {
+        let name = nix::nix_query(attr)?.name().to_string();
+        set_priority(&name, priority)?;
+    }
+    Ok(())
+}
+
+/// Sets the priority of the installed package `name`.
+fn set_priority(name: &str, priority: i32) -> Result<(), MainErr> {
+    proc::run_cmd_interactive(nix::nix_command("nix-env").args([
+        "--set-flag",
+        "priority",
+        &priority.to_string(),
+        name,
+    ]))?;
+    Ok(())
+}
+
+/// The command that opens a URL in the user's browser.
+#[cfg(target_os = "macos")]
+const OPEN_COMMAND: &str = "open";
+#[cfg(not(target_os = "macos"))]
+const OPEN_COMMAND: &str = "xdg-open";
+
+/// Resolves a possibly-unqualified attribute (like `gzip`) against the cache.
+/// Attributes that aren't in the cache (or if there is no cache) are passed
+/// through unchanged, as are all attributes with --exact-attr. If there are
+/// several matches, they're listed on `eterm`.
+fn resolve_attr(attr: &str, opt: &Opt, eterm: &mut Term) -> Result<String, MainErr> {
+    if opt.exact_attr || !cache::cache_exists() {
+        return Ok(attr.to_string());
+    }
+
+    resolve_attr_in(&cache::read_cache()?, attr, eterm)
+}
+
+/// Like `resolve_attr`, but against the cache lines in `all_attrs`.
+fn resolve_attr_in(all_attrs: &str, attr: &str, eterm: &mut Term) -> Result<String, MainErr> {
+    match nix::resolve_attr(all_attrs, attr) {
+        nix::Resolved::Attr(resolved) => Ok(resolved),
+        nix::Resolved::NotFound => Ok(attr.to_string()),
+        nix::Resolved::Ambiguous(candidates) => {
+            writeln!(eterm, "{} matches several attributes:", attr)?;
+            for candidate in candidates {
+                writeln!(eterm, "    {}", candidate)?;
+            }
+            Err(MainErr::Ambiguous(attr.to_string()))
+        }
+    }
+}
+
+/// The description column for `attr` in the cache, if the attribute is
+/// cached and has a description.
+fn cached_description(attr: &str) -> Result<Option<String>, MainErr> {
+    Ok(cached_line(attr)?.and_then(|(rich, line)| {
+        let description = nix::AttrLine::parse(&line, rich).description;
+        if description.is_empty() {
+            None
+        } else {
+            Some(description.to_string())
+        }
+    }))
+}
+
+/// The cache line for `attr`, if it's cached, and whether the cache is rich.
+fn cached_line(attr: &str) -> Result<Option<(bool, String)>, MainErr> {
+    if !cache::cache_exists() {
+        return Ok(None);
+    }
+    let rich = cache::read_cache_header()?.rich;
+    for line in cache::read_cache_lines()? {
+        let line = line?;
+        if line_attr(&line) == attr {
+            return Ok(Some((rich, line)));
+        }
+    }
+    Ok(None)
+}
+
+/// Expands an editor command template, where `{file}` and `{line}` are
+/// replaced with the file and line to open.
+fn expand_editor_template(template: &str, file: &str, line: usize) -> Vec<String> {
+    template
+        .split_whitespace()
+        .map(|arg| {
+            arg.replace("{file}", file)
+                .replace("{line}", &line.to_string())
+        })
+        .collect()
+}
+
+fn editor_command(file: &str, line: usize) -> Command {
+    let template = env::var("NIX_QUERY_EDITOR").unwrap_or_else(|_| {
+        let editor = env::var("VISUAL")
+            .or_else(|_| env::var("EDITOR"))
+            .unwrap_or_else(|_| "vi".to_string());
+        format!("{} +{{line}} {{file}}", editor)
+    });
+    let mut args = expand_editor_template(&template, file, line).into_iter();
+    let mut cmd = Command::new(args.next().unwrap_or_else(|| "vi".to_string()));
+    cmd.args(args);
+    cmd
+}
+
+/// The cache's attribute lines before it's rebuilt, so `write_refresh_summary`
+/// can say what changed. `None` if there's no cache or it's in an older format.
+fn attrs_before_refresh() -> Option<String> {
+    if !cache::cache_exists() {
+        return None;
+    }
+    cache::read_cache_with_header()
+        .ok()
+        .filter(|(header, _)| header.is_current_format())
+        .map(|(_, attrs)| attrs)
+}
+
+/// Prints a summary like `412 added, 37 removed, 1,203 version changes`
+/// comparing the cache before and after a refresh.
+fn write_refresh_summary(eterm: &mut Term, before: Option<String>, after: &str) -> io::Result<()> {
+    match before {
+        Some(before) => writeln!(eterm, "{}.", diff::diff_attrs(&before, after).summary()),
+        None => Ok(()),
+    }
+}
+
+fn write_diff(term: &mut Term, diff: &diff::CacheDiff) -> io::Result<()> {
+    if diff.is_empty() {
+        return term.write_line("No changes.");
+    }
+
+    if !diff.added.is_empty() {
+        writeln!(
+            term,
+            "{}",
+            style(format!("Added ({}):", diff.added.len())).bold()
+        )?;
+        for attr in &diff.added {
+            writeln!(term, "    {}", style(attr).green())?;
+        }
+    }
+
+    if !diff.removed.is_empty() {
+        writeln!(
+            term,
+            "{}",
+            style(format!("Removed ({}):", diff.removed.len())).bold()
+        )?;
+        for attr in &diff.removed {
+            writeln!(term, "    {}", style(attr).red())?;
+        }
+    }
+
+    if !diff.changed.is_empty() {
+        writeln!(
+            term,
+            "{}",
+            style(format!("Changed ({}):", diff.changed.len())).bold()
+        )?;
+        for (attr, old, new) in &diff.changed {
+            writeln!(term, "    {}: {} -> {}", attr, old, style(new).cyan())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves the lines of `s` for favorite attributes to the front, best first.
+/// If `only` is set, the other lines are dropped.
+fn favorites_first(s: &str, favorites: &[cache::Favorite], only: bool) -> String {
+    let ranks: HashMap<&str, usize> = favorites
+        .iter()
+        .enumerate()
+        .map(|(rank, fav)| (fav.attr.as_str(), rank))
+        .collect();
+    let (mut favored, rest): (Vec<&str>, Vec<&str>) = s
+        .lines()
+        .partition(|line| ranks.contains_key(line_attr(line)));
+    favored.sort_by_key(|line| ranks[line_attr(line)]);
+
+    if !only {
+        favored.extend(rest);
+    }
+
+    let mut ret = String::with_capacity(s.len());
+    for line in favored {
+        ret.push_str(line);
+        ret.push('\n');
+    }
+    ret
+}
+
+/// Makes a query from outside the fuzzy-finder safe to start it with, by
+/// replacing newlines, tabs, and other control characters (which would
+/// corrupt its display) with spaces.
+fn sanitize_query(query: &str) -> String {
+    query
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+/// Replaces the escapes `\0`, `\n`, `\t`, and `\\` in `s`. Other
+/// backslashes are left alone.
+fn unescape(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('0') => ret.push('\0'),
+            Some('n') => ret.push('\n'),
+            Some('t') => ret.push('\t'),
+            Some('\\') => ret.push('\\'),
+            Some(other) => {
+                ret.push('\\');
+                ret.push(other);
+            }
+            None => ret.push('\\'),
+        }
+    }
+    ret
+}
+
+/// Whether there's a terminal for the fuzzy-finder, which draws on (and reads
+/// keys from) `/dev/tty` rather than stdin and stdout, so those can be
+/// redirected, as in `nix-env -iA $(nix-query)`.
+fn has_terminal() -> bool {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .is_ok()
+}
+
+/// Formats a duration in its largest whole unit, like `3h` or `2d`; the
+/// inverse of `parse_duration`, give or take.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    for (unit, unit_secs) in &[("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60)] {
+        if secs >= *unit_secs {
+            return format!("{}{}", secs / unit_secs, unit);
+        }
+    }
+    format!("{}s", secs)
+}
+
+/// Populates the attribute list like `cache::populate_cache`, printing how
+/// long each stage took. The result is written to a temporary file rather
+/// than the cache.
+fn benchmark(opts: &nix::PopulateOptions, term: &mut Term) -> Result<(), MainErr> {
+    let (all, mut timings) = nix::nix_query_all_timed(opts)?;
+
+    let path = env::temp_dir().join(format!("nix-query-benchmark-{}", std::process::id()));
+    let start = std::time::Instant::now();
+    let written = std::fs::write(
+        &path,
+        format!("{}\n{}", cache::CacheHeader::new(opts), all.attrs),
+    );
+    timings.push(("write".to_string(), start.elapsed()));
+    let _ = std::fs::remove_file(&path);
+    written?;
+
+    let total: Duration = timings.iter().map(|(_, elapsed)| *elapsed).sum();
+    timings.push(("total".to_string(), total));
+    let width = timings
+        .iter()
+        .map(|(stage, _)| stage.len())
+        .max()
+        .unwrap_or(0);
+    for (stage, elapsed) in timings {
+        writeln!(
+            term,
+            "{:width$}  {:>8.3}s",
+            stage,
+            elapsed.as_secs_f64(),
+            width = width
+        )?;
+    }
+    writeln!(term, "{} attributes", all.attrs.lines().count())?;
+    Ok(())
+}
+
+/// Prints `--version-json`. The cache's source is `null` if there's no cache
+/// or it doesn't record one.
+fn print_version_json(term: &mut Term) -> Result<(), MainErr> {
+    let cache_source = if cache::cache_exists() {
+        cache::read_cache_header()?.source
+    } else {
+        None
+    };
+    writeln!(
+        term,
+        "{}",
+        serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "cache_format_version": cache::CACHE_FORMAT_VERSION,
+            "cache_source": cache_source,
+            "source": nix::source_descriptor(),
+        })
+    )?;
+    Ok(())
+}
+
+/// Prints `--stats`.
+fn print_stats(term: &mut Term) -> Result<(), MainErr> {
+    if !cache::cache_exists() {
+        writeln!(term, "There's no cache yet.")?;
+        return Ok(());
+    }
+    let header = cache::read_cache_header()?;
+    let mut channels: BTreeMap<String, usize> = BTreeMap::new();
+    for line in cache::read_cache_lines()? {
+        let line = line?;
+        let channel = line_attr(&line).split('.').next().unwrap_or_default();
+        match channels.get_mut(channel) {
+            Some(count) => *count += 1,
+            None => {
+                channels.insert(channel.to_string(), 1);
+            }
+        }
+    }
+
+    if let Some(dir) = cache::cache_dir() {
+        writeln!(term, "path: {}", dir.display())?;
+    }
+    if let Some(age) = cache::cache_age()? {
+        writeln!(term, "age: {}", format_duration(age))?;
+    }
+    writeln!(term, "format version: {}", header.version)?;
+    writeln!(term, "mode: {}", if header.rich { "rich" } else { "plain" })?;
+    writeln!(
+        term,
+        "extra package sets: {}",
+        if header.skip_extra_attrs { "no" } else { "yes" }
+    )?;
+    writeln!(
+        term,
+        "private attributes: {}",
+        if header.include_private { "yes" } else { "no" }
+    )?;
+    let current = nix::source_descriptor();
+    write!(
+        term,
+        "source: {}",
+        header.source.as_deref().unwrap_or("unknown")
+    )?;
+    if !header.source_matches(current.as_deref()) {
+        write!(
+            term,
+            " (now {}, so the cache will be rebuilt)",
+            current.unwrap_or_default()
+        )?;
+    }
+    writeln!(term)?;
+    writeln!(term, "attributes: {}", channels.values().sum::<usize>())?;
+    for (channel, count) in channels {
+        writeln!(term, "    {}: {}", channel, count)?;
+    }
+    Ok(())
+}
+
+/// Parses a column number for --select-field, which counts from 1.
+fn parse_field_number(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(n) if n >= 1 => Ok(n),
+        _ => Err(format!("Field '{}' isn't a column number (1 or more)", s)),
+    }
+}
+
+/// The `n`th (counting from 1) `FIELD_DELIMITER`-separated column of a
+/// cache line, or `""` if it doesn't have that many. Like
+/// `nix::AttrLine::parse`, descriptions in a plain cache may contain the
+/// delimiter, so everything past the second column is the third.
+fn line_field(line: &str, n: usize, rich: bool) -> &str {
+    let field = if rich {
+        line.split(nix::FIELD_DELIMITER).nth(n - 1)
+    } else {
+        line.splitn(3, nix::FIELD_DELIMITER).nth(n - 1)
+    };
+    field.unwrap_or_default().trim()
+}
+
+/// Checks --height like the config file's picker height.
+fn validate_height(s: String) -> Result<(), String> {
+    config::check_height(&s)
+}
+
+/// Parses a duration like `90s`, `30m`, `2h`, `7d`, or `1w`.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("Duration '{}' needs a unit (s, m, h, d, or w)", s))?;
+    let (count, unit) = s.split_at(split);
+    let count: u64 = count
+        .parse()
+        .map_err(|_| format!("Duration '{}' should start with a number", s))?;
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("Unknown duration unit '{}'", unit)),
+    };
+    count
+        .checked_mul(unit_secs)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("Duration '{}' is too long", s))
+}
+
+/// The characters output may use.
+fn charset(opt: &Opt) -> nix::Charset {
+    if opt.ascii {
+        nix::Charset::Ascii
+    } else {
+        nix::Charset::Unicode
+    }
+}
+
+/// The prefix stripped from attributes in the fuzzy-finder, if any.
+fn shown_prefix(opt: &Opt) -> &str {
+    match &opt.prefix {
+        Some(prefix) if opt.strip_prefix => prefix,
+        _ => "",
+    }
+}
+
+/// Quotes `s` as a single shell word.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Replaces each line of `s` with `f(line)`.
+fn map_lines<'a>(s: &'a str, f: impl Fn(&'a str) -> &'a str) -> String {
+    s.lines()
+        .fold(String::with_capacity(s.len()), |mut acc, line| {
+            acc.push_str(f(line));
+            acc.push('\n');
+            acc
+        })
+}
+
+/// The --supported-only, --has-tests, --channel, and --prefix filters, which
+/// keep or drop each cache line on its own.
+struct LineSelection {
+    /// The system packages must support, with --supported-only.
+    system: Option<String>,
+    has_tests: bool,
+    /// `channel.`, or `None` to keep every channel.
+    channel: Option<String>,
+    /// Whether `channel` is the default channel picked by `--channel auto`,
+    /// which falls back to every channel if no line is in it.
+    auto_channel: bool,
+    prefix: Option<String>,
+}
+
+impl LineSelection {
+    fn new(opt: &Opt) -> Self {
+        LineSelection {
+            system: if opt.supported_only {
+                Some(nix::current_system())
+            } else {
+                None
+            },
+            has_tests: opt.has_tests,
+            channel: match opt.channel.as_str() {
+                "all" => None,
+                "auto" => Some(format!("{}.", nix::default_channel())),
+                channel => Some(format!("{}.", channel)),
+            },
+            auto_channel: opt.channel == "auto",
+            prefix: opt.prefix.clone(),
+        }
+    }
+
+    /// Whether every line is kept.
+    fn keeps_all(&self) -> bool {
+        self.system.is_none() && !self.has_tests && self.channel.is_none() && self.prefix.is_none()
+    }
+
+    /// Whether `line` passes every filter but --prefix. An automatic channel
+    /// is only kept if some line passes this.
+    fn in_channel(&self, line: &str) -> bool {
+        if self.system.is_some() || self.has_tests {
+            // Both options imply --rich.
+            let parsed = nix::AttrLine::parse(line, true);
+            if let Some(system) = &self.system {
+                if !parsed.supports(system) {
+                    return false;
+                }
+            }
+            if self.has_tests && parsed.tests != Some(true) {
+                return false;
+            }
+        }
+        match &self.channel {
+            Some(channel) => line.starts_with(channel.as_str()),
+            None => true,
+        }
+    }
+
+    fn keeps(&self, line: &str) -> bool {
+        self.in_channel(line)
+            && match &self.prefix {
+                Some(prefix) => line.starts_with(prefix.as_str()),
+                None => true,
+            }
+    }
+}
+
+/// The lines of `s` for which `pred` is true.
+fn filter_lines(s: &str, pred: impl Fn(&str) -> bool) -> String {
+    s.lines()
+        .filter(|line| pred(line))
+        .fold(String::with_capacity(s.len()), |mut acc, line| {
+            acc.push_str(line);
+            acc.push('\n');
+            acc
+        })
+}
+
+/// Whether the characters of `needle` appear in order in `haystack`, ignoring
+/// case. `needle` must be lowercase.
+fn is_subsequence(needle: &str, haystack: &str) -> bool {
+    if needle.is_ascii() {
+        // Much faster, and almost every query is ASCII.
+        let mut needle = needle.bytes().peekable();
+        for b in haystack.bytes() {
+            match needle.peek() {
+                Some(&n) if n == b.to_ascii_lowercase() => {
+                    needle.next();
+                }
+                Some(_) => {}
+                None => return true,
+            }
+        }
+        return needle.peek().is_none();
+    }
+    let mut needle = needle.chars().peekable();
+    for c in haystack.chars().flat_map(char::to_lowercase) {
+        if needle.peek() == Some(&c) {
+            needle.next();
+        }
+    }
+    needle.peek().is_none()
+}
+
+/// The lines of `input` which could match `query` in the fuzzy-finder, to
+/// give it less to start with. No line the fuzzy-finder would match is left
+/// out: each term must be a subsequence of the line, ignoring case and the
+/// `'`, `^`, and `$` markers, and negated (`!`) terms are ignored. `None` if
+/// nothing can be filtered out, like when the query is empty or uses `|`.
+fn prefilter(input: &str, query: &str) -> Option<String> {
+    if query.contains('|') {
+        return None;
+    }
+    let terms: Vec<String> = query
+        .split_whitespace()
+        .filter(|term| !term.starts_with('!'))
+        .map(|term| {
+            let term = term.trim_start_matches(['\'', '^']);
+            term.strip_suffix('$').unwrap_or(term).to_lowercase()
+        })
+        .filter(|term| !term.is_empty())
+        .collect();
+    if terms.is_empty() {
+        return None;
+    }
+    Some(
+        input
+            .lines()
+            .filter(|line| terms.iter().all(|term| is_subsequence(term, line)))
+            .fold(String::new(), |mut acc, line| {
+                acc.push_str(line);
+                acc.push('\n');
+                acc
+            }),
+    )
+}
+
+/// Truncates `s` to its first `n` lines, returning whether any were removed.
+fn truncate_lines(s: &mut String, n: usize) -> bool {
+    let end = if n == 0 {
+        Some(0)
+    } else {
+        s.match_indices('\n').nth(n - 1).map(|(i, _)| i + 1)
+    };
+    match end {
+        Some(end) if end < s.len() => {
+            s.truncate(end);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The first (or, if `tail` is set, last) `n` lines of `s`, in order.
+fn slice_lines(s: &str, n: usize, tail: bool) -> Vec<&str> {
+    if tail {
+        let mut ret: Vec<_> = s.lines().rev().take(n).collect();
+        ret.reverse();
+        ret
+    } else {
+        s.lines().take(n).collect()
+    }
+}
+
+/// The width to wrap --info output to: --width (or $COLUMNS) if given, or
+/// else the terminal's width. Output that isn't going to a terminal isn't
+/// wrapped.
+fn output_width(opt: &Opt, term: &Term) -> Option<usize> {
+    opt.width.or_else(|| {
+        if term.is_term() {
+            Some(term.size_checked()?.1 as usize)
+        } else {
+            None
+        }
+    })
+}
+
+/// Cuts the middle out of `attr` if it's longer than `width` characters,
+/// keeping its start and end on either side of `ellipsis`.
+fn middle_truncate(attr: &str, width: usize, ellipsis: &str) -> String {
+    let len = attr.chars().count();
+    if len <= width {
+        return attr.to_string();
+    }
+    let kept = width.saturating_sub(ellipsis.chars().count());
+    let tail = kept / 2;
+    let head = kept - tail;
+    attr.chars()
+        .take(head)
+        .chain(ellipsis.chars())
+        .chain(attr.chars().skip(len - tail))
+        .collect()
+}
+
+/// Cuts the cache lines in `input` down to the attribute, name, and
+/// description the fuzzy-finder shows, dropping a rich cache's other columns.
+/// Column gaps within descriptions are collapsed, so the description is a
+/// single field.
+fn picker_lines(input: &str, rich: bool) -> String {
+    input
+        .lines()
+        .fold(String::with_capacity(input.len()), |mut acc, line| {
+            let line = nix::AttrLine::parse(line, rich);
+            acc.push_str(line.attr);
+            acc.push_str(nix::FIELD_DELIMITER);
+            acc.push_str(line.name);
+            acc.push_str(nix::FIELD_DELIMITER);
+            for (i, word) in line.description.split_whitespace().enumerate() {
+                if i > 0 {
+                    acc.push(' ');
+                }
+                acc.push_str(word);
+            }
+            acc.push('\n');
+            acc
+        })
+}
+
+/// Puts a column before each line of `input` with its attribute shortened to
+/// `width` characters, for the fuzzy-finder to display instead. See
+/// `skim_attrs`.
+fn with_short_attrs(input: &str, width: usize, ellipsis: &str) -> String {
+    input
+        .lines()
+        .fold(String::with_capacity(input.len()), |mut acc, line| {
+            acc.push_str(&middle_truncate(line_attr(line), width, ellipsis));
+            acc.push_str(nix::FIELD_DELIMITER);
+            acc.push_str(line);
+            acc.push('\n');
+            acc
+        })
+}
+
+/// The header line `group_by_channel` puts before `channel`'s attributes.
+/// Its fields are empty up to the text, which is past the attribute, name,
+/// and description the fuzzy-finder searches and previews.
+fn channel_header(channel: &str, charset: nix::Charset) -> String {
+    let rule = charset.rule().repeat(2);
+    format!(
+        "{}{}",
+        nix::FIELD_DELIMITER.repeat(4),
+        style(format!("{} {} {}", rule, channel, rule)).dim()
+    )
+}
+
+/// Whether an attribute selected in the fuzzy-finder is really a
+/// `channel_header`. Attributes never start with a rule.
+fn is_channel_header(attr: &str, charset: nix::Charset) -> bool {
+    attr.starts_with(charset.rule())
+}
+
+/// Sorts the lines of `input` by channel and then attribute, putting a
+/// `channel_header` before each channel's lines.
+fn group_by_channel(input: &str, charset: nix::Charset) -> String {
+    let mut lines: Vec<(&str, &str, &str)> = input
+        .lines()
+        .map(|line| {
+            let attr = line_attr(line);
+            let channel = attr.split('.').next().unwrap_or_default();
+            (channel, attr, line)
+        })
+        .collect();
+    lines.sort_unstable();
+
+    let mut ret = String::with_capacity(input.len());
+    let mut last_channel = None;
+    for (channel, _, line) in lines {
+        if last_channel != Some(channel) {
+            ret.push_str(&channel_header(channel, charset));
+            ret.push('\n');
+            last_channel = Some(channel);
+        }
+        ret.push_str(line);
+        ret.push('\n');
+    }
+    ret
+}
+
+/// The attribute at the start of a cache line.
+fn line_attr(line: &str) -> &str {
+    line.trim_start()
+        .split(nix::FIELD_DELIMITER)
+        .next()
+        .unwrap_or_default()
+        .trim_end()
+}
+
+/// The attribute of a line selected in the fuzzy-finder. Styling is stripped,
+/// so that colored input never leaks escape codes into the attribute.
+fn selected_attr(text: &str) -> String {
+    line_attr(&console::strip_ansi_codes(text)).to_string()
+}
+
+/// The attribute of a line of the fuzzy-finder's input, which starts with
+/// the shortened attribute if `short_attrs` (see `with_short_attrs`).
+fn picked_attr(line: &str, short_attrs: bool) -> String {
+    match line.split_once(nix::FIELD_DELIMITER) {
+        Some((_, line)) if short_attrs => selected_attr(line),
+        _ => selected_attr(line),
+    }
+}
+
+/// How sure `first_match` is about the attribute it picked.
+#[derive(Debug, PartialEq)]
+enum FirstMatch<'a> {
+    Found(&'a str),
+    /// The best few attributes, which match about equally well.
+    Ambiguous(Vec<&'a str>),
+    NotFound,
+}
+
+/// How much better than the runner-up the best fuzzy match has to score to be
+/// picked by --first-match.
+const FIRST_MATCH_MARGIN: i64 = 20;
+
+/// The attribute in the cache lines `lines` best matching `query`, without
+/// the fuzzy-finder. An attribute named exactly `query` (in any channel or
+/// package set) wins, if one is nested less deeply than the others; otherwise,
+/// the best fuzzy match wins if it's clearly better than the rest. With
+/// `name_first`, only attributes and names are matched, like --rank
+/// name-first.
+fn first_match<'a>(lines: &'a str, query: &str, name_first: bool, rich: bool) -> FirstMatch<'a> {
+    let mut exact: Vec<&str> = lines
+        .lines()
+        .map(line_attr)
+        .filter(|attr| attr.rsplit('.').next() == Some(query))
+        .collect();
+    exact.sort_by_key(|attr| attr.matches('.').count());
+    match exact.as_slice() {
+        [attr] => return FirstMatch::Found(attr),
+        [attr, next, ..] if attr.matches('.').count() < next.matches('.').count() => {
+            return FirstMatch::Found(attr)
+        }
+        _ => {}
+    }
+
+    let scored: Vec<(i64, &str)> = fuzzy_filter(lines, query, name_first, rich)
+        .into_iter()
+        .map(|(score, line)| (score, line_attr(line)))
+        .collect();
+    match scored.as_slice() {
+        [] => FirstMatch::NotFound,
+        [(_, attr)] => FirstMatch::Found(attr),
+        [(best, attr), (next, _), ..] if best - next >= FIRST_MATCH_MARGIN => {
+            FirstMatch::Found(attr)
+        }
+        _ => FirstMatch::Ambiguous(scored.iter().take(5).map(|&(_, attr)| attr).collect()),
+    }
+}
+
+/// The cache lines in `lines` fuzzy-matching `query`, with their scores, best
+/// first. Attributes, names, and descriptions are matched, like in the
+/// fuzzy-finder; with `name_first`, only attributes and names are. `rich` is
+/// whether the lines are from a rich cache, whose other columns are skipped.
+fn fuzzy_filter<'a>(
+    lines: &'a str,
+    query: &str,
+    name_first: bool,
+    rich: bool,
+) -> Vec<(i64, &'a str)> {
+    let matcher = SkimMatcherV2::default();
+    let mut scored: Vec<(i64, &str)> = lines
+        .lines()
+        .filter_map(|line| {
+            let parsed = nix::AttrLine::parse(line.trim_start(), rich);
+            let text = if name_first {
+                format!("{} {}", parsed.attr, parsed.name)
+            } else {
+                format!("{} {} {}", parsed.attr, parsed.name, parsed.description)
+            };
+            matcher.fuzzy_match(&text, query).map(|score| (score, line))
+        })
+        .collect();
+    scored.sort_by_key(|&(score, _)| Reverse(score));
+    scored
+}
+
+/// How often --watch checks whether the cache changed.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Prints the attributes matching `query`, best first, and prints them again
+/// whenever the cache changes, until interrupted.
+fn watch(query: &str, opt: &Opt, term: &mut Term) -> Result<(), MainErr> {
+    cache::watch_cache(WATCH_INTERVAL, || {
+        let (rich, attrs) = if cache::cache_exists() {
+            let (header, attrs) = cache::read_cache_with_header()?;
+            (header.rich, attrs)
+        } else {
+            (false, String::new())
+        };
+        if term.is_term() {
+            term.clear_screen()?;
+        }
+        for (_, line) in fuzzy_filter(&attrs, query, opt.rank == "name-first", rich) {
+            writeln!(term, "{}", line_attr(line))?;
+        }
+        Ok(())
+    })
+}
+
+/// How the user left the fuzzy-finder.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PickAction {
+    /// Pressed enter to accept the selected lines.
+    Accept,
+    /// Pressed escape or ctrl-c.
+    Abort,
+    /// Pressed ctrl-b to show or hide broken packages.
+    ToggleBroken,
+    /// Pressed ctrl-u to show or hide unfree packages.
+    ToggleUnfree,
+}
+
+/// What the user did in the fuzzy-finder.
+#[derive(Debug, Clone, PartialEq)]
+struct PickResult {
+    /// The attributes of the selected lines. Only meaningful for
+    /// `PickAction::Accept`.
+    selections: Vec<String>,
+    /// The query when the user left, or the initial query if they aborted.
+    query: String,
+    action: PickAction,
+}
+
+/// The command skim runs to preview the highlighted line: nix-query itself,
+/// with the options that affect how the attribute is shown.
+fn preview_command(opt: &Opt) -> String {
+    // Channel headers have no attribute to preview.
+    format!(
+        "test -n {{{attr_field}}} && {exe} --color {color} --timeout-info {timeout}{markdown}{ascii}{fast}{nix_bin}{cache_path} --exact-attr {preview} {prefix}{{{attr_field}}}",
+        exe = env::current_exe()
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| "nix-query".to_string()),
+        color = opt.color,
+        timeout = opt.timeout_info,
+        markdown = if opt.markdown { " --markdown" } else { "" },
+        ascii = if opt.ascii { " --ascii" } else { "" },
+        fast = if opt.fast { " --fast" } else { "" },
+        nix_bin = opt.nix_bin.as_ref().map_or_else(String::new, |dir| format!(
+            " --nix-bin {}",
+            shell_quote(&dir.to_string_lossy())
+        )),
+        // --describe and --fast read the cache.
+        cache_path = opt.cache_path.as_ref().map_or_else(String::new, |dir| format!(
+            " --cache-path {}",
+            shell_quote(&dir.to_string_lossy())
+        )),
+        preview = match opt.preview_format.as_str() {
+            "plain" => "--format plain --info",
+            "raw" => "--format json --info",
+            "describe" => "--describe",
+            _ => "--info",
+        },
+        // skim quotes the field itself; the quoted prefix joins onto it.
+        prefix = shell_quote(shown_prefix(opt)),
+        attr_field = if opt.max_attr_width.is_some() { 2 } else { 1 },
+    )
+}
+
+/// Runs the fuzzy-finder over the cache lines in `input`, starting with
+/// `query`. If `filter` is given, the keys to toggle it are enabled. Options
+/// not given on the command line come from `picker`.
+fn skim_attrs(
+    input: String,
+    opt: &Opt,
+    picker: &config::PickerConfig,
+    query: &str,
+    filter: Option<nix::LineFilter>,
+) -> Result<PickResult, MainErr> {
+    use std::io::Cursor;
+
+    // With --max-attr-width, the first column is the shortened attribute, and
+    // the cache line follows it.
+    let short_attrs = opt.max_attr_width.is_some();
+
+    let preview_cmd = preview_command(opt);
+
+    // The input is `picker_lines`, and with --max-attr-width the shortened
+    // attribute is shown in place of the full one. skim applies `nth` to the
+    // fields shown (`with_nth`), not to the input line, so in either case the
+    // fields searched are the attribute, name, and description.
+    let shown_fields = if short_attrs { Some("1,3..") } else { None };
+    // With `name-first`, only search the attribute and name. Channel headers
+    // have nothing in the fields searched, so they never match.
+    let search_fields = if opt.rank == "name-first" {
+        Some("1,2")
+    } else {
+        Some("1..3")
+    };
+
+    let header = filter.map(|filter| {
+        format!(
+            "ctrl-b: {} broken, ctrl-u: {} unfree",
+            if filter.hide_broken { "show" } else { "hide" },
+            if filter.hide_unfree { "show" } else { "hide" },
+        )
+    });
+
+    let options = SkimOptionsBuilder::default()
+        .height(Some(
+            opt.height
+                .as_deref()
+                .or(picker.height.as_deref())
+                .unwrap_or("100%"),
+        ))
+        .layout(
+            opt.layout
+                .as_deref()
+                .or(picker.layout.as_deref())
+                .unwrap_or("default"),
+        )
+        .multi(true)
+        .preview(Some(&preview_cmd))
+        .preview_window(Some(
+            picker.preview_window.as_deref().unwrap_or("down:wrap:50%"),
+        ))
+        // alt-j and alt-k scroll the preview, and alt-w toggles its wrapping.
+        .bind(vec![
+            "alt-j:preview-down",
+            "alt-k:preview-up",
+            "alt-w:toggle-preview-wrap",
+        ])
+        .tiebreak(Some(
+            picker
+                .tiebreak
+                .clone()
+                .unwrap_or_else(|| "score,end".to_string()),
+        ))
+        .exact(picker.exact.unwrap_or(false))
+        // For the dim channel headers.
+        .ansi(opt.group_by_channel)
+        .no_hscroll(true)
+        .delimiter(Some(nix::FIELD_DELIMITER))
+        .nth(search_fields) // fields to search
+        .with_nth(shown_fields) // fields to show
+        .query(Some(query))
+        .expect(filter.map(|_| "ctrl-b,ctrl-u".to_string()))
+        .header(header.as_deref())
+        .build()
+        .map_err(MainErr::Skim)?;
+
+    let out = match Skim::run_with(&options, Some(Box::new(Cursor::new(input)))) {
+        Some(out) => out,
+        // Aborted.
+        None => {
+            return Ok(PickResult {
+                selections: Vec::new(),
+                query: query.to_string(),
+                action: PickAction::Abort,
+            })
+        }
+    };
+    let action = match out.accept_key.as_deref() {
+        Some("ctrl-b") => PickAction::ToggleBroken,
+        Some("ctrl-u") => PickAction::ToggleUnfree,
+        _ => PickAction::Accept,
+    };
+    let selections = out
+        .selected_items
+        .iter()
+        // `get_text` is only the fields shown; the whole input line is the
+        // output text.
+        .map(|i| picked_attr(&i.get_output_text(), short_attrs))
+        .filter(|attr| !is_channel_header(attr, charset(opt)))
+        .collect();
+    Ok(PickResult {
+        selections,
+        query: out.query,
+        action,
+    })
+}
+
+/// Queries Nix about every attribute in `all_attrs` (from `start_at` on, in
+/// sorted order) and reports the ones whose information doesn't match our
+/// schema. With --json-lines, every attribute's result is printed as it comes
+/// in instead.
+fn audit_schemas(
+    all_attrs: &str,
+    start_at: Option<&str>,
+    opt: &Opt,
+    term: &mut Term,
+) -> Result<(), MainErr> {
+    let mut attrs: Vec<String> = all_attrs
+        .lines()
+        .map(|line| line_attr(line).to_string())
+        .filter(|attr| match start_at {
+            Some(start) => attr.as_str() >= start,
+            None => true,
+        })
+        .collect();
+    attrs.sort_unstable();
+
+    let total = attrs.len();
+    let threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let mut invalid = Vec::new();
+    let mut query_failed = 0;
+    let mut json_out = if opt.json_lines {
+        Some(BufWriter::new(io::stdout().lock()))
+    } else {
+        None
+    };
+    for (inx, (attr, audit)) in
+        proc::parallel_map(attrs, threads, |attr| nix::audit_schema(attr)).enumerate()
+    {
+        if let Some(out) = &mut json_out {
+            writeln!(out, "{}", audit_record(&attr, &audit))?;
+            // Each attribute takes a while to query, so don't hold results
+            // back until the buffer fills.
+            out.flush()?;
+            continue;
+        }
+        match audit {
+            nix::SchemaAudit::Ok => {
+                if opt.verbose {
+                    writeln!(term, "OK: {} [{}/{}]", attr, inx + 1, total)?;
+                }
+            }
+            nix::SchemaAudit::Invalid(e) => {
+                writeln!(term, "{}: {}", style(&attr).bold().red(), e)?;
+                invalid.push((attr, e));
+            }
+            nix::SchemaAudit::QueryFailed(e) => {
+                if opt.verbose {
+                    writeln!(term, "{}: couldn't query Nix: {:?}", attr, e)?;
+                }
+                query_failed += 1;
+            }
+        }
+    }
+
+    if json_out.is_some() {
+        return Ok(());
+    }
+
+    invalid.sort_by(|a, b| a.0.cmp(&b.0));
+    writeln!(
+        term,
+        "\nChecked {} attributes: {} didn't match the schema, {} couldn't be queried.",
+        total,
+        invalid.len(),
+        query_failed
+    )?;
+    for (attr, e) in &invalid {
+        writeln!(term, "    {}: {}", attr, e)?;
+    }
+    Ok(())
+}
+
+/// An attribute's --audit-schemas result, for --json-lines.
+fn audit_record(attr: &str, audit: &nix::SchemaAudit) -> serde_json::Value {
+    let (status, error) = match audit {
+        nix::SchemaAudit::Ok => ("ok", None),
+        nix::SchemaAudit::Invalid(e) => ("invalid", Some(e.to_string())),
+        nix::SchemaAudit::QueryFailed(e) => ("query_failed", Some(e.to_string())),
+    };
+    serde_json::json!({
+        "attr": attr,
+        "status": status,
+        "error": error,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_expand_editor_template() {
+        assert_eq!(
+            vec!["code", "-g", "/nix/store/x/default.nix:12"],
+            expand_editor_template("code -g {file}:{line}", "/nix/store/x/default.nix", 12)
+        );
+        assert_eq!(
+            vec!["vim", "+12", "default.nix"],
+            expand_editor_template("vim +{line} {file}", "default.nix", 12)
+        );
+    }
+
+    #[test]
+    fn test_slice_lines() {
+        let s = "a\nb\nc\nd\n";
+        assert_eq!(vec!["a", "b"], slice_lines(s, 2, false));
+        assert_eq!(vec!["c", "d"], slice_lines(s, 2, true));
+        assert_eq!(vec!["a", "b", "c", "d"], slice_lines(s, 10, true));
+        assert!(slice_lines(s, 0, false).is_empty());
+    }
+
+    #[test]
+    fn test_line_attr() {
+        assert_eq!(
+            "nixpkgs.gzip",
+            line_attr(
+                &[
+                    "nixpkgs.gzip",
+                    "gzip-1.10",
+                    "GNU zip compression program",
+                    "false",
+                ]
+                .join(nix::FIELD_DELIMITER)
+            )
+        );
+        assert_eq!("nixpkgs.gzip", line_attr("  nixpkgs.gzip    gzip-1.10"));
+        assert_eq!("nixpkgs.gzip", line_attr("nixpkgs.gzip"));
+    }
+
+    #[test]
+    fn test_selected_attr() {
+        let styled = format!(
+            "{}{}{}",
+            console::style("nixpkgs.gzip").force_styling(true).blue(),
+            nix::FIELD_DELIMITER,
+            console::style("gzip-1.10").force_styling(true).dim(),
+        );
+        assert_ne!(styled, console::strip_ansi_codes(&styled));
+        assert_eq!("nixpkgs.gzip", selected_attr(&styled));
+        assert_eq!("nixpkgs.gzip", selected_attr("nixpkgs.gzip    gzip-1.10"));
+    }
+
+    #[test]
+    fn test_favorites_first() {
+        let favorites = vec![
+            cache::Favorite {
+                attr: "c".to_string(),
+                count: 2,
+            },
+            cache::Favorite {
+                attr: "a".to_string(),
+                count: 1,
+            },
+        ];
+        let s = "a    a-1\nb    b-1\nc    c-1\n";
+        assert_eq!(
+            "c    c-1\na    a-1\nb    b-1\n",
+            favorites_first(s, &favorites, false)
+        );
+        assert_eq!("c    c-1\na    a-1\n", favorites_first(s, &favorites, true));
+        assert_eq!(s, favorites_first(s, &[], false));
+    }
+
+    #[test]
+    fn test_first_match() {
+        let lines = [
+            "nixpkgs.firefox    firefox-91    A web browser",
+            "nixpkgs.firefox-bin    firefox-bin-91    A web browser",
+            "nixpkgs.python3Packages.ripgrep    ripgrep-1    Not really",
+            "nixpkgs.ripgrep    ripgrep-12    A fast grep",
+            "nixpkgs.gnugrep    gnugrep-3    GNU grep",
+            "nixpkgs.zstd    zstd-1.4    Zstandard compression",
+        ]
+        .join("\n");
+        assert_eq!(
+            FirstMatch::Found("nixpkgs.firefox"),
+            first_match(&lines, "firefox", false, false)
+        );
+        assert_eq!(
+            FirstMatch::Found("nixpkgs.ripgrep"),
+            first_match(&lines, "ripgrep", false, false)
+        );
+        assert_eq!(
+            FirstMatch::Found("nixpkgs.zstd"),
+            first_match(&lines, "zstand", false, false)
+        );
+        assert!(matches!(
+            first_match(&lines, "fire", false, false),
+            FirstMatch::Ambiguous(_)
+        ));
+        assert_eq!(
+            FirstMatch::NotFound,
+            first_match(&lines, "qqq", false, false)
+        );
+        // Descriptions aren't matched with `name_first`.
+        assert_eq!(
+            FirstMatch::NotFound,
+            first_match(&lines, "zstandard", true, false)
+        );
+
+        // A rich cache's other columns aren't matched.
+        let rich_line = [
+            "nixpkgs.gzip",
+            "gzip-1.10",
+            "GNU zip",
+            "false",
+            "true",
+            "x86_64-linux",
+            "true",
+            "true",
+        ]
+        .join(nix::FIELD_DELIMITER);
+        assert_eq!(
+            FirstMatch::Found("nixpkgs.gzip"),
+            first_match(&rich_line, "gnu zip", false, true)
+        );
+        assert_eq!(
+            FirstMatch::NotFound,
+            first_match(&rich_line, "linux", false, true)
+        );
+    }
+
+    #[test]
+    fn test_opt() {
+        // clap checks for clashing argument names when the parser is built.
+        let opt = Opt::from_iter_safe(&["nix-query", "--max-attr-width", "40"]).unwrap();
+        assert_eq!(Some(40), opt.max_attr_width);
+        assert!(Opt::from_iter_safe(&["nix-query", "--height", "40%"]).is_ok());
+        assert!(Opt::from_iter_safe(&["nix-query", "--height", "tall"]).is_err());
+    }
+
+    #[test]
+    fn test_preview_command() {
+        let preview = |args: &[&str]| {
+            preview_command(
+                &Opt::from_iter_safe(std::iter::once(&"nix-query").chain(args)).unwrap(),
+            )
+        };
+        let cmd = preview(&["--fast", "--cache-path", "/tmp/nix query"]);
+        assert!(cmd.contains(" --fast"));
+        assert!(cmd.contains(" --cache-path '/tmp/nix query' "));
+        assert!(cmd.ends_with(" --exact-attr --info ''{1}"));
+        assert!(!preview(&["--fast"]).contains("--cache-path"));
+        assert!(
+            preview(&["--preview-format", "describe", "--max-attr-width", "20"])
+                .ends_with(" --describe ''{2}")
+        );
+    }
+
+    #[test]
+    fn test_line_selection() {
+        let selection = |args: &[&str]| {
+            LineSelection::new(
+                &Opt::from_iter_safe(std::iter::once(&"nix-query").chain(args)).unwrap(),
+            )
+        };
+        let all = selection(&["--channel", "all"]);
+        assert!(all.keeps_all());
+        assert!(all.keeps("unstable.hello    hello-2.12"));
+
+        let prefixed = selection(&[
+            "--channel",
+            "nixpkgs",
+            "--prefix",
+            "nixpkgs.python3Packages.",
+        ]);
+        assert!(!prefixed.keeps_all());
+        assert!(prefixed.keeps("nixpkgs.python3Packages.six    six-1.16"));
+        assert!(prefixed.in_channel("nixpkgs.hello    hello-2.12"));
+        assert!(!prefixed.keeps("nixpkgs.hello    hello-2.12"));
+        assert!(!prefixed.in_channel("unstable.hello    hello-2.12"));
+        assert!(!prefixed.auto_channel);
+        assert!(selection(&[]).auto_channel);
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let attrs = "nixpkgs.gzip    gzip-1.10    GNU zip\nnixpkgs.hello    hello-2.10    ";
+        let records = attrs.lines().map(|line| {
+            let line = nix::AttrLine::parse(line, false);
+            (line.attr.trim(), line)
+        });
+        let mut out = Vec::new();
+        write_attr_json_lines(&mut out, records).unwrap();
+        assert_eq!(
+            "{\"attr\":\"nixpkgs.gzip\",\"description\":\"GNU zip\",\"name\":\"gzip-1.10\"}\n\
+             {\"attr\":\"nixpkgs.hello\",\"description\":\"\",\"name\":\"hello-2.10\"}\n",
+            String::from_utf8(out).unwrap()
+        );
+
+        assert_eq!(
+            serde_json::json!({"attr": "nixpkgs.gzip", "status": "ok", "error": null}),
+            audit_record("nixpkgs.gzip", &nix::SchemaAudit::Ok)
+        );
+        let e = serde_json::from_str::<nix::NixInfo>("{}").unwrap_err();
+        let message = e.to_string();
+        assert_eq!(
+            serde_json::json!({"attr": "nixpkgs.bad", "status": "invalid", "error": message}),
+            audit_record("nixpkgs.bad", &nix::SchemaAudit::Invalid(e))
+        );
+
+        assert!(Opt::from_iter_safe(&["nix-query", "--json-lines", "--json"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_attr_list() {
+        assert_eq!(
+            vec!["nixpkgs.gzip", "hello", "nixpkgs.ripgrep"],
+            parse_attr_list(
+                "# Things to audit.\n\
+                 nixpkgs.gzip\n\
+                 \n\
+                 \x20 hello  \n\
+                 #nixpkgs.skipped\r\n\
+                 nixpkgs.ripgrep",
+                '\n'
+            )
+        );
+        assert!(parse_attr_list("\n# nothing\n", '\n').is_empty());
+        assert_eq!(
+            vec!["nixpkgs.gzip", "nixpkgs.hello"],
+            parse_attr_list("nixpkgs.gzip\0\0nixpkgs.hello\0", '\0')
+        );
+        assert_eq!(vec!["not\nsplit"], parse_attr_list("not\nsplit\0", '\0'));
+    }
+
+    #[test]
+    fn test_prefilter() {
+        let input = "nixpkgs.gzip    gzip-1.10    GNU zip\n\
+                     nixpkgs.ripgrep    ripgrep-12.0.0    Fast grep\n\
+                     nixpkgs.hello    hello-2.10    Hello, World\n";
+        assert_eq!(
+            Some(
+                "nixpkgs.gzip    gzip-1.10    GNU zip\n\
+                 nixpkgs.ripgrep    ripgrep-12.0.0    Fast grep\n"
+                    .to_string()
+            ),
+            prefilter(input, "Gp")
+        );
+        assert_eq!(
+            Some("nixpkgs.hello    hello-2.10    Hello, World\n".to_string()),
+            prefilter(input, "^hel 'world$ !gzip")
+        );
+        assert_eq!(Some(String::new()), prefilter(input, "qqq"));
+        assert_eq!(None, prefilter(input, ""));
+        assert_eq!(None, prefilter(input, "!gzip"));
+        assert_eq!(None, prefilter(input, "gzip | hello"));
+    }
+
+    #[test]
+    fn test_line_field() {
+        let line = "nixpkgs.gzip    gzip-1.10    GNU zip    with spaces";
+        assert_eq!("nixpkgs.gzip", line_field(line, 1, false));
+        assert_eq!("gzip-1.10", line_field(line, 2, false));
+        assert_eq!("GNU zip    with spaces", line_field(line, 3, false));
+        assert_eq!("", line_field(line, 4, false));
+        assert_eq!("with spaces", line_field(line, 4, true));
+        assert_eq!(Ok(2), parse_field_number("2"));
+        assert!(parse_field_number("0").is_err());
+    }
+
+    #[test]
+    fn test_output_format() {
+        let format = |args: &[&str]| {
+            Opt::from_iter_safe(std::iter::once(&"nix-query").chain(args))
+                .unwrap()
+                .output_format()
+        };
+        assert_eq!(OutputFormat::Console, format(&[]));
+        assert_eq!(OutputFormat::Tsv, format(&["--format", "tsv"]));
+        assert_eq!(OutputFormat::Json, format(&["--format", "plain", "--json"]));
+        assert!(Opt::from_iter_safe(&["nix-query", "--format", "yaml"]).is_err());
+    }
+
+    #[test]
+    fn test_error_json() {
+        assert_eq!(
+            serde_json::json!({
+                "error": "Nothing matches fire",
+                "kind": "not_found",
+            }),
+            MainErr::NoMatch("fire".to_string()).to_json()
+        );
+        assert_eq!(
+            serde_json::json!({
+                "error": "Invalid fuzzy-finder option: `height` must be initialized",
+                "kind": "skim",
+            }),
+            MainErr::from("`height` must be initialized".to_string()).to_json()
+        );
+        assert_eq!(
+            serde_json::json!({
+                "error": "Missing: nixpkgs.a, nixpkgs.b",
+                "kind": "missing_attrs",
+            }),
+            MainErr::MissingAttrs(vec!["nixpkgs.a".to_string(), "nixpkgs.b".to_string()]).to_json()
+        );
+        assert_eq!(
+            "timeout",
+            MainErr::NixQuery(nix::NixQueryError::Command(CommandError::Timeout(
+                Duration::from_secs(1)
+            )))
+            .kind()
+        );
+        assert_eq!(
+            "nix_failed",
+            MainErr::NixQuery(nix::NixQueryError::Command(CommandError::Stderr(
+                "error: ...".to_string()
+            )))
+            .kind()
+        );
+        assert_eq!(
+            "The command took longer than 1s",
+            MainErr::NixQuery(nix::NixQueryError::Command(CommandError::Timeout(
+                Duration::from_secs(1)
+            )))
+            .message()
+        );
+    }
+
+    #[test]
+    fn test_group_by_channel() {
+        let grouped = group_by_channel(
+            "nixpkgs.b    b-1\nnixos.a    a-1\nnixpkgs.a    a-1\n",
+            nix::Charset::Ascii,
+        );
+        assert_eq!(
+            "                -- nixos --\n\
+             nixos.a    a-1\n\
+             \x20               -- nixpkgs --\n\
+             nixpkgs.a    a-1\n\
+             nixpkgs.b    b-1\n",
+            console::strip_ansi_codes(&grouped)
+        );
+        let headers: Vec<_> = grouped
+            .lines()
+            .map(selected_attr)
+            .filter(|attr| is_channel_header(attr, nix::Charset::Ascii))
+            .collect();
+        assert_eq!(vec!["-- nixos --", "-- nixpkgs --"], headers);
+        // Nothing in a header's attribute, name, or description to match.
+        let header = channel_header("nixos", nix::Charset::Ascii);
+        let fields: Vec<_> = header.split(nix::FIELD_DELIMITER).take(3).collect();
+        assert_eq!(vec!["", "", ""], fields);
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!("'/opt/nix/bin'", shell_quote("/opt/nix/bin"));
+        assert_eq!("'it'\\''s here'", shell_quote("it's here"));
+    }
+
+    #[test]
+    fn test_middle_truncate() {
+        assert_eq!("nixpkgs.gzip", middle_truncate("nixpkgs.gzip", 12, "…"));
+        assert_eq!("nixp…gzip", middle_truncate("nixpkgs.gzip", 9, "…"));
+        assert_eq!("nix...zip", middle_truncate("nixpkgs.gzip", 9, "..."));
+        assert_eq!("…", middle_truncate("nixpkgs.gzip", 0, "…"));
+    }
+
+    #[test]
+    fn test_picker_lines() {
+        let rich_line = [
+            "nixpkgs.gzip",
+            "gzip-1.10",
+            "GNU zip",
+            "false",
+            "true",
+            "x86_64-linux",
+            "true",
+            "true",
+        ]
+        .join(nix::FIELD_DELIMITER);
+        assert_eq!(
+            "nixpkgs.gzip    gzip-1.10    GNU zip\n",
+            picker_lines(&format!("{}\n", rich_line), true)
+        );
+        assert_eq!(
+            "nixpkgs.a    a-1    Split by a column gap\n\
+             nixpkgs.b    b-1    \n",
+            picker_lines(
+                "nixpkgs.a    a-1    Split by    a column gap\n\
+                 nixpkgs.b    b-1\n",
+                false
+            )
+        );
+    }
+
+    #[test]
+    fn test_picked_attr() {
+        assert_eq!(
+            "a.bbbbc",
+            picked_attr("a.…c    a.bbbbc    c-1    Description", true)
+        );
+        assert_eq!("a.b", picked_attr("a.b    a.b    b-1", true));
+        assert_eq!(
+            "a.bbbbc",
+            picked_attr("a.bbbbc    c-1    Description", false)
+        );
+    }
+
+    #[test]
+    fn test_with_short_attrs() {
+        assert_eq!(
+            "a.b    a.b    b-1\n\
+             a.…c    a.bbbbc    c-1\n",
+            with_short_attrs("a.b    b-1\na.bbbbc    c-1\n", 4, "…")
+        );
+    }
+
+    #[test]
+    fn test_truncate_lines() {
+        let truncated = |s: &str, n| {
+            let mut s = s.to_string();
+            let removed = truncate_lines(&mut s, n);
+            (s, removed)
+        };
+        assert_eq!(("a\nb\n".to_string(), true), truncated("a\nb\nc\n", 2));
+        assert_eq!(("a\nb\nc\n".to_string(), false), truncated("a\nb\nc\n", 3));
+        assert_eq!(("a\nb\nc".to_string(), false), truncated("a\nb\nc", 5));
+        assert_eq!((String::new(), true), truncated("a\n", 0));
+        assert_eq!((String::new(), false), truncated("", 0));
+    }
+
+    #[test]
+    fn test_sanitize_query() {
+        assert_eq!("firefox", sanitize_query("firefox"));
+        assert_eq!("fire fox", sanitize_query("fire\nfox\n"));
+        assert_eq!("a b  c", sanitize_query("\ta\tb\r\nc"));
+        assert_eq!("[0m", sanitize_query("\x1b[0m"));
+        assert_eq!("", sanitize_query("\n\n"));
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!("\n", unescape("\\n"));
+        assert_eq!("\0", unescape("\\0"));
+        assert_eq!(" ", unescape(" "));
+        assert_eq!("a\tb\\", unescape("a\\tb\\\\"));
+        assert_eq!("\\x\\", unescape("\\x\\"));
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!("0s", format_duration(Duration::from_secs(0)));
+        assert_eq!("59s", format_duration(Duration::from_secs(59)));
+        assert_eq!("30m", format_duration(Duration::from_secs(30 * 60 + 5)));
+        assert_eq!("2h", format_duration(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!("8d", format_duration(Duration::from_secs(8 * 24 * 60 * 60)));
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(Ok(Duration::from_secs(30 * 60)), parse_duration("30m"));
+        assert_eq!(Ok(Duration::from_secs(2 * 60 * 60)), parse_duration("2h"));
+        assert_eq!(
+            Ok(Duration::from_secs(7 * 24 * 60 * 60)),
+            parse_duration("7d")
+        );
+        assert!(parse_duration("7").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("3y").is_err());
+        assert!(parse_duration("99999999999999999w").is_err());
     }
 }
@@ -1,22 +1,134 @@
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryFrom;
+use std::env;
+use std::ffi::OsString;
 use std::fmt;
 use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
 
-use console::{style, StyledObject};
+use console::StyledObject;
 use lazy_static::lazy_static;
-use regex::Regex;
-use serde::{Deserialize, Deserializer};
+use serde::{de, Deserialize, Deserializer, Serialize};
 use serde_json;
 
+use crate::cache;
 use crate::proc;
 use crate::proc::CommandError;
+use crate::regexes;
 
 pub const FIELD_DELIMITER: &str = "    ";
 
+/// Environment variable naming the directory to run Nix's programs from.
+pub const NIX_BIN_VAR: &str = "NIX_QUERY_NIX_BIN";
+
+lazy_static! {
+    static ref NIX_BIN_OVERRIDE: RwLock<Option<PathBuf>> = RwLock::new(None);
+}
+
+/// Sets the directory to run Nix's programs (`nix`, `nix-env`, and so on)
+/// from, taking precedence over the environment. This is how `--nix-bin` is
+/// implemented.
+pub fn set_nix_bin(dir: PathBuf) {
+    *NIX_BIN_OVERRIDE.write().unwrap() = Some(dir);
+}
+
+/// The path to run `program` from: in the directory given explicitly or in
+/// `$NIX_QUERY_NIX_BIN`, or else just `program`, to be found on `$PATH`.
+fn resolve_nix_program(
+    program: &str,
+    explicit: Option<PathBuf>,
+    nix_bin: Option<OsString>,
+) -> PathBuf {
+    explicit
+        .or_else(|| nix_bin.filter(|s| !s.is_empty()).map(PathBuf::from))
+        .map_or_else(|| PathBuf::from(program), |dir| dir.join(program))
+}
+
+/// A command running one of Nix's programs, like `nix-env`. See
+/// `set_nix_bin`.
+pub fn nix_command(program: &str) -> Command {
+    Command::new(resolve_nix_program(
+        program,
+        NIX_BIN_OVERRIDE.read().unwrap().clone(),
+        env::var_os(NIX_BIN_VAR),
+    ))
+}
+
+/// Whether formatted output includes ANSI styling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Styling {
+    /// Style output if `console::colors_enabled()`.
+    Auto,
+    /// Never style output, regardless of `console::colors_enabled()`.
+    Plain,
+    /// Always style output, regardless of `console::colors_enabled()`.
+    Always,
+}
+
+impl Styling {
+    fn style<D>(self, val: D) -> StyledObject<D> {
+        match self {
+            Styling::Auto => console::style(val),
+            Styling::Plain => console::style(val).force_styling(false),
+            Styling::Always => console::style(val).force_styling(true),
+        }
+    }
+
+    fn enabled(self) -> bool {
+        match self {
+            Styling::Auto => console::colors_enabled(),
+            Styling::Plain => false,
+            Styling::Always => true,
+        }
+    }
+
+    fn url<D>(self, val: D) -> StyledObject<D> {
+        self.style(val).underlined().cyan()
+    }
+}
+
+/// Which characters formatted output may use. Every non-ASCII glyph we print
+/// is chosen here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Charset {
+    Unicode,
+    /// For terminals which render Unicode poorly.
+    Ascii,
+}
+
+impl Charset {
+    /// Marks a list item.
+    fn bullet(self) -> &'static str {
+        match self {
+            Charset::Unicode => "•",
+            Charset::Ascii => "*",
+        }
+    }
+
+    /// Draws a horizontal line.
+    pub fn rule(self) -> &'static str {
+        match self {
+            Charset::Unicode => "─",
+            Charset::Ascii => "-",
+        }
+    }
+
+    /// Marks where text was cut out.
+    pub fn ellipsis(self) -> &'static str {
+        match self {
+            Charset::Unicode => "…",
+            Charset::Ascii => "...",
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, PartialEq, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct FullLicense {
@@ -29,12 +141,16 @@ pub struct FullLicense {
 }
 
 impl FullLicense {
-    pub fn console_fmt(&self) -> ConsoleFormatFullLicense {
-        ConsoleFormatFullLicense(self)
+    pub fn console_fmt(&self) -> ConsoleFormatFullLicense<'_> {
+        self.console_fmt_with(Styling::Auto)
+    }
+
+    pub fn console_fmt_with(&self, styling: Styling) -> ConsoleFormatFullLicense<'_> {
+        ConsoleFormatFullLicense(self, styling)
     }
 }
 
-pub struct ConsoleFormatFullLicense<'a>(&'a FullLicense);
+pub struct ConsoleFormatFullLicense<'a>(&'a FullLicense, Styling);
 
 impl<'a> ConsoleFormatFullLicense<'a> {
     fn fmt_unfree(&self, f: &mut Formatter<'_>) -> fmt::Result {
@@ -47,9 +163,9 @@ impl<'a> ConsoleFormatFullLicense<'a> {
         if has_full_name {
             write!(f, "{}", license.full_name)?;
             parenthetical = true;
-            write!(f, " ({}", style("unfree").bold().red())?;
+            write!(f, " ({}", self.1.style("unfree").bold().red())?;
         } else {
-            write!(f, "{}", style("unfree").bold().red())?;
+            write!(f, "{}", self.1.style("unfree").bold().red())?;
         }
 
         if has_short_name {
@@ -64,7 +180,7 @@ impl<'a> ConsoleFormatFullLicense<'a> {
         }
 
         if let Some(url_str) = &license.url {
-            write!(f, " {}", url(url_str))?;
+            write!(f, " {}", self.1.url(url_str))?;
         }
 
         Ok(())
@@ -85,7 +201,7 @@ impl<'a> ConsoleFormatFullLicense<'a> {
         }
 
         if let Some(url_str) = &license.url {
-            write!(f, " {}", url(url_str))?;
+            write!(f, " {}", self.1.url(url_str))?;
         }
 
         Ok(())
@@ -122,47 +238,73 @@ pub enum License {
     FullVec(Vec<FullLicense>),
     Named(NamedLicense),
     Url(UrlLicense),
+    /// Some older nixpkgs give `false` to mean there's no free license.
+    Bool(bool),
 }
 
 impl License {
-    pub fn console_fmt(&self) -> ConsoleFormatLicense {
-        ConsoleFormatLicense(self)
+    pub fn console_fmt(&self) -> ConsoleFormatLicense<'_> {
+        self.console_fmt_with(Styling::Auto)
     }
-}
 
-fn url<C>(s: C) -> StyledObject<C> {
-    style(s).underlined().cyan()
+    pub fn console_fmt_with(&self, styling: Styling) -> ConsoleFormatLicense<'_> {
+        ConsoleFormatLicense(self, styling)
+    }
+
+    /// Whether the license is free. Licenses that don't say otherwise are
+    /// assumed to be free.
+    pub fn is_free(&self) -> bool {
+        match self {
+            License::Id(s) => !s.eq_ignore_ascii_case("unfree"),
+            License::Full(l) => l.free,
+            License::FullVec(ls) => ls.iter().all(|l| l.free),
+            License::Named(l) => l.full_name != "Unfree",
+            License::Url(_) => true,
+            License::Bool(free) => *free,
+        }
+    }
 }
 
-fn write_licenses(licenses: &[FullLicense], f: &mut Formatter<'_>) -> fmt::Result {
+fn write_licenses(
+    licenses: &[FullLicense],
+    styling: Styling,
+    f: &mut Formatter<'_>,
+) -> fmt::Result {
     if licenses.is_empty() {
         Ok(())
     } else if licenses.len() == 1 {
-        write!(f, "{}", licenses.get(0).unwrap().console_fmt())
+        write!(f, "{}", licenses.first().unwrap().console_fmt_with(styling))
     } else {
         for license in licenses
             .iter()
             .take(licenses.len() - 1)
-            .map(FullLicense::console_fmt)
+            .map(|l| l.console_fmt_with(styling))
         {
             write!(f, "{}\n         ", license)?;
         }
-        write!(f, "{}", licenses.last().unwrap().console_fmt())?;
+        write!(f, "{}", licenses.last().unwrap().console_fmt_with(styling))?;
 
         Ok(())
     }
 }
 
-pub struct ConsoleFormatLicense<'a>(&'a License);
+pub struct ConsoleFormatLicense<'a>(&'a License, Styling);
 
 impl Display for ConsoleFormatLicense<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let styling = self.1;
         match self.0 {
             License::Id(s) => write!(f, "{}", s),
             License::Named(s) => write!(f, "{}", s.full_name),
-            License::Url(s) => write!(f, "{}", url(&s.url)),
-            License::Full(s) => write!(f, "{}", s.console_fmt()),
-            License::FullVec(s) => write_licenses(s, f),
+            License::Url(s) => write!(f, "{}", styling.url(&s.url)),
+            License::Full(s) => write!(f, "{}", s.console_fmt_with(styling)),
+            License::FullVec(s) => write_licenses(s, styling, f),
+            License::Bool(false) => write!(
+                f,
+                "{} (no license specified)",
+                styling.style("unfree").bold().red()
+            ),
+            License::Bool(true) => write!(f, "free (no license specified)"),
         }
     }
 }
@@ -171,6 +313,25 @@ fn true_() -> bool {
     true
 }
 
+/// Deserializes a present value, even `null`, as `Some`, so that a missing
+/// field (which defaults to `None`) can be told apart from a `null` one.
+fn deserialize_nullable<'de, D, T>(d: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(d).map(Some)
+}
+
+/// Deserializes any value at all as `true`, for fields where we only care
+/// whether they're present.
+fn deserialize_present<'de, D>(d: D) -> Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    de::IgnoredAny::deserialize(d).map(|_| true)
+}
+
 #[derive(Deserialize, Debug, PartialEq, Clone)]
 #[serde(rename_all = "camelCase", try_from = "String")]
 pub struct NixPath {
@@ -178,6 +339,16 @@ pub struct NixPath {
     line: usize,
 }
 
+impl NixPath {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum NixPathParseErr {
     BadSplit,
@@ -238,6 +409,120 @@ pub enum Maintainer {
     Info(MaintainerInfo),
 }
 
+impl Maintainer {
+    pub fn console_fmt(&self) -> ConsoleFormatMaintainer<'_> {
+        self.console_fmt_with(Styling::Auto)
+    }
+
+    pub fn console_fmt_with(&self, styling: Styling) -> ConsoleFormatMaintainer<'_> {
+        ConsoleFormatMaintainer(self, styling)
+    }
+}
+
+/// An OSC 8 terminal hyperlink. Without styling, only the text is written.
+struct Hyperlink<'a, D> {
+    target: &'a str,
+    text: D,
+    styling: Styling,
+}
+
+impl<D: Display> Display for Hyperlink<'_, D> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.styling.enabled() {
+            write!(
+                f,
+                "\x1b]8;;{}\x1b\\{}\x1b]8;;\x1b\\",
+                self.target, self.text
+            )
+        } else {
+            write!(f, "{}", self.text)
+        }
+    }
+}
+
+pub struct ConsoleFormatMaintainer<'a>(&'a Maintainer, Styling);
+
+impl Display for ConsoleFormatMaintainer<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let info = match self.0 {
+            Maintainer::Name(name) => return write!(f, "{}", name),
+            Maintainer::Info(info) => info,
+        };
+
+        let name = info.name.as_ref().unwrap_or(&info.email);
+        match &info.github {
+            Some(github) => write!(
+                f,
+                "{} {}",
+                name,
+                Hyperlink {
+                    target: &format!("https://github.com/{}", github),
+                    text: self.1.url(format!("@{}", github)),
+                    styling: self.1,
+                }
+            ),
+            // Without a GitHub account, link to their email instead.
+            None => write!(
+                f,
+                "{}",
+                Hyperlink {
+                    target: &format!("mailto:{}", info.email),
+                    text: name,
+                    styling: self.1,
+                }
+            ),
+        }
+    }
+}
+
+/// A team in `meta.teams`, which newer nixpkgs uses for maintainership
+/// shared by a group, like the GNOME or Rust teams.
+#[derive(Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TeamInfo {
+    short_name: Option<String>,
+    #[serde(deserialize_with = "deserialize_maintainers")]
+    members: Vec<Maintainer>,
+}
+
+impl Display for TeamInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.short_name.as_deref().unwrap_or("unnamed team")
+        )?;
+        match self.members.len() {
+            0 => Ok(()),
+            1 => write!(f, " (1 member)"),
+            n => write!(f, " ({} members)", n),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Priority {
+    Number(isize),
+    /// Older nixpkgs sometimes gives priorities as strings, like `"10"`.
+    String(String),
+}
+
+fn deserialize_priority<'de, D>(d: D) -> Result<Option<isize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<Priority>::deserialize(d)? {
+        None => Ok(None),
+        Some(Priority::Number(n)) => Ok(Some(n)),
+        Some(Priority::String(s)) => s
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| de::Error::custom(format!("invalid priority '{}'", s))),
+    }
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(untagged)]
 enum Platforms {
@@ -262,7 +547,25 @@ where
     Platforms::deserialize(d).map(Into::into)
 }
 
-#[derive(Deserialize, Debug, PartialEq, Clone, Default)]
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Maintainers {
+    List(Vec<Maintainer>),
+    /// Some packages give a single maintainer rather than a list of them.
+    One(Maintainer),
+}
+
+fn deserialize_maintainers<'de, D>(d: D) -> Result<Vec<Maintainer>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Maintainers::deserialize(d).map(|m| match m {
+        Maintainers::List(list) => list,
+        Maintainers::One(one) => vec![one],
+    })
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
 #[serde(rename_all = "camelCase", default)]
 pub struct NixMeta {
     #[serde(default = "true_")]
@@ -270,31 +573,126 @@ pub struct NixMeta {
     broken: bool,
     description: Option<String>,
     long_description: Option<String>,
-    homepage: Option<String>, // url
-    license: Option<License>,
+    homepage: Option<String>,      // url
+    download_page: Option<String>, // url
+    /// `None` if the package doesn't give a license, and `Some(None)` if it
+    /// gives a `null` license, which means the license is unknown.
+    #[serde(deserialize_with = "deserialize_nullable")]
+    license: Option<Option<License>>,
     name: Option<String>,
     outputs_to_install: Vec<String>,
     #[serde(deserialize_with = "deserialize_platforms")]
     platforms: Vec<String>,
     position: Option<NixPath>,
+    #[serde(deserialize_with = "deserialize_priority")]
     priority: Option<isize>,
+    #[serde(deserialize_with = "deserialize_maintainers")]
     maintainers: Vec<Maintainer>,
+    teams: Vec<TeamInfo>,
+    /// Whether the package has `passthru.tests`, which nixpkgs exposes as
+    /// `meta.tests`.
+    #[serde(deserialize_with = "deserialize_present")]
+    tests: bool,
+}
+
+impl Default for NixMeta {
+    /// The metadata of a package with no `meta` attribute at all; notably,
+    /// such a package is still available.
+    fn default() -> Self {
+        NixMeta {
+            available: true,
+            broken: false,
+            description: None,
+            long_description: None,
+            homepage: None,
+            download_page: None,
+            license: None,
+            name: None,
+            outputs_to_install: Vec::new(),
+            platforms: Vec::new(),
+            position: None,
+            priority: None,
+            maintainers: Vec::new(),
+            teams: Vec::new(),
+            tests: false,
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, PartialEq, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct NixInfo {
-    name: String,    // gzip-1.10
-    pname: String,   // gzip
-    version: String, // 1.10
-    system: String,  // x86_64-linux
+    name: String,           // gzip-1.10
+    pname: String,          // gzip
+    version: String,        // 1.10
+    system: Option<String>, // x86_64-linux
+    #[serde(default)]
     meta: NixMeta,
     attr: Option<String>, // nixos.gzip
 }
 
 impl NixInfo {
-    pub fn console_fmt(&self) -> ConsoleFormatInfo {
-        ConsoleFormatInfo(self)
+    pub fn console_fmt(&self) -> ConsoleFormatInfo<'_> {
+        self.console_fmt_fields(InfoField::ALL)
+    }
+
+    /// Like `console_fmt`, but only writes the given fields, in the given
+    /// order.
+    pub fn console_fmt_fields<'a>(&'a self, fields: &'a [InfoField]) -> ConsoleFormatInfo<'a> {
+        ConsoleFormatInfo {
+            info: self,
+            fields,
+            styling: Styling::Auto,
+            markdown: false,
+            width: None,
+            charset: Charset::Unicode,
+        }
+    }
+
+    /// The given fields as a JSON object keyed by their `--fields` names.
+    pub fn json_fields(&self, fields: &[InfoField]) -> serde_json::Map<String, serde_json::Value> {
+        fields
+            .iter()
+            .map(|field| (field.name().to_string(), field.json_value(self)))
+            .collect()
+    }
+
+    /// The given fields as a tab-separated line, without a trailing newline.
+    pub fn tsv_fields(&self, fields: &[InfoField]) -> String {
+        fields
+            .iter()
+            .map(|field| field.tsv_value(self))
+            .collect::<Vec<_>>()
+            .join("\t")
+    }
+
+    /// The package's name, like `gzip-1.10`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The package's name without its version, like `gzip`.
+    pub fn pname(&self) -> &str {
+        &self.pname
+    }
+
+    pub fn homepage(&self) -> Option<&str> {
+        self.meta.homepage.as_deref()
+    }
+
+    /// The package's one-line description, if it has one.
+    pub fn description(&self) -> Option<&str> {
+        self.meta.description.as_deref()
+    }
+
+    /// The platforms the package supports, like `x86_64-linux`.
+    pub fn platforms(&self) -> &[String] {
+        &self.meta.platforms
+    }
+
+    /// The file and line the package is defined at, if known.
+    pub fn position(&self) -> Option<&NixPath> {
+        self.meta.position.as_ref()
     }
 }
 
@@ -304,13 +702,141 @@ pub struct AllNixInfo {
     pub attrs: HashMap<String, NixInfo>,
 }
 
-pub struct ConsoleFormatInfo<'a>(&'a NixInfo);
+/// A single labeled line (or group of lines) in `--info` output.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum InfoField {
+    Attr,
+    Name,
+    System,
+    Broken,
+    Available,
+    Priority,
+    Homepage,
+    DownloadPage,
+    Description,
+    LongDescription,
+    License,
+    Maintainers,
+    Teams,
+    Tests,
+    Position,
+}
+
+impl InfoField {
+    /// Every field, in the order `--info` prints them by default.
+    pub const ALL: &'static [InfoField] = &[
+        InfoField::Attr,
+        InfoField::Name,
+        InfoField::System,
+        InfoField::Broken,
+        InfoField::Available,
+        InfoField::Priority,
+        InfoField::Homepage,
+        InfoField::DownloadPage,
+        InfoField::Description,
+        InfoField::LongDescription,
+        InfoField::License,
+        InfoField::Maintainers,
+        InfoField::Teams,
+        InfoField::Tests,
+        InfoField::Position,
+    ];
+
+    /// The name used to select this field with `--fields`.
+    pub fn name(self) -> &'static str {
+        match self {
+            InfoField::Attr => "attr",
+            InfoField::Name => "name",
+            InfoField::System => "system",
+            InfoField::Broken => "broken",
+            InfoField::Available => "available",
+            InfoField::Priority => "priority",
+            InfoField::Homepage => "homepage",
+            InfoField::DownloadPage => "download-page",
+            InfoField::Description => "description",
+            InfoField::LongDescription => "long-description",
+            InfoField::License => "license",
+            InfoField::Maintainers => "maintainers",
+            InfoField::Teams => "teams",
+            InfoField::Tests => "tests",
+            InfoField::Position => "position",
+        }
+    }
+
+    /// This field's value in `info`, for `--format json`. Fields the package
+    /// doesn't have are `null`.
+    pub fn json_value(self, info: &NixInfo) -> serde_json::Value {
+        use serde_json::Value;
+
+        let meta = &info.meta;
+        let string = |s: Option<&String>| s.map_or(Value::Null, |s| Value::from(s.as_str()));
+        match self {
+            InfoField::Attr => string(info.attr.as_ref()),
+            InfoField::Name => Value::from(info.name.as_str()),
+            InfoField::System => string(info.system.as_ref()),
+            InfoField::Broken => Value::from(meta.broken),
+            InfoField::Available => Value::from(meta.available),
+            InfoField::Priority => meta.priority.map_or(Value::Null, Value::from),
+            InfoField::Homepage => string(meta.homepage.as_ref()),
+            InfoField::DownloadPage => string(meta.download_page.as_ref()),
+            InfoField::Description => string(meta.description.as_ref()),
+            InfoField::LongDescription => string(meta.long_description.as_ref()),
+            InfoField::License => match &meta.license {
+                Some(Some(license)) => {
+                    Value::from(license.console_fmt_with(Styling::Plain).to_string())
+                }
+                Some(None) => Value::from("unknown"),
+                None => Value::Null,
+            },
+            InfoField::Maintainers => meta
+                .maintainers
+                .iter()
+                .map(|m| m.console_fmt_with(Styling::Plain).to_string())
+                .collect(),
+            InfoField::Teams => meta.teams.iter().map(|t| t.to_string()).collect(),
+            InfoField::Tests => Value::from(meta.tests),
+            InfoField::Position => meta.position.as_ref().map_or(Value::Null, |pos| {
+                format!("{}:{}", pos.path, pos.line).into()
+            }),
+        }
+    }
+
+    /// This field's value in `info` as a single line of text, for `--format
+    /// tsv`. Fields the package doesn't have are empty.
+    pub fn tsv_value(self, info: &NixInfo) -> String {
+        use serde_json::Value;
+
+        let text = match self.json_value(info) {
+            Value::Null => String::new(),
+            Value::String(s) => s,
+            Value::Array(values) => values
+                .iter()
+                .map(|v| v.as_str().map_or_else(|| v.to_string(), str::to_string))
+                .collect::<Vec<_>>()
+                .join(", "),
+            value => value.to_string(),
+        };
+        text.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    fn write(self, fmt: &ConsoleFormatInfo<'_>, f: &mut Formatter<'_>) -> fmt::Result {
+        let ConsoleFormatInfo {
+            info,
+            styling,
+            markdown,
+            width,
+            charset,
+            ..
+        } = *fmt;
 
-impl Display for ConsoleFormatInfo<'_> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         macro_rules! write_val {
             ($f:expr, $label:expr, $val:expr) => {
-                writeln!($f, "{} {}", style(format!("{}:", $label)).bold(), $val)
+                writeln!(
+                    $f,
+                    "{} {}",
+                    styling.style(format!("{}:", $label)).bold(),
+                    $val
+                )
             };
         }
 
@@ -324,63 +850,253 @@ impl Display for ConsoleFormatInfo<'_> {
             };
         }
 
-        let info = self.0;
-        write_val_opt!(f, "attr", &info.attr.as_ref().map(|a| style(a).bold().green()))?;
-        write_val!(f, "name", style(&info.name).bold().green())?;
-
         let meta = &info.meta;
-        if meta.broken {
-            write_val!(f, "broken", style("true").bold().red())?;
+        match self {
+            InfoField::Attr => write_val_opt!(
+                f,
+                "attr",
+                &info.attr.as_ref().map(|a| styling.style(a).bold().green())
+            ),
+            InfoField::Name => write_val!(f, "name", styling.style(&info.name).bold().green()),
+            InfoField::System => write_val_opt!(f, "system", &info.system),
+            InfoField::Broken if meta.broken => {
+                write_val!(f, "broken", styling.style("true").bold().red())
+            }
+            InfoField::Available if !meta.available => {
+                write_val!(f, "available", styling.style("false").bold().red())
+            }
+            InfoField::Broken | InfoField::Available => Ok(()),
+            InfoField::Priority => write_val_opt!(f, "priority", &meta.priority),
+            InfoField::Homepage => write_val_opt!(
+                f,
+                "homepage",
+                &meta.homepage.as_ref().map(|h| styling.url(h))
+            ),
+            InfoField::DownloadPage => write_val_opt!(
+                f,
+                "download page",
+                &meta.download_page.as_ref().map(|p| styling.url(p))
+            ),
+            InfoField::Description => match &meta.description {
+                Some(desc) => {
+                    let mut lines = wrap_text(desc, width, "description: ".len()).into_iter();
+                    write_val!(f, "description", lines.next().unwrap_or_default())?;
+                    for line in lines {
+                        writeln!(f, "{}{}", LONG_DESC_INDENT, line)?;
+                    }
+                    Ok(())
+                }
+                None => Ok(()),
+            },
+            InfoField::LongDescription => {
+                // long_description is multiline so we indent it
+                if let Some(long_desc) = &meta.long_description {
+                    let long_desc: Cow<'_, str> = if markdown {
+                        render_markdown(long_desc, styling, charset).into()
+                    } else {
+                        long_desc.into()
+                    };
+                    let mut lines =
+                        wrap_text(&long_desc, width, LONG_DESC_INDENT.len()).into_iter();
+                    if let Some(first_line) = lines.next() {
+                        write_val!(f, "long desc.", first_line)?;
+                        for line in lines {
+                            writeln!(f, "{}{}", LONG_DESC_INDENT, line)?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+            InfoField::License => match &meta.license {
+                Some(Some(license)) => write_val!(f, "license", license.console_fmt_with(styling)),
+                Some(None) => write_val!(f, "license", styling.style("unknown").yellow()),
+                None => Ok(()),
+            },
+            InfoField::Maintainers if !meta.maintainers.is_empty() => write_val!(
+                f,
+                "maintainers",
+                meta.maintainers
+                    .iter()
+                    .map(|m| m.console_fmt_with(styling).to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            InfoField::Maintainers => Ok(()),
+            InfoField::Teams if !meta.teams.is_empty() => write_val!(
+                f,
+                "teams",
+                meta.teams
+                    .iter()
+                    .map(|t| t.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            InfoField::Teams => Ok(()),
+            InfoField::Tests if meta.tests => {
+                write_val!(f, "tests", styling.style("yes").green())
+            }
+            InfoField::Tests => Ok(()),
+            InfoField::Position => write_val_opt!(
+                f,
+                "defined in",
+                &meta.position.as_ref().map(|pos| format!(
+                    "{} line {}",
+                    styling.style(&pos.path).underlined(),
+                    pos.line,
+                ))
+            ),
         }
-        if !meta.available {
-            write_val!(f, "available", style("false").bold().red())?;
+    }
+}
+
+/// The indent of the lines after the first in a long description, which lines
+/// them up after the `long desc.:` label.
+const LONG_DESC_INDENT: &str = "            ";
+
+/// Wraps each line of `text` at spaces to fit in `width` columns after
+/// `indent` columns, or splits it into lines as-is if `width` is `None`.
+/// Words too long to fit get a line of their own. Styling doesn't count
+/// toward a line's width.
+fn wrap_text(text: &str, width: Option<usize>, indent: usize) -> Vec<String> {
+    let width = match width {
+        // Too narrow to be worth wrapping.
+        Some(width) if width >= indent + 20 => width - indent,
+        _ => return text.lines().map(str::to_string).collect(),
+    };
+    let mut ret = Vec::new();
+    for line in text.lines() {
+        // Wrapped lines keep the line's indent, like a list item's.
+        let words = line.trim_start_matches(' ');
+        let indent = &line[..line.len() - words.len()];
+        let mut current = indent.to_string();
+        let mut current_width = indent.len();
+        for (i, word) in words.split(' ').enumerate() {
+            let word_width = console::measure_text_width(word);
+            if i > 0 {
+                if current_width + 1 + word_width > width {
+                    ret.push(std::mem::replace(&mut current, indent.to_string()));
+                    current_width = indent.len();
+                } else {
+                    current.push(' ');
+                    current_width += 1;
+                }
+            }
+            current.push_str(word);
+            current_width += word_width;
         }
+        ret.push(current);
+    }
+    ret
+}
+
+/// Renders a minimal subset of Markdown for the terminal: `**bold**` and
+/// `__bold__`, `` `code` ``, and `-`/`*`/`+` list items. The markup is
+/// removed even if styling is off.
+fn render_markdown(text: &str, styling: Styling, charset: Charset) -> String {
+    let text = regexes::MARKDOWN_LIST_ITEM
+        .replace_all(text, format!("${{1}}{} ", charset.bullet()).as_str());
+    let text = regexes::MARKDOWN_CODE.replace_all(&text, |c: &regex::Captures<'_>| {
+        styling.style(&c[1]).cyan().to_string()
+    });
+    regexes::MARKDOWN_BOLD
+        .replace_all(&text, |c: &regex::Captures<'_>| {
+            let inner = c.get(1).or_else(|| c.get(2)).map_or("", |m| m.as_str());
+            styling.style(inner).bold().to_string()
+        })
+        .into_owned()
+}
 
-        write_val_opt!(f, "priority", &meta.priority)?;
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownFieldError(String);
 
-        if let Some(homepage) = &meta.homepage {
-            write_val!(f, "homepage", style(homepage).underlined().cyan())?;
-        }
+impl Display for UnknownFieldError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown field '{}'; valid fields are: ", self.0)?;
+        let names: Vec<_> = InfoField::ALL.iter().map(|field| field.name()).collect();
+        write!(f, "{}", names.join(", "))
+    }
+}
 
-        write_val_opt!(f, "description", &meta.description)?;
+impl FromStr for InfoField {
+    type Err = UnknownFieldError;
 
-        // long_description is multiline so we indent it
-        if let Some(long_desc) = &meta.long_description {
-            let mut lines = long_desc.lines();
-            let first_line_opt = lines.next();
-            if let Some(first_line) = first_line_opt {
-                write_val!(f, "long desc.", first_line)?;
-                for line in lines {
-                    writeln!(f, "            {}", line)?;
-                }
-            }
-        }
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        InfoField::ALL
+            .iter()
+            .find(|field| field.name() == s)
+            .copied()
+            .ok_or_else(|| UnknownFieldError(s.to_string()))
+    }
+}
 
-        write_val_opt!(
-            f,
-            "license",
-            &meta.license.as_ref().map(License::console_fmt)
-        )?;
+/// The narrowest width `--info` output is wrapped to; narrower terminals get
+/// lines this wide rather than a column of single words.
+pub const MIN_WIDTH: usize = 40;
+
+pub struct ConsoleFormatInfo<'a> {
+    info: &'a NixInfo,
+    fields: &'a [InfoField],
+    styling: Styling,
+    /// Render the long description as Markdown.
+    markdown: bool,
+    /// Wrap descriptions to this many columns.
+    width: Option<usize>,
+    charset: Charset,
+}
 
-        write_val_opt!(
-            f,
-            "defined in",
-            &meta.position.as_ref().map(|pos| format!(
-                "{} line {}",
-                style(&pos.path).underlined(),
-                pos.line,
-            ))
-        )?;
+impl ConsoleFormatInfo<'_> {
+    pub fn with_styling(self, styling: Styling) -> Self {
+        ConsoleFormatInfo { styling, ..self }
+    }
+
+    pub fn with_markdown(self, markdown: bool) -> Self {
+        ConsoleFormatInfo { markdown, ..self }
+    }
+
+    /// Wraps descriptions to `width` columns. A width of 0, which terminals
+    /// sometimes report when there's no real terminal, turns wrapping off, and
+    /// tiny widths are widened to `MIN_WIDTH`.
+    pub fn with_width(self, width: Option<usize>) -> Self {
+        let width = width.filter(|&w| w > 0).map(|w| w.max(MIN_WIDTH));
+        ConsoleFormatInfo { width, ..self }
+    }
+
+    pub fn with_charset(self, charset: Charset) -> Self {
+        ConsoleFormatInfo { charset, ..self }
+    }
+}
 
+impl Display for ConsoleFormatInfo<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for field in self.fields {
+            field.write(self, f)?;
+        }
         Ok(())
     }
 }
 
+/// Formats a package like `--info` does, but without any ANSI styling.
+pub fn format_info_plain(info: &NixInfo) -> String {
+    info.console_fmt().with_styling(Styling::Plain).to_string()
+}
+
 #[derive(Debug)]
 pub enum NixQueryError {
     Command(CommandError),
     /// Output was well-formed but empty. (This should not appear.)
     Empty,
+    /// Nix doesn't have these attributes.
+    Missing(Vec<String>),
+}
+
+impl Display for NixQueryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            NixQueryError::Command(e) => write!(f, "{}", e),
+            NixQueryError::Empty => write!(f, "Nix didn't give any information"),
+            NixQueryError::Missing(attrs) => write!(f, "Nix doesn't have {}", attrs.join(", ")),
+        }
+    }
 }
 
 impl From<CommandError> for NixQueryError {
@@ -389,158 +1105,2886 @@ impl From<CommandError> for NixQueryError {
     }
 }
 
-pub fn nix_query(attr: &str) -> Result<NixInfo, NixQueryError> {
-    serde_json::from_str::<AllNixInfo>(&proc::run_cmd_stdout(Command::new("nix-env").args(&[
-        "--query",
-        "--available",
-        "--json",
-        "--attr",
-        attr,
-    ]))?)
-    .map_err(CommandError::De)?
-    .attrs
-    .iter()
-    .next()
-    .ok_or(NixQueryError::Empty)
-    .map(|(attr, info)| NixInfo {
-        attr: Some(attr.clone()),
-        ..info.clone()
-    })
+fn nix_query_cmd(attr: &str) -> Command {
+    let mut cmd = nix_command("nix-env");
+    cmd.args(["--query", "--available", "--json", "--attr", attr]);
+    cmd
 }
 
-/// nix-env gives very long lines that are nicely, yet inconveniently, aligned:
-/// ```plain
-/// nixos._0x0                                                                0x0-2018-06-24                                                                      A client for 0x0.st
-/// ```
-/// That's nice. rewrite_attr_line replaces long stretches of whitespace with
-/// FIELD_DELIMITER.
-fn rewrite_attr_line<'a>(line: &'a str) -> Cow<'a, str> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(" {2,}").unwrap();
-    }
-    RE.replace_all(line, FIELD_DELIMITER)
+/// Quotes `s` as a Nix string.
+fn nix_string(s: &str) -> String {
+    format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace("${", "\\${")
+    )
 }
 
-fn rewrite_attr_lines(stdout: String) -> String {
-    stdout
-        .lines()
-        // Attribute names starting with _ are usually meant to be "private"
-        .filter(|attr| !attr.contains("._"))
-        // Reformat each line
-        .fold(String::with_capacity(stdout.len()), |mut acc, line| {
-            acc.push_str(&rewrite_attr_line(line).trim_end());
-            acc.push_str("\n");
-            acc
-        })
+/// The `meta` attributes `NixMeta` reads. Only these are evaluated, because
+/// others may hold functions, which can't be converted to JSON.
+const META_KEYS: &[&str] = &[
+    "available",
+    "broken",
+    "description",
+    "longDescription",
+    "homepage",
+    "downloadPage",
+    "license",
+    "name",
+    "outputsToInstall",
+    "platforms",
+    "position",
+    "priority",
+    "maintainers",
+    "teams",
+];
+
+/// A Nix expression for the path of `channel`. Like nix-env, channels are
+/// looked up in `defexpr` (`~/.nix-defexpr`) first, the user's before root's;
+/// other channels are looked up in `NIX_PATH`.
+fn channel_path_expr(channel: &str, defexpr: Option<&Path>) -> String {
+    let path = defexpr.and_then(|defexpr| {
+        ["channels", "channels_root"]
+            .iter()
+            .map(|dir| defexpr.join(dir).join(channel))
+            .find(|path| path.is_dir())
+    });
+    match path {
+        Some(path) => nix_string(&path.to_string_lossy()),
+        None => format!(
+            "(builtins.findFile builtins.nixPath {})",
+            nix_string(channel)
+        ),
+    }
 }
 
-pub fn nix_query_all() -> Result<String, CommandError> {
-    let mut args = vec!["--query", "--available", "--attr-path", "--description"];
-
-    let mut output =
-        proc::run_cmd_stdout(Command::new("nix-env").args(&args)).map(rewrite_attr_lines)?;
-
-    // A few sub-packages don't show up by default. Is there a better way to
-    // include them...?
-    // TODO: Select 'nixpkgs' or 'nixos' automatically, somehow.
-    let extra_attrs = &["nixpkgs.nodePackages", "nixpkgs.haskellPackages"];
-
-    args.push("--attr");
-    // We'll fill this last value in with the individual attr in the loop.
-    args.push("");
+/// A Nix expression importing `channel`, found like `channel_path_expr`.
+fn channel_import(channel: &str, defexpr: Option<&Path>) -> String {
+    format!("import {} {{ }}", channel_path_expr(channel, defexpr))
+}
 
-    for base_attr in extra_attrs {
-        args.pop();
-        args.push(base_attr);
-        output.push_str(
-            &proc::run_cmd_stdout(Command::new("nix-env").args(&args)).map(rewrite_attr_lines)?,
+/// A Nix expression for the information of each of `attrs`, in the same shape
+/// as `nix-env --query --json` output. Each channel is imported once.
+fn nix_query_many_expr(attrs: &[&str], defexpr: Option<&Path>) -> String {
+    let mut channels: Vec<&str> = attrs
+        .iter()
+        .map(|attr| attr.split('.').next().unwrap_or_default())
+        .collect();
+    channels.sort_unstable();
+    channels.dedup();
+
+    let mut expr = String::from("let\n  info = p: {\n");
+    expr.push_str("    inherit (p) name system;\n");
+    expr.push_str("    inherit (builtins.parseDrvName p.name) version;\n");
+    expr.push_str("    pname = (builtins.parseDrvName p.name).name;\n");
+    expr.push_str("    meta = builtins.intersectAttrs {");
+    for key in META_KEYS {
+        expr.push_str(&format!(" {} = null;", key));
+    }
+    expr.push_str(" } (p.meta or { })\n");
+    expr.push_str("      // (if p.meta.tests or null == null then { } else { tests = true; });\n");
+    expr.push_str("  };\n");
+    for (i, channel) in channels.iter().enumerate() {
+        expr.push_str(&format!(
+            "  c{} = {};\n",
+            i,
+            channel_import(channel, defexpr)
+        ));
+    }
+    expr.push_str("in {\n");
+    for attr in attrs {
+        let mut path = attr.split('.');
+        let channel = path.next().unwrap_or_default();
+        let i = channels.binary_search(&channel).unwrap_or_default();
+        let path: Vec<String> = path.map(nix_string).collect();
+        if path.is_empty() {
+            // The channel itself.
+            expr.push_str(&format!("  {} = info c{};\n", nix_string(attr), i));
+            continue;
+        }
+        // Missing attributes are `null`, rather than failing the whole query.
+        let path = path.join(".");
+        expr.push_str(&format!(
+            "  {} = if c{i} ? {path} then info c{i}.{path} else null;\n",
+            nix_string(attr),
+            i = i,
+            path = path,
+        ));
+    }
+    expr.push('}');
+    expr
+}
+
+fn nix_query_many_cmd(attrs: &[&str]) -> Command {
+    let mut cmd = nix_command("nix");
+    cmd.args([
+        "--extra-experimental-features",
+        "nix-command",
+        "eval",
+        "--impure",
+        "--json",
+        "--expr",
+    ])
+    .arg(nix_query_many_expr(attrs, defexpr_dir().as_deref()));
+    cmd
+}
+
+/// Whether to query with `nix eval`, which needs Nix 2.4 or later. If the
+/// version can't be found, `nix-env` is used, which every version has.
+fn use_nix_eval() -> bool {
+    let info = cache::backend_info().ok().or_else(|| detect_backend().ok());
+    info.map(|info| info.backend) == Some(Backend::Nix)
+}
+
+/// Queries each of `attrs` with `nix-env`, for versions of Nix without
+/// `nix eval`. nix-env fails if the attribute doesn't exist, so an attribute
+/// it fails for is missing; other errors are returned.
+fn nix_env_query_many(
+    attrs: &[&str],
+    timeout: Option<Duration>,
+) -> Result<BatchQuery, NixQueryError> {
+    let mut found = Vec::new();
+    let mut missing = Vec::new();
+    for attr in attrs {
+        let mut cmd = nix_query_cmd(attr);
+        let json = match timeout {
+            Some(timeout) => proc::run_cmd_stdout_timeout(&mut cmd, timeout),
+            None => proc::run_cmd_stdout(&mut cmd),
+        };
+        match json {
+            Ok(json) => found.extend(parse_query_all(&json)?),
+            Err(CommandError::ExitStatus(_)) => missing.push(attr.to_string()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    found.sort_by(|a, b| (&a.attr, &a.system).cmp(&(&b.attr, &b.system)));
+    Ok(BatchQuery { found, missing })
+}
+
+/// The result of querying several attributes at once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchQuery {
+    /// The information Nix gave, sorted by attribute. This may include
+    /// attributes that weren't asked for.
+    pub found: Vec<NixInfo>,
+    /// The attributes that were asked for but that Nix doesn't have, in the
+    /// order they were asked for.
+    pub missing: Vec<String>,
+}
+
+impl BatchQuery {
+    /// Splits the output of a batch query for `attrs` into the attributes
+    /// found and missing. Missing attributes are `null` or left out.
+    fn parse(attrs: &[&str], json: &str) -> Result<Self, NixQueryError> {
+        let all: HashMap<String, Option<NixInfo>> =
+            serde_json::from_str(json).map_err(CommandError::De)?;
+        let missing = attrs
+            .iter()
+            .filter(|attr| !matches!(all.get(**attr), Some(Some(_))))
+            .map(|attr| attr.to_string())
+            .collect();
+        Ok(BatchQuery {
+            found: sorted_infos(
+                all.into_iter()
+                    .filter_map(|(attr, info)| Some((attr, info?))),
+            ),
+            missing,
+        })
+    }
+
+    /// The first package found, or an error if any attribute is missing.
+    fn into_first(self) -> Result<NixInfo, NixQueryError> {
+        if !self.missing.is_empty() {
+            return Err(NixQueryError::Missing(self.missing));
+        }
+        first_info(self.found)
+    }
+}
+
+/// Queries the information of many attributes at once. Unlike calling
+/// `nix_query` for each of them, nixpkgs is only evaluated once, which is
+/// much faster. Versions of Nix before 2.4 don't have `nix eval`, so there
+/// each attribute is queried with `nix-env` in turn.
+pub fn nix_query_many(attrs: &[&str]) -> Result<BatchQuery, NixQueryError> {
+    if !use_nix_eval() {
+        return nix_env_query_many(attrs, None);
+    }
+    BatchQuery::parse(
+        attrs,
+        &proc::run_cmd_stdout(&mut nix_query_many_cmd(attrs))?,
+    )
+}
+
+/// Like `nix_query_many`, but gives up if Nix takes longer than `timeout`.
+pub fn nix_query_many_timeout(
+    attrs: &[&str],
+    timeout: Duration,
+) -> Result<BatchQuery, NixQueryError> {
+    if !use_nix_eval() {
+        return nix_env_query_many(attrs, Some(timeout));
+    }
+    BatchQuery::parse(
+        attrs,
+        &proc::run_cmd_stdout_timeout(&mut nix_query_many_cmd(attrs), timeout)?,
+    )
+}
+
+pub fn nix_query(attr: &str) -> Result<NixInfo, NixQueryError> {
+    nix_query_many(&[attr])?.into_first()
+}
+
+/// Like `nix_query`, but gives up if Nix takes longer than `timeout`.
+pub fn nix_query_timeout(attr: &str, timeout: Duration) -> Result<NixInfo, NixQueryError> {
+    nix_query_many_timeout(&[attr], timeout)?.into_first()
+}
+
+/// Queries `attr` with `nix-env --json` rather than `nix eval`, parsing the
+/// output with `try_parse_all`, so entries that don't match our schema are
+/// skipped rather than failing the query. A fallback for when `nix_query`
+/// fails.
+pub fn nix_query_lenient(attr: &str, timeout: Duration) -> Result<NixInfo, NixQueryError> {
+    let parsed = try_parse_all(&proc::run_cmd_stdout_timeout(
+        &mut nix_query_cmd(attr),
+        timeout,
+    )?)
+    .map_err(CommandError::De)?;
+    match parsed.failed.into_iter().next() {
+        Some((_, e)) if parsed.ok.is_empty() => Err(CommandError::De(e).into()),
+        _ => first_info(parsed.ok),
+    }
+}
+
+/// Whether each of `attrs` exists, according to Nix. They're queried all at
+/// once first; if that fails (say, because one is in a channel that doesn't
+/// exist), they're queried one at a time. Only attributes Nix says it doesn't
+/// have are missing; other failures, like timeouts, are errors.
+pub fn nix_attrs_exist(attrs: &[&str], timeout: Duration) -> Result<Vec<bool>, NixQueryError> {
+    if attrs.is_empty() {
+        return Ok(Vec::new());
+    }
+    match nix_query_many_timeout(attrs, timeout) {
+        Ok(batch) => {
+            return Ok(attrs
+                .iter()
+                .map(|attr| !batch.missing.iter().any(|missing| missing == attr))
+                .collect())
+        }
+        Err(e @ NixQueryError::Command(CommandError::Timeout(_))) => return Err(e),
+        Err(_) => {}
+    }
+    attrs
+        .iter()
+        .map(|attr| match nix_query_timeout(attr, timeout) {
+            Ok(_) => Ok(true),
+            Err(NixQueryError::Missing(_)) | Err(NixQueryError::Empty) => Ok(false),
+            Err(e) => Err(e),
+        })
+        .collect()
+}
+
+fn first_info(all: Vec<NixInfo>) -> Result<NixInfo, NixQueryError> {
+    all.into_iter().next().ok_or(NixQueryError::Empty)
+}
+
+/// Like `nix_query_timeout`, but returns every entry Nix gives for the
+/// attribute (one per system, say) rather than just the first.
+pub fn nix_query_all_systems(attr: &str, timeout: Duration) -> Result<Vec<NixInfo>, NixQueryError> {
+    let all = parse_query_all(&proc::run_cmd_stdout_timeout(
+        &mut nix_query_cmd(attr),
+        timeout,
+    )?)?;
+    if all.is_empty() {
+        Err(NixQueryError::Empty)
+    } else {
+        Ok(all)
+    }
+}
+
+/// Made-up packages which, between them, exercise every styled part of
+/// `--info`: each kind of license, broken and unavailable packages,
+/// maintainers, URLs, and Markdown.
+const COLOR_TEST_JSON: &str = r#"{
+    "colorTest.a-everything": {
+        "name": "everything-1.0",
+        "pname": "everything",
+        "version": "1.0",
+        "system": "x86_64-linux",
+        "meta": {
+            "description": "A package with every field set",
+            "longDescription": "Has **bold** and __bold__ text, `code`, and a list:\n- one\n* two\n+ three\n",
+            "homepage": "https://example.com/",
+            "downloadPage": "https://example.com/download",
+            "license": {
+                "fullName": "MIT License",
+                "shortName": "mit",
+                "spdxId": "MIT",
+                "url": "https://spdx.org/licenses/MIT.html"
+            },
+            "maintainers": [
+                "someone",
+                {"name": "Jane Doe", "email": "jane@example.com", "github": "jane"},
+                {"email": "jdoe@example.com"}
+            ],
+            "teams": [
+                {"shortName": "example", "members": ["someone", "another"]}
+            ],
+            "priority": 10,
+            "tests": {},
+            "position": "/nix/store/example/pkgs/everything/default.nix:42"
+        }
+    },
+    "colorTest.b-broken": {
+        "name": "broken-2.0",
+        "pname": "broken",
+        "version": "2.0",
+        "meta": {
+            "description": "Broken, unavailable, and unfree",
+            "broken": true,
+            "available": false,
+            "license": {
+                "fullName": "Unfree redistributable",
+                "shortName": "unfreeRedistributable",
+                "free": false,
+                "url": "https://example.com/LICENSE"
+            }
+        }
+    },
+    "colorTest.c-licenses": {
+        "name": "licenses-3.0",
+        "pname": "licenses",
+        "version": "3.0",
+        "meta": {
+            "description": "Several licenses",
+            "license": [
+                {"fullName": "BSD 3-clause", "shortName": "bsd3"},
+                {"fullName": "Unfree", "shortName": "unfree", "free": false}
+            ]
+        }
+    },
+    "colorTest.d-named-license": {
+        "name": "named-license-4.0",
+        "pname": "named-license",
+        "version": "4.0",
+        "meta": {"license": {"fullName": "Public Domain"}}
+    },
+    "colorTest.d-url-license": {
+        "name": "url-license-4.1",
+        "pname": "url-license",
+        "version": "4.1",
+        "meta": {"license": {"url": "https://example.com/LICENSE"}}
+    },
+    "colorTest.e-license-id": {
+        "name": "license-id-5.0",
+        "pname": "license-id",
+        "version": "5.0",
+        "meta": {"license": "gpl3Plus"}
+    },
+    "colorTest.f-unfree-bool": {
+        "name": "unfree-bool-6.0",
+        "pname": "unfree-bool",
+        "version": "6.0",
+        "meta": {"license": false}
+    },
+    "colorTest.g-unknown-license": {
+        "name": "unknown-license-7.0",
+        "pname": "unknown-license",
+        "version": "7.0",
+        "meta": {"license": null}
+    }
+}"#;
+
+/// The packages shown by `--color-test`.
+pub fn color_test_infos() -> Vec<NixInfo> {
+    parse_query_all(COLOR_TEST_JSON).expect("the --color-test packages should parse")
+}
+
+/// Every entry in `nix-env --json` output, sorted by attribute and system.
+fn parse_query_all(json: &str) -> Result<Vec<NixInfo>, NixQueryError> {
+    Ok(sorted_infos(
+        serde_json::from_str::<AllNixInfo>(json)
+            .map_err(CommandError::De)?
+            .attrs,
+    ))
+}
+
+/// Packages keyed by attribute, with their attributes filled in, sorted by
+/// attribute and system.
+fn sorted_infos(infos: impl IntoIterator<Item = (String, NixInfo)>) -> Vec<NixInfo> {
+    let mut all: Vec<NixInfo> = infos
+        .into_iter()
+        .map(|(attr, info)| NixInfo {
+            attr: Some(attr),
+            ..info
+        })
+        .collect();
+    all.sort_by(|a, b| (&a.attr, &a.system).cmp(&(&b.attr, &b.system)));
+    all
+}
+
+/// Whether a package exists and can be installed, for `--check`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PackageStatus {
+    pub attr: String,
+    pub exists: bool,
+    pub available: bool,
+    pub broken: bool,
+    pub version: Option<String>,
+}
+
+impl PackageStatus {
+    /// The status of `attr`, given its information if it exists.
+    pub fn new(attr: &str, info: Option<&NixInfo>) -> Self {
+        PackageStatus {
+            attr: attr.to_string(),
+            exists: info.is_some(),
+            available: info.is_some_and(|info| info.meta.available),
+            broken: info.is_some_and(|info| info.meta.broken),
+            version: info.map(|info| info.version.clone()),
+        }
+    }
+
+    /// Whether the package can be installed.
+    pub fn ok(&self) -> bool {
+        self.exists && self.available && !self.broken
+    }
+}
+
+/// Queries Nix for the status of `attr`. An attribute Nix can't find
+/// doesn't exist, rather than being an error.
+pub fn package_status(attr: &str) -> Result<PackageStatus, NixQueryError> {
+    match nix_query(attr) {
+        Ok(info) => Ok(PackageStatus::new(attr, Some(&info))),
+        Err(NixQueryError::Empty) | Err(NixQueryError::Missing(_)) => {
+            Ok(PackageStatus::new(attr, None))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// How an attribute's `nix-env --json` output fared against our schema.
+#[derive(Debug)]
+pub enum SchemaAudit {
+    Ok,
+    /// The output didn't match our schema.
+    Invalid(serde_json::Error),
+    /// Nix couldn't be queried about the attribute at all.
+    QueryFailed(NixQueryError),
+}
+
+/// Checks that the attribute's information can be parsed.
+pub fn audit_schema(attr: &str) -> SchemaAudit {
+    match nix_query(attr) {
+        Ok(_) => SchemaAudit::Ok,
+        Err(NixQueryError::Command(CommandError::De(e))) => SchemaAudit::Invalid(e),
+        Err(e) => SchemaAudit::QueryFailed(e),
+    }
+}
+
+/// nix-env gives very long lines that are nicely, yet inconveniently, aligned:
+/// ```plain
+/// nixos._0x0                                                                0x0-2018-06-24                                                                      A client for 0x0.st
+/// ```
+/// That's nice. rewrite_attr_line replaces long stretches of whitespace with
+/// FIELD_DELIMITER.
+fn rewrite_attr_line<'a>(line: &'a str) -> Cow<'a, str> {
+    regexes::COLUMN_GAP.replace_all(line, FIELD_DELIMITER)
+}
+
+/// Whether `line` looks like a line of `nix-env --query` output: an
+/// attribute, then a column gap and a value. Some setups print progress like
+/// `(1/3) querying...` to stdout among the attribute lines.
+fn is_attr_line(line: &str) -> bool {
+    regexes::ATTR_LINE.is_match(line)
+}
+
+/// Attribute names starting with _ are usually meant to be "private".
+fn is_private(attr: &str) -> bool {
+    attr.contains("._")
+}
+
+/// Reformats `nix-env --query` output for the cache, leaving out private
+/// attributes unless `include_private` is set.
+pub(crate) fn rewrite_attr_lines(stdout: String, include_private: bool) -> String {
+    stdout
+        .lines()
+        .filter(|line| is_attr_line(line))
+        .filter(|line| include_private || !is_private(line))
+        // Reformat each line
+        .fold(String::with_capacity(stdout.len()), |mut acc, line| {
+            acc.push_str(rewrite_attr_line(line).trim_end());
+            acc.push('\n');
+            acc
+        })
+}
+
+/// nix-env warns when two channels define the same top-level name:
+/// ```plain
+/// warning: name collision in input Nix expressions, skipping '/home/user/.nix-defexpr/channels_root/nixos'
+/// ```
+/// parse_collisions returns the skipped names from those warnings.
+fn parse_collisions(stderr: &str) -> Vec<String> {
+    regexes::NAME_COLLISION
+        .captures_iter(stderr)
+        .map(|c| c[1].to_string())
+        .collect()
+}
+
+/// Two packages `nix-env --install` couldn't install together, because they
+/// have the same priority and provide the same file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PriorityCollision {
+    /// The colliding packages' names, like `gzip-1.10`.
+    pub names: Vec<String>,
+    pub priority: i32,
+}
+
+/// Parses a collision from `nix-env --install`'s stderr, which looks like:
+/// ```plain
+/// error: packages '/nix/store/...-gzip-1.10/bin/gzip' and '/nix/store/...-gzip-1.11/bin/gzip' have the same priority 5; use 'nix-env --set-flag priority NUMBER INSTALLED_PKGNAME' to change the priority of one of the conflicting packages (0 being the highest priority)
+/// ```
+pub fn parse_priority_collision(stderr: &str) -> Option<PriorityCollision> {
+    // The paths are of the colliding files, within the packages' store paths.
+    let name = |path: &str| {
+        let store_path = path.split('/').skip_while(|dir| *dir != "store").nth(1)?;
+        Some(store_path_name(store_path).to_string())
+    };
+    let captures = regexes::PRIORITY_COLLISION.captures(stderr)?;
+    Some(PriorityCollision {
+        names: vec![name(&captures[1])?, name(&captures[2])?],
+        priority: captures[3].parse().ok()?,
+    })
+}
+
+/// The result of populating the attribute list.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct AllAttrs {
+    /// One line per attribute, with fields separated by `FIELD_DELIMITER`.
+    pub attrs: String,
+    /// Names nix-env skipped because of a name collision.
+    pub collisions: Vec<String>,
+    /// How many lines `dedup` removed because their attribute was already
+    /// listed.
+    pub duplicates: usize,
+    /// Attributes whose `--json` metadata didn't match our schema, which are
+    /// left out of `attrs`, and why.
+    pub unparsed: Vec<(String, String)>,
+}
+
+impl AllAttrs {
+    fn push_query(&mut self, (stdout, stderr): (String, String), include_private: bool) {
+        self.attrs
+            .push_str(&rewrite_attr_lines(stdout, include_private));
+        for name in parse_collisions(&stderr) {
+            if !self.collisions.contains(&name) {
+                self.collisions.push(name);
+            }
+        }
+    }
+
+    /// Removes lines for attributes listed on an earlier line, which happens
+    /// when the extra package sets overlap with the main query.
+    fn dedup(&mut self) {
+        let mut seen = HashSet::new();
+        let mut deduped = String::with_capacity(self.attrs.len());
+        for line in self.attrs.lines() {
+            let attr = line
+                .trim_start()
+                .split(FIELD_DELIMITER)
+                .next()
+                .unwrap_or_default();
+            if seen.insert(attr) {
+                deduped.push_str(line);
+                deduped.push('\n');
+            } else {
+                self.duplicates += 1;
+            }
+        }
+        self.attrs = deduped;
+    }
+
+    /// True if no attributes were found at all, which usually means the
+    /// channels are missing or misconfigured.
+    pub fn is_empty(&self) -> bool {
+        self.attrs.lines().all(|line| line.trim().is_empty())
+    }
+}
+
+/// Options controlling how the attribute list is populated.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PopulateOptions {
+    /// Query full package metadata with `--json --meta` rather than just
+    /// names and descriptions. This is much slower, but adds columns for
+    /// whether each package is broken or free and the platforms it supports.
+    pub rich: bool,
+    /// Don't scan `EXTRA_PACKAGE_SETS`, which is faster but leaves out their
+    /// packages.
+    pub skip_extra_attrs: bool,
+    /// Fail if any package's metadata doesn't match our schema, rather than
+    /// leaving it out. Only matters with `rich`, which parses JSON.
+    pub strict: bool,
+    /// Keep "private" attributes, whose names start with `_`, like
+    /// `nixpkgs._sources`.
+    pub include_private: bool,
+}
+
+/// Package sets scanned separately because their packages don't show up in
+/// nix-env's default query. Is there a better way to include them...? They're
+/// scanned in the `default_channel`.
+pub const EXTRA_PACKAGE_SETS: &[&str] = &["nodePackages", "haskellPackages"];
+
+/// The `EXTRA_PACKAGE_SETS` in `nixpkgs`, as they were scanned before the
+/// `default_channel` was.
+#[deprecated(
+    note = "use `EXTRA_PACKAGE_SETS` or `extra_attrs()`, which respect the default channel"
+)]
+pub const EXTRA_ATTRS: &[&str] = &["nixpkgs.nodePackages", "nixpkgs.haskellPackages"];
+
+/// Whether this machine runs NixOS.
+pub fn is_nixos() -> bool {
+    Path::new("/etc/NIXOS").exists()
+}
+
+/// The channel packages usually come from: `nixos` on NixOS and `nixpkgs`
+/// elsewhere.
+pub fn default_channel() -> &'static str {
+    if is_nixos() {
+        "nixos"
+    } else {
+        "nixpkgs"
+    }
+}
+
+/// Packages parsed from `nix-env --json` output.
+#[derive(Debug, Default)]
+pub struct ParsedInfo {
+    /// Packages that parsed successfully, sorted by attribute.
+    pub ok: Vec<NixInfo>,
+    /// Attributes that failed to parse, and why.
+    pub failed: Vec<(String, serde_json::Error)>,
+}
+
+/// Parses `nix-env --json` output leniently: a package which doesn't match
+/// our schema is recorded in `failed` rather than failing the whole parse.
+pub fn try_parse_all(json: &str) -> Result<ParsedInfo, serde_json::Error> {
+    let mut ret = ParsedInfo::default();
+    for (attr, value) in serde_json::from_str::<BTreeMap<String, serde_json::Value>>(json)? {
+        match serde_json::from_value::<NixInfo>(value) {
+            Ok(info) => ret.ok.push(NixInfo {
+                attr: Some(attr),
+                ..info
+            }),
+            Err(e) => ret.failed.push((attr, e)),
+        }
+    }
+    Ok(ret)
+}
+
+/// Formats a package as a line of a rich cache:
+/// ```plain
+/// attr    name    description    broken    free    platforms    tests    available
+/// ```
+/// `broken`, `free`, `tests`, and `available` are `true` or `false`, and
+/// `platforms` is comma-separated. `available` is `meta.available` for the
+/// system Nix evaluated the package for, which is the current one. Like the
+/// plain cache, the attribute comes first so the display column is the same.
+fn rich_attr_line(info: &NixInfo) -> String {
+    let meta = &info.meta;
+    [
+        info.attr.as_deref().unwrap_or_default(),
+        &info.name,
+        &regexes::WHITESPACE.replace_all(meta.description.as_deref().unwrap_or_default(), " "),
+        if meta.broken { "true" } else { "false" },
+        if meta.license.iter().flatten().all(License::is_free) {
+            "true"
+        } else {
+            "false"
+        },
+        &meta.platforms.join(","),
+        if meta.tests { "true" } else { "false" },
+        if meta.available { "true" } else { "false" },
+    ]
+    .join(FIELD_DELIMITER)
+}
+
+/// A line of the cache, split into its fields.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct AttrLine<'a> {
+    pub attr: &'a str,
+    pub name: &'a str,
+    pub description: &'a str,
+    /// The remaining fields are only present in a rich cache; see
+    /// `rich_attr_line`.
+    pub broken: Option<bool>,
+    pub free: Option<bool>,
+    /// Empty if the package doesn't list its platforms.
+    pub platforms: Option<Vec<&'a str>>,
+    /// Missing from rich caches written before this column was added.
+    pub tests: Option<bool>,
+    /// Whether the package is available on the current system. Missing from
+    /// rich caches written before this column was added.
+    pub available: Option<bool>,
+}
+
+impl<'a> AttrLine<'a> {
+    /// Splits a line of the cache into fields. `rich` says whether the line
+    /// came from a rich cache; in a plain cache, descriptions may be split
+    /// into several fields, so fields past the third are part of the
+    /// description.
+    pub fn parse(line: &'a str, rich: bool) -> Self {
+        if !rich {
+            let mut fields = line.splitn(3, FIELD_DELIMITER);
+            return AttrLine {
+                attr: fields.next().unwrap_or_default(),
+                name: fields.next().unwrap_or_default(),
+                description: fields.next().unwrap_or_default(),
+                ..Default::default()
+            };
+        }
+
+        let mut fields = line.split(FIELD_DELIMITER);
+        let mut next = || fields.next().unwrap_or_default();
+        AttrLine {
+            attr: next(),
+            name: next(),
+            description: next(),
+            broken: Some(next() == "true"),
+            free: Some(next() != "false"),
+            platforms: Some(next().split(',').filter(|p| !p.is_empty()).collect()),
+            tests: fields.next().map(|tests| tests == "true"),
+            available: fields.next().map(|available| available == "true"),
+        }
+    }
+
+    pub fn console_fmt_with(&'a self, styling: Styling) -> ConsoleFormatAttrLine<'a> {
+        ConsoleFormatAttrLine(self, styling)
+    }
+
+    /// Whether the package can be built for `system`, which should be the
+    /// current system. If the cache records `meta.available`, that decides;
+    /// otherwise, the package's platforms do. Packages that don't list their
+    /// platforms (or come from a plain cache) are assumed to support every
+    /// system.
+    pub fn supports(&self, system: &str) -> bool {
+        if let Some(available) = self.available {
+            return available;
+        }
+        match &self.platforms {
+            Some(platforms) if !platforms.is_empty() => platforms.contains(&system),
+            _ => true,
+        }
+    }
+}
+
+/// Splits a package name like `gzip-1.10` into its name and version, like
+/// `builtins.parseDrvName`: the version starts after the first `-` that's
+/// followed by something other than a letter.
+pub fn split_name_version(name: &str) -> (&str, &str) {
+    name.match_indices('-')
+        .find(|(i, _)| {
+            name[i + 1..]
+                .chars()
+                .next()
+                .is_some_and(|c| !c.is_alphabetic())
+        })
+        .map_or((name, ""), |(i, _)| (&name[..i], &name[i + 1..]))
+}
+
+/// The next component of a version, like `builtins.compareVersions` splits
+/// them: a run of digits or of other characters, skipping `.` and `-`
+/// separators. Empty at the end of the version.
+fn next_version_component<'a>(version: &mut &'a str) -> &'a str {
+    *version = version.trim_start_matches(['.', '-']);
+    let is_digit = version.starts_with(|c: char| c.is_ascii_digit());
+    let len = version
+        .find(|c: char| c == '.' || c == '-' || c.is_ascii_digit() != is_digit)
+        .unwrap_or(version.len());
+    let (component, rest) = version.split_at(len);
+    *version = rest;
+    component
+}
+
+/// Compares two version components like Nix does: numbers numerically, `pre`
+/// before anything else, and other strings before numbers and lexically
+/// among themselves. An empty component (at the end of a version) comes
+/// before a number, so `1.0` is older than `1.0.1`.
+fn version_component_cmp(a: &str, b: &str) -> Ordering {
+    // Numbers are compared without parsing them, so huge ones work too.
+    fn number(s: &str) -> Option<&str> {
+        if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+            Some(s.trim_start_matches('0'))
+        } else {
+            None
+        }
+    }
+    match (number(a), number(b)) {
+        (Some(a), Some(b)) => a.len().cmp(&b.len()).then_with(|| a.cmp(b)),
+        (None, Some(_)) if a.is_empty() => Ordering::Less,
+        (Some(_), None) if b.is_empty() => Ordering::Greater,
+        _ if a == b => Ordering::Equal,
+        _ if a == "pre" => Ordering::Less,
+        _ if b == "pre" => Ordering::Greater,
+        (None, Some(_)) => Ordering::Less,
+        (Some(_), None) => Ordering::Greater,
+        (None, None) => a.cmp(b),
+    }
+}
+
+/// Compares two versions, like `1.9` and `1.10`, the way
+/// `builtins.compareVersions` does. Versions Nix considers equal but which
+/// are written differently, like `1.01` and `1.1`, are compared as strings so
+/// the order is total.
+pub fn version_cmp(a: &str, b: &str) -> Ordering {
+    let (mut rest_a, mut rest_b) = (a, b);
+    while !rest_a.is_empty() || !rest_b.is_empty() {
+        let ordering = version_component_cmp(
+            next_version_component(&mut rest_a),
+            next_version_component(&mut rest_b),
+        );
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.cmp(b)
+}
+
+/// A short `--info`, using only what the cache knows about a package.
+pub struct ConsoleFormatAttrLine<'a>(&'a AttrLine<'a>, Styling);
+
+impl Display for ConsoleFormatAttrLine<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let ConsoleFormatAttrLine(line, styling) = *self;
+        let label = |label: &str| styling.style(format!("{}:", label)).bold();
+        let (_, version) = split_name_version(line.name);
+
+        writeln!(
+            f,
+            "{} {}",
+            label("attr"),
+            styling.style(line.attr).bold().green()
+        )?;
+        writeln!(
+            f,
+            "{} {}",
+            label("name"),
+            styling.style(line.name).bold().green()
+        )?;
+        if !version.is_empty() {
+            writeln!(f, "{} {}", label("version"), version)?;
+        }
+        writeln!(
+            f,
+            "{} {}",
+            label("channel"),
+            line.attr.split('.').next().unwrap_or_default()
+        )?;
+        if line.broken == Some(true) {
+            writeln!(
+                f,
+                "{} {}",
+                label("broken"),
+                styling.style("true").bold().red()
+            )?;
+        }
+        if line.free == Some(false) {
+            writeln!(f, "{} {}", label("license"), styling.style("unfree").red())?;
+        }
+        match &line.platforms {
+            Some(platforms) if !platforms.is_empty() => {
+                writeln!(f, "{} {}", label("platforms"), platforms.join(", "))?
+            }
+            _ => {}
+        }
+        if !line.description.is_empty() {
+            writeln!(f, "{} {}", label("description"), line.description)?;
+        }
+        Ok(())
+    }
+}
+
+/// Which lines of a rich cache to hide.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LineFilter {
+    pub hide_broken: bool,
+    pub hide_unfree: bool,
+}
+
+impl LineFilter {
+    /// Whether the line should be shown. Lines from a plain cache are always
+    /// shown.
+    pub fn keeps(&self, line: &AttrLine<'_>) -> bool {
+        !(self.hide_broken && line.broken == Some(true)
+            || self.hide_unfree && line.free == Some(false))
+    }
+
+    /// The lines of a rich cache this filter keeps.
+    pub fn apply(&self, lines: &str) -> String {
+        lines
+            .lines()
+            .filter(|line| self.keeps(&AttrLine::parse(line, true)))
+            .fold(String::with_capacity(lines.len()), |mut acc, line| {
+                acc.push_str(line);
+                acc.push('\n');
+                acc
+            })
+    }
+}
+
+/// Which Nix command-line interface is used to query packages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// The classic `nix-env`.
+    NixEnv,
+    /// The `nix` command from Nix 2.4 and later, which supports flakes.
+    Nix,
+}
+
+impl Backend {
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::NixEnv => "nix-env",
+            Backend::Nix => "nix",
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nix-env" => Ok(Backend::NixEnv),
+            "nix" => Ok(Backend::Nix),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The detected backend and the version of Nix it belongs to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackendInfo {
+    pub backend: Backend,
+    pub version: String,
+}
+
+/// Parses the version out of `nix --version` output like `nix (Nix) 2.3.4`.
+fn parse_nix_version(output: &str) -> Option<&str> {
+    output.split_whitespace().last()
+}
+
+/// Whether a Nix version is 2.4 or later, and so has the `nix` command with
+/// flake support.
+fn has_nix_command(version: &str) -> bool {
+    let mut parts = version.split('.').map(|n| n.parse::<u32>().unwrap_or(0));
+    let major = parts.next().unwrap_or(0);
+    let minor = parts.next().unwrap_or(0);
+    (major, minor) >= (2, 4)
+}
+
+/// Detects the backend by asking Nix for its version. This runs a command,
+/// so prefer `cache::backend_info`, which remembers the result.
+pub fn detect_backend() -> Result<BackendInfo, CommandError> {
+    let output = proc::run_cmd_stdout(nix_command("nix-env").arg("--version"))?;
+    let version = parse_nix_version(&output).unwrap_or_default().to_string();
+    Ok(BackendInfo {
+        backend: if has_nix_command(&version) {
+            Backend::Nix
+        } else {
+            Backend::NixEnv
+        },
+        version,
+    })
+}
+
+/// The result of looking up a possibly-unqualified attribute in the cache.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resolved {
+    /// The attribute to query.
+    Attr(String),
+    /// More than one attribute matches.
+    Ambiguous(Vec<String>),
+    /// No attribute matches.
+    NotFound,
+}
+
+/// Resolves a fully-qualified attribute (`nixpkgs.gzip`) or a bare one
+/// (`gzip`, matching `nixpkgs.gzip`, `nixos.gzip`, and so on) against the
+/// attribute column of the cache.
+pub fn resolve_attr(attrs: &str, query: &str) -> Resolved {
+    let mut candidates = Vec::new();
+    for attr in attrs
+        .lines()
+        .filter_map(|line| line.split(FIELD_DELIMITER).next())
+    {
+        if attr == query {
+            return Resolved::Attr(attr.to_string());
+        }
+        if attr.split_once('.').map(|(_, rest)| rest) == Some(query) {
+            candidates.push(attr.to_string());
+        }
+    }
+
+    match candidates.len() {
+        0 => Resolved::NotFound,
+        1 => Resolved::Attr(candidates.remove(0)),
+        _ => Resolved::Ambiguous(candidates),
+    }
+}
+
+/// Channels in order of preference, when nothing else is configured.
+pub const DEFAULT_CHANNEL_PRIORITY: &[&str] = &["nixpkgs", "nixos", "unstable"];
+
+/// How preferred the channel of `attr` (like `nixos` for `nixos.gzip`) is,
+/// given the channels in order of preference; lower is better. Channels not
+/// in `priority` come after all the ones that are.
+pub fn channel_priority<S: AsRef<str>>(attr: &str, priority: &[S]) -> u8 {
+    let channel = attr.split('.').next().unwrap_or_default();
+    priority
+        .iter()
+        .position(|c| c.as_ref() == channel)
+        .unwrap_or(priority.len())
+        .min(u8::MAX as usize) as u8
+}
+
+/// Sorts cache lines by attribute, ignoring the channel. The same attribute
+/// from several channels is ordered by `channel_priority`.
+pub fn sort_attr_lines<S: AsRef<str>>(lines: &str, priority: &[S]) -> String {
+    let mut sorted: Vec<(&str, u8, &str)> = lines
+        .lines()
+        .map(|line| {
+            let attr = line.split(FIELD_DELIMITER).next().unwrap_or_default();
+            let bare = attr.split_once('.').map_or(attr, |(_, rest)| rest);
+            (bare, channel_priority(attr, priority), line)
+        })
+        .collect();
+    sorted.sort();
+    sorted.into_iter().fold(
+        String::with_capacity(lines.len()),
+        |mut acc, (_, _, line)| {
+            acc.push_str(line);
+            acc.push('\n');
+            acc
+        },
+    )
+}
+
+/// Sorts cache lines by package name and then version, oldest first (see
+/// `version_cmp`). Packages with the same name and version are ordered like
+/// `sort_attr_lines`.
+pub fn sort_attr_lines_by_version<S: AsRef<str>>(lines: &str, priority: &[S]) -> String {
+    let mut sorted: Vec<(&str, &str, &str, u8, &str)> = lines
+        .lines()
+        .map(|line| {
+            let mut fields = line.split(FIELD_DELIMITER);
+            let attr = fields.next().unwrap_or_default();
+            let (name, version) = split_name_version(fields.next().unwrap_or_default());
+            let bare = attr.split_once('.').map_or(attr, |(_, rest)| rest);
+            (name, version, bare, channel_priority(attr, priority), line)
+        })
+        .collect();
+    sorted.sort_by(|a, b| {
+        a.0.cmp(b.0)
+            .then_with(|| version_cmp(a.1, b.1))
+            .then_with(|| (a.2, a.3).cmp(&(b.2, b.3)))
+    });
+    sorted.into_iter().fold(
+        String::with_capacity(lines.len()),
+        |mut acc, (_, _, _, _, line)| {
+            acc.push_str(line);
+            acc.push('\n');
+            acc
+        },
+    )
+}
+
+/// `~/.nix-defexpr`, where nix-env finds channels.
+fn defexpr_dir() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(".nix-defexpr"))
+}
+
+/// The channels nix-env queries, from `~/.nix-defexpr`, sorted. Both the
+/// user's and root's channels are included.
+pub fn current_channels() -> Vec<String> {
+    let defexpr = match defexpr_dir() {
+        Some(defexpr) => defexpr,
+        None => return Vec::new(),
+    };
+    let mut channels: Vec<String> = ["channels", "channels_root"]
+        .iter()
+        .flat_map(|dir| channels_in(&defexpr.join(dir)))
+        .collect();
+    channels.sort_unstable();
+    channels.dedup();
+    channels
+}
+
+/// The names of the channels in `dir`, a directory of channels like
+/// `~/.nix-defexpr/channels`.
+fn channels_in(dir: &Path) -> Vec<String> {
+    fs::read_dir(dir)
+        .into_iter()
+        .flat_map(|entries| entries.flatten())
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| name != "binary-caches")
+        .collect()
+}
+
+/// A channel from `nix-channel --list`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Channel {
+    /// Like `nixpkgs`.
+    pub name: String,
+    /// Like `https://nixos.org/channels/nixpkgs-unstable`. `None` for root's
+    /// channels, which only root's `nix-channel --list` knows the URLs of.
+    pub url: Option<String>,
+}
+
+/// Parses `nix-channel --list` output, which has a line like `nixpkgs
+/// https://nixos.org/channels/nixpkgs-unstable` for each channel.
+fn parse_channel_list(stdout: &str) -> Vec<Channel> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let (name, url) = line.trim().split_once(char::is_whitespace)?;
+            Some(Channel {
+                name: name.to_string(),
+                url: Some(url.trim().to_string()),
+            })
+        })
+        .collect()
+}
+
+/// The channels nix-env uses: the user's, from `nix-channel --list`, and then
+/// root's, from `~/.nix-defexpr/channels_root`.
+pub fn list_channels() -> Result<Vec<Channel>, CommandError> {
+    let user = parse_channel_list(&proc::run_cmd_stdout(
+        nix_command("nix-channel").arg("--list"),
+    )?);
+    let mut root = defexpr_dir()
+        .map(|defexpr| channels_in(&defexpr.join("channels_root")))
+        .unwrap_or_default();
+    root.sort_unstable();
+    Ok(with_root_channels(user, root))
+}
+
+/// Adds the channels named in `root` to the `user`'s. Like nix-env, a user's
+/// channel hides root's channel of the same name.
+fn with_root_channels(mut user: Vec<Channel>, root: Vec<String>) -> Vec<Channel> {
+    for name in root {
+        if !user.iter().any(|channel| channel.name == name) {
+            user.push(Channel { name, url: None });
+        }
+    }
+    user
+}
+
+/// The version of nixpkgs in the channel `name`, like `20.03pre210712.d8cb4ed910c`,
+/// from its `lib.version`. `None` if the channel doesn't have one (it might
+/// not be nixpkgs) or Nix can't be asked.
+pub fn channel_version(name: &str) -> Option<String> {
+    let expr = format!(
+        "(import ({} + \"/lib\")).version",
+        channel_path_expr(name, defexpr_dir().as_deref())
+    );
+    proc::run_cmd_stdout(nix_command("nix-instantiate").args(["--eval", "--json", "--expr", &expr]))
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+}
+
+/// Describes where packages are queried from, like `channels:nixos,nixpkgs`,
+/// so a cache built from other channels can be told apart. `None` if the
+/// channels can't be found.
+pub fn source_descriptor() -> Option<String> {
+    let channels = current_channels();
+    if channels.is_empty() {
+        None
+    } else {
+        Some(format!("channels:{}", channels.join(",")))
+    }
+}
+
+/// The Nix system string (like `x86_64-linux`) for this machine, as reported
+/// by Nix. If Nix can't be asked, it's guessed from the platform this program
+/// was compiled for.
+pub fn current_system() -> String {
+    proc::run_cmd_stdout(nix_command("nix-instantiate").args([
+        "--eval",
+        "--json",
+        "--expr",
+        "builtins.currentSystem",
+    ]))
+    .ok()
+    .and_then(|json| serde_json::from_str(&json).ok())
+    .unwrap_or_else(|| {
+        let os = match std::env::consts::OS {
+            "macos" => "darwin",
+            os => os,
+        };
+        format!("{}-{}", std::env::consts::ARCH, os)
+    })
+}
+
+/// Parses `nix-env --out-path` output for a single package, like
+/// `gzip-1.10  /nix/store/...-gzip-1.10` or, for a package with several
+/// outputs, `bin=/nix/store/...-bin;out=/nix/store/...`.
+fn parse_out_paths(line: &str) -> Vec<String> {
+    line.split_whitespace()
+        .last()
+        .unwrap_or_default()
+        .split(';')
+        .map(|output| output.split_once('=').map_or(output, |(_, path)| path))
+        .filter(|path| !path.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// The name part of a store path, without the directory or hash; e.g.
+/// `/nix/store/5k3wn...-gzip-1.10` becomes `gzip-1.10`.
+fn store_path_name(path: &str) -> &str {
+    let base = path.rsplit('/').next().unwrap_or(path);
+    base.split_once('-').map_or(base, |(_, name)| name)
+}
+
+/// A store path that refers to a package's outputs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Referrer {
+    pub path: String,
+    /// Attributes in the cache whose name matches the store path.
+    pub attrs: Vec<String>,
+}
+
+/// The store paths that depend on a package.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReverseDeps {
+    pub attr: String,
+    /// The package's outputs that are in the store. Only these can have
+    /// referrers.
+    pub outputs: Vec<String>,
+    pub referrers: Vec<Referrer>,
+}
+
+impl ReverseDeps {
+    pub fn console_fmt(&self) -> ConsoleFormatReverseDeps<'_> {
+        ConsoleFormatReverseDeps(self)
+    }
+}
+
+pub struct ConsoleFormatReverseDeps<'a>(&'a ReverseDeps);
+
+impl Display for ConsoleFormatReverseDeps<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let deps = self.0;
+        if deps.outputs.is_empty() {
+            return writeln!(
+                f,
+                "{} isn't in the Nix store.",
+                Styling::Auto.style(&deps.attr).bold()
+            );
+        }
+        if deps.referrers.is_empty() {
+            return writeln!(
+                f,
+                "Nothing in the Nix store depends on {}.",
+                Styling::Auto.style(&deps.attr).bold()
+            );
+        }
+        for referrer in &deps.referrers {
+            write!(
+                f,
+                "{}",
+                Styling::Auto.style(store_path_name(&referrer.path)).bold()
+            )?;
+            if !referrer.attrs.is_empty() {
+                write!(f, " ({})", referrer.attrs.join(", "))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Matches store paths to the cache's attributes by name. A path for a
+/// non-default output (like `gzip-1.10-man`) matches the package's name too.
+fn match_referrers(cache: &str, paths: Vec<String>) -> Vec<Referrer> {
+    let mut by_name: HashMap<&str, Vec<&str>> = HashMap::new();
+    for line in cache.lines() {
+        let line = AttrLine::parse(line, false);
+        by_name.entry(line.name).or_default().push(line.attr);
+    }
+    paths
+        .into_iter()
+        .map(|path| {
+            let name = store_path_name(&path);
+            let attrs = by_name
+                .get(name)
+                .or_else(|| by_name.get(name.rsplit_once('-')?.0))
+                .map(|attrs| attrs.iter().map(|a| a.to_string()).collect())
+                .unwrap_or_default();
+            Referrer { path, attrs }
+        })
+        .collect()
+}
+
+/// Lists the store paths which depend on the given attribute's outputs,
+/// matched against the attributes in `cache` where possible. Only outputs
+/// already in the store are considered; finding reverse dependencies across
+/// all of nixpkgs would mean evaluating every package.
+pub fn nix_reverse_deps(attr: &str, cache: &str) -> Result<ReverseDeps, NixQueryError> {
+    let out_paths = proc::run_cmd_stdout(nix_command("nix-env").args([
+        "--query",
+        "--available",
+        "--out-path",
+        "--attr",
+        attr,
+    ]))?;
+    let outputs: Vec<String> = out_paths
+        .lines()
+        .next()
+        .map(parse_out_paths)
+        .ok_or(NixQueryError::Empty)?
+        .into_iter()
+        .filter(|path| std::path::Path::new(path).exists())
+        .collect();
+
+    let mut referrers = Vec::new();
+    if !outputs.is_empty() {
+        referrers = proc::run_cmd_stdout_lines(
+            nix_command("nix-store")
+                .args(["--query", "--referrers"])
+                .args(&outputs),
+        )?
+        .into_iter()
+        .filter(|path| !outputs.contains(path) && !path.ends_with(".drv"))
+        .collect();
+        referrers.sort();
+        referrers.dedup();
+    }
+
+    Ok(ReverseDeps {
+        attr: attr.to_string(),
+        outputs,
+        referrers: match_referrers(cache, referrers),
+    })
+}
+
+/// The `out` output's path in `nix-env --out-path` output for a single
+/// package; see `parse_out_paths`. An unnamed path is the `out` output.
+fn parse_out_path(line: &str) -> Option<&str> {
+    line.split_whitespace()
+        .last()?
+        .split(';')
+        .find_map(|output| match output.split_once('=') {
+            Some(("out", path)) => Some(path),
+            Some(_) => None,
+            None => Some(output),
+        })
+        .filter(|path| !path.is_empty())
+}
+
+/// Parses the size in `nix path-info -S` output, like
+/// `/nix/store/...-gzip-1.10    1234567`.
+fn parse_closure_size(output: &str) -> Option<u64> {
+    output.split_whitespace().last()?.parse().ok()
+}
+
+/// Formats a number of bytes for people, like `1.5 MiB`.
+fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next in &UNITS[1..] {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next;
+    }
+    format!("{:.1} {}", size, unit)
+}
+
+/// The closure size, in bytes, of the given attribute's `out` output, or
+/// `None` if it isn't in the Nix store. Gives up if either Nix command takes
+/// longer than `timeout`.
+pub fn nix_closure_size(attr: &str, timeout: Duration) -> Result<Option<u64>, NixQueryError> {
+    let out_paths = proc::run_cmd_stdout_timeout(
+        nix_command("nix-env").args(["--query", "--available", "--out-path", "--attr", attr]),
+        timeout,
+    )?;
+    let path = match out_paths.lines().next().and_then(parse_out_path) {
+        Some(path) if Path::new(path).exists() => path,
+        Some(_) => return Ok(None),
+        None => return Err(NixQueryError::Empty),
+    };
+    let output = proc::run_cmd_stdout_timeout(
+        nix_command("nix").args([
+            "--extra-experimental-features",
+            "nix-command",
+            "path-info",
+            "--closure-size",
+            path,
+        ]),
+        timeout,
+    )?;
+    Ok(parse_closure_size(&output))
+}
+
+/// Formats the result of `nix_closure_size` like a field of `--info`.
+pub struct ConsoleFormatSize(Option<u64>, Styling);
+
+impl ConsoleFormatSize {
+    pub fn new(size: Option<u64>, styling: Styling) -> Self {
+        ConsoleFormatSize(size, styling)
+    }
+}
+
+impl Display for ConsoleFormatSize {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let ConsoleFormatSize(size, styling) = *self;
+        write!(f, "{} ", styling.style("size:").bold())?;
+        match size {
+            Some(size) => writeln!(f, "{}", human_size(size)),
+            None => writeln!(f, "{}", styling.style("not in the Nix store").dim()),
+        }
+    }
+}
+
+fn rich_attr_lines(infos: &[NixInfo], include_private: bool) -> String {
+    infos
+        .iter()
+        .filter(|info| include_private || !is_private(info.attr.as_deref().unwrap_or_default()))
+        .fold(String::new(), |mut acc, info| {
+            acc.push_str(&rich_attr_line(info));
+            acc.push('\n');
+            acc
+        })
+}
+
+impl AllAttrs {
+    fn push_rich_query(
+        &mut self,
+        (stdout, stderr): (String, String),
+        include_private: bool,
+    ) -> Result<(), CommandError> {
+        let parsed = try_parse_all(&stdout)?;
+        self.attrs
+            .push_str(&rich_attr_lines(&parsed.ok, include_private));
+        self.unparsed.extend(
+            parsed
+                .failed
+                .into_iter()
+                .map(|(attr, e)| (attr, e.to_string())),
+        );
+        self.push_query((String::new(), stderr), include_private);
+        Ok(())
+    }
+}
+
+/// How long each stage of populating the attribute list took, in order.
+pub type StageTimings = Vec<(String, Duration)>;
+
+/// Runs `f`, recording how long it took as the stage `name`.
+fn time_stage<T>(timings: &mut StageTimings, name: String, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let ret = f();
+    timings.push((name, start.elapsed()));
+    ret
+}
+
+/// Runs a `nix-env` query with the extra arguments `args`, adding the
+/// packages it finds to `output`. The query and parsing its output are
+/// timed separately, named after `label`.
+fn push_nix_env_query(
+    output: &mut AllAttrs,
+    opts: &PopulateOptions,
+    args: &[&str],
+    label: &str,
+    timings: &mut StageTimings,
+) -> Result<(), CommandError> {
+    let mut cmd = nix_command("nix-env");
+    if opts.rich {
+        cmd.args(["--query", "--available", "--json", "--meta"]);
+    } else {
+        cmd.args(["--query", "--available", "--attr-path", "--description"]);
+    }
+    let result = time_stage(timings, format!("query {}", label), || {
+        proc::run_cmd_stdout_stderr(cmd.args(args))
+    })?;
+    time_stage(timings, format!("parse {}", label), || {
+        if opts.rich {
+            output.push_rich_query(result, opts.include_private)
+        } else {
+            output.push_query(result, opts.include_private);
+            Ok(())
+        }
+    })
+}
+
+/// The `EXTRA_PACKAGE_SETS` in the `default_channel`, like
+/// `nixpkgs.haskellPackages`.
+pub fn extra_attrs() -> Vec<String> {
+    let channel = default_channel();
+    EXTRA_PACKAGE_SETS
+        .iter()
+        .map(|set| format!("{}.{}", channel, set))
+        .collect()
+}
+
+pub fn nix_query_all(opts: &PopulateOptions) -> Result<AllAttrs, CommandError> {
+    nix_query_all_timed(opts).map(|(output, _)| output)
+}
+
+/// Like `nix_query_all`, but also reports how long each stage took.
+pub fn nix_query_all_timed(
+    opts: &PopulateOptions,
+) -> Result<(AllAttrs, StageTimings), CommandError> {
+    let mut output = AllAttrs::default();
+    let mut timings = StageTimings::new();
+    push_nix_env_query(&mut output, opts, &[], "channels", &mut timings)?;
+
+    if opts.skip_extra_attrs {
+        return Ok((output, timings));
+    }
+
+    for base_attr in extra_attrs() {
+        push_nix_env_query(
+            &mut output,
+            opts,
+            &["--attr", &base_attr],
+            &base_attr,
+            &mut timings,
+        )?;
+    }
+
+    time_stage(&mut timings, "dedup".to_string(), || output.dedup());
+    Ok((output, timings))
+}
+
+/// Queries just the packages under `base_attr`, like one of the
+/// `extra_attrs`.
+pub fn nix_query_attr_set(
+    base_attr: &str,
+    opts: &PopulateOptions,
+) -> Result<AllAttrs, CommandError> {
+    let mut output = AllAttrs::default();
+    push_nix_env_query(
+        &mut output,
+        opts,
+        &["--attr", base_attr],
+        base_attr,
+        &mut StageTimings::new(),
+    )?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    /// Parses the first package in `nix-env --json` output, like `nix_query`.
+    fn nix_query_json(json: &str) -> NixInfo {
+        try_parse_all(json).unwrap().ok.remove(0)
+    }
+
+    #[test]
+    fn test_deserialize_tern() {
+        let tern = include_str!("../test_data/tern.json");
+        assert_eq!(
+            &NixInfo {
+                name: "node_tern-0.24.2".to_string(),
+                pname: "node_tern".to_string(),
+                version: "0.24.2".to_string(),
+                system: Some("x86_64-linux".to_string()),
+                meta: NixMeta {
+                    available: true,
+                    description: Some("A JavaScript code analyzer for deep, cross-editor language support".to_string()),
+                    homepage: Some("https://github.com/ternjs/tern#readme".to_string()),
+                    download_page: None,
+                    license: Some(Some(License::Id("MIT".to_string()))),
+                    name: Some("node_tern-0.24.2".to_string()),
+                    outputs_to_install: vec!["out".to_string()],
+                    position: Some(NixPath {
+                        path: "/nix/store/lybqxz1h84knafw4l9mh248lfiqrw35a-nixpkgs-20.03pre210712.d8cb4ed910c/nixpkgs/pkgs/development/node-packages/node-packages-v10.nix".to_string(),
+                        line: 72689,
+                    }),
+                    broken: false,
+                    long_description: None,
+                    maintainers: vec![],
+                    teams: vec![],
+                    platforms: vec![],
+                    priority: None,
+                    tests: false,
+                },
+                attr: None,
+            },
+            serde_json::from_str::<AllNixInfo>(tern)
+                .unwrap()
+                .attrs
+                .get("nixpkgs.nodePackages.tern").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_deserialize_ok() {
+        let check = |s: &str, label: &str| {
+            serde_json::from_str::<AllNixInfo>(s)
+                .unwrap_or_else(|e| panic!("Can deserialize test data for {}: {}", label, e))
+                .attrs
+                .values()
+                .next()
+                .unwrap_or_else(|| {
+                    panic!(
+                        "String -> NixInfo map for {} has at least one value.",
+                        label
+                    )
+                })
+                .clone()
+        };
+
+        let _ = check(include_str!("../test_data/gcc.json"), "test_data/gcc.json");
+        let _ = check(include_str!("../test_data/gzip.json"), "test_data/gcc.json");
+        let _ = check(
+            include_str!("../test_data/spotify.json"),
+            "test_data/spotify.json",
+        );
+        let _ = check(
+            include_str!("../test_data/tern.json"),
+            "test_data/tern.json",
+        );
+        let _ = check(
+            include_str!("../test_data/acpitool.json"),
+            "test_data/acpitool.json",
+        );
+    }
+
+    #[test]
+    fn test_rewrite_attr_lines() {
+        assert_eq!(
+            format!(
+                "{}\n{}\n",
+                "nixpkgs.all-cabal-hashes    10e6ea0c54a4aa41de51d1d7e2314115bb2e172a.tar.gz",
+                "unstable.all-cabal-hashes    10e6ea0c54a4aa41de51d1d7e2314115bb2e172a.tar.gz",
+            ),
+            rewrite_attr_lines(
+                include_str!("../test_data/attrs_unfiltered.txt").to_string(),
+                false
+            )
+        );
+    }
+
+    #[test]
+    fn test_rewrite_attr_lines_include_private() {
+        let stdout = "nixpkgs._sources    sources-1.0    Sources\n\
+                      nixpkgs.gzip    gzip-1.10    GNU zip\n"
+            .to_string();
+        assert_eq!(
+            "nixpkgs.gzip    gzip-1.10    GNU zip\n",
+            rewrite_attr_lines(stdout.clone(), false)
+        );
+        assert_eq!(
+            "nixpkgs._sources    sources-1.0    Sources\n\
+             nixpkgs.gzip    gzip-1.10    GNU zip\n",
+            rewrite_attr_lines(stdout, true)
+        );
+    }
+
+    #[test]
+    fn test_rewrite_attr_lines_progress() {
+        assert_eq!(
+            "nixpkgs.gzip    gzip-1.10    GNU zip\n\
+             nixpkgs.hello    hello-2.10\n",
+            rewrite_attr_lines(
+                "(1/3) querying available packages...\n\
+                 nixpkgs.gzip    gzip-1.10    GNU zip\n\
+                 [2/3 built] copying path\n\
+                 \n\
+                 querying\n\
+                 nixpkgs.hello    hello-2.10    \n\
+                 (3/3) done\n"
+                    .to_string(),
+                false
+            )
+        );
+        assert!(is_attr_line(
+            "nixpkgs.haskellPackages.\"3d-graphics-examples\"    3d-graphics-examples-0.0.0.2"
+        ));
+        assert!(!is_attr_line("   nixpkgs.gzip    gzip-1.10"));
+    }
+
+    #[test]
+    fn test_parse_info_field() {
+        assert_eq!(Ok(InfoField::LongDescription), "long-description".parse());
+        for field in InfoField::ALL {
+            assert_eq!(Ok(*field), field.name().parse());
+        }
+
+        let err = "homepages".parse::<InfoField>().unwrap_err().to_string();
+        assert!(err.contains("'homepages'"));
+        assert!(err.contains("attr, name, system, broken"));
+    }
+
+    #[test]
+    fn test_resolve_nix_program() {
+        let resolve = |explicit: Option<&str>, nix_bin: Option<&str>| {
+            resolve_nix_program(
+                "nix-env",
+                explicit.map(PathBuf::from),
+                nix_bin.map(OsString::from),
+            )
+        };
+        assert_eq!(PathBuf::from("nix-env"), resolve(None, None));
+        assert_eq!(PathBuf::from("nix-env"), resolve(None, Some("")));
+        assert_eq!(
+            PathBuf::from("/opt/nix/bin/nix-env"),
+            resolve(None, Some("/opt/nix/bin"))
+        );
+        assert_eq!(
+            PathBuf::from("/usr/local/bin/nix-env"),
+            resolve(Some("/usr/local/bin"), Some("/opt/nix/bin"))
+        );
+    }
+
+    #[test]
+    fn test_parse_channel_list() {
+        assert_eq!(
+            vec![
+                Channel {
+                    name: "nixpkgs".to_string(),
+                    url: Some("https://nixos.org/channels/nixpkgs-unstable".to_string()),
+                },
+                Channel {
+                    name: "home-manager".to_string(),
+                    url: Some(
+                        "https://github.com/nix-community/home-manager/archive/master.tar.gz"
+                            .to_string()
+                    ),
+                },
+            ],
+            parse_channel_list(
+                "nixpkgs https://nixos.org/channels/nixpkgs-unstable\n\
+                 \n\
+                 home-manager https://github.com/nix-community/home-manager/archive/master.tar.gz\n"
+            )
+        );
+        assert!(parse_channel_list("").is_empty());
+    }
+
+    #[test]
+    fn test_with_root_channels() {
+        let user = parse_channel_list("nixpkgs https://nixos.org/channels/nixpkgs-unstable\n");
+        assert_eq!(
+            vec![
+                user[0].clone(),
+                Channel {
+                    name: "nixos".to_string(),
+                    url: None,
+                },
+            ],
+            with_root_channels(user, vec!["nixos".to_string(), "nixpkgs".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_parse_collisions() {
+        assert_eq!(
+            vec![
+                "/home/user/.nix-defexpr/channels_root/nixos".to_string(),
+                "/home/user/.nix-defexpr/channels/nixpkgs".to_string(),
+            ],
+            parse_collisions(concat!(
+                "warning: name collision in input Nix expressions, skipping '/home/user/.nix-defexpr/channels_root/nixos'\n",
+                "warning: something else entirely\n",
+                "warning: name collision in input Nix expressions, skipping '/home/user/.nix-defexpr/channels/nixpkgs'\n",
+            ))
+        );
+        assert!(parse_collisions("").is_empty());
+    }
+
+    #[test]
+    fn test_all_attrs_is_empty() {
+        let mut all = AllAttrs::default();
+        assert!(all.is_empty());
+        all.push_query((String::new(), String::new()), false);
+        assert!(all.is_empty());
+        all.push_query(("\n\n".to_string(), String::new()), false);
+        assert!(all.is_empty());
+        all.push_query(
+            (
+                "nixpkgs.gzip    gzip-1.10    GNU zip compression program\n".to_string(),
+                String::new(),
+            ),
+            false,
+        );
+        assert!(!all.is_empty());
+    }
+
+    #[test]
+    fn test_all_attrs_dedup() {
+        let mut all = AllAttrs::default();
+        all.push_query(
+            (
+                "nixpkgs.a    a-1    A\n\
+             nixpkgs.nodePackages.b    b-1    B\n\
+             nixpkgs.c    c-1    C\n"
+                    .to_string(),
+                String::new(),
+            ),
+            false,
+        );
+        all.push_query(
+            (
+                "nixpkgs.nodePackages.b    b-1    B again\n\
+             nixpkgs.nodePackages.d    d-1    D\n"
+                    .to_string(),
+                String::new(),
+            ),
+            false,
+        );
+        all.dedup();
+        assert_eq!(
+            "nixpkgs.a    a-1    A\n\
+             nixpkgs.nodePackages.b    b-1    B\n\
+             nixpkgs.c    c-1    C\n\
+             nixpkgs.nodePackages.d    d-1    D\n",
+            all.attrs
+        );
+        assert_eq!(1, all.duplicates);
+    }
+
+    #[test]
+    fn test_rich_attr_lines() {
+        assert_eq!(
+            format!(
+                "{}\n",
+                [
+                    "nixpkgs.nodePackages.tern",
+                    "node_tern-0.24.2",
+                    "A JavaScript code analyzer for deep, cross-editor language support",
+                    "false",
+                    "true",
+                    "",
+                    "false",
+                    "true",
+                ]
+                .join(FIELD_DELIMITER)
+            ),
+            rich_attr_lines(
+                &try_parse_all(include_str!("../test_data/tern.json"))
+                    .unwrap()
+                    .ok,
+                false
+            )
+        );
+
+        let spotify = rich_attr_lines(
+            &try_parse_all(include_str!("../test_data/spotify.json"))
+                .unwrap()
+                .ok,
+            false,
+        );
+        let columns: Vec<_> = spotify.trim_end().split(FIELD_DELIMITER).collect();
+        assert_eq!("false", columns[4]);
+        assert_eq!("x86_64-linux", columns[5]);
+    }
+
+    #[test]
+    fn test_push_rich_query() {
+        let mut all = AllAttrs::default();
+        all.push_rich_query(
+            (
+                r#"{
+                "nixpkgs.good": {
+                    "name": "good-1.0",
+                    "pname": "good",
+                    "version": "1.0",
+                    "meta": {}
+                },
+                "nixpkgs.bad": {"name": 1}
+            }"#
+                .to_string(),
+                String::new(),
+            ),
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            vec!["nixpkgs.good"],
+            all.attrs
+                .lines()
+                .map(|line| AttrLine::parse(line, true).attr)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["nixpkgs.bad"],
+            all.unparsed
+                .iter()
+                .map(|(attr, _)| attr.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_try_parse_all() {
+        let parsed = try_parse_all(
+            r#"{
+                "nixpkgs.good": {
+                    "name": "good-1.0",
+                    "pname": "good",
+                    "version": "1.0",
+                    "system": "x86_64-linux",
+                    "meta": {}
+                },
+                "nixpkgs.bad": {"name": 1}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            vec![Some("nixpkgs.good")],
+            parsed
+                .ok
+                .iter()
+                .map(|info| info.attr.as_deref())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["nixpkgs.bad"],
+            parsed
+                .failed
+                .iter()
+                .map(|(attr, _)| attr.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_deserialize_no_meta() {
+        assert_eq!(
+            NixInfo {
+                name: "hello-2.10".to_string(),
+                pname: "hello".to_string(),
+                version: "2.10".to_string(),
+                system: Some("x86_64-linux".to_string()),
+                meta: NixMeta::default(),
+                attr: None,
+            },
+            serde_json::from_str::<NixInfo>(
+                r#"{
+                    "name": "hello-2.10",
+                    "pname": "hello",
+                    "version": "2.10",
+                    "system": "x86_64-linux"
+                }"#
+            )
+            .unwrap()
+        );
+        assert!(NixMeta::default().available);
+    }
+
+    #[test]
+    fn test_deserialize_single_maintainer() {
+        let meta: NixMeta = serde_json::from_str(
+            r#"{"maintainers": {"email": "a@example.com", "github": "a", "name": "A"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            vec![Maintainer::Info(MaintainerInfo {
+                name: Some("A".to_string()),
+                email: "a@example.com".to_string(),
+                github: Some("a".to_string()),
+                github_id: None,
+                keys: Vec::new(),
+            })],
+            meta.maintainers
+        );
+
+        let meta: NixMeta = serde_json::from_str(r#"{"maintainers": ["a", "b"]}"#).unwrap();
+        assert_eq!(
+            vec![
+                Maintainer::Name("a".to_string()),
+                Maintainer::Name("b".to_string())
+            ],
+            meta.maintainers
+        );
+    }
+
+    #[test]
+    fn test_deserialize_teams() {
+        let meta: NixMeta = serde_json::from_str(
+            r#"{"teams": [
+                {
+                    "shortName": "GNOME",
+                    "scope": "Maintain GNOME desktop environment and platform.",
+                    "members": [
+                        {"email": "a@example.com", "github": "a", "name": "A"},
+                        "b"
+                    ],
+                    "githubTeams": ["gnome"]
+                },
+                {}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                TeamInfo {
+                    short_name: Some("GNOME".to_string()),
+                    members: vec![
+                        Maintainer::Info(MaintainerInfo {
+                            name: Some("A".to_string()),
+                            email: "a@example.com".to_string(),
+                            github: Some("a".to_string()),
+                            github_id: None,
+                            keys: Vec::new(),
+                        }),
+                        Maintainer::Name("b".to_string()),
+                    ],
+                },
+                TeamInfo::default(),
+            ],
+            meta.teams
+        );
+        assert!(NixMeta::default().teams.is_empty());
+
+        assert_eq!(
+            "teams: GNOME (2 members), unnamed team\n",
+            format_meta_plain(r#"{"teams": [{"shortName": "GNOME", "members": ["a", "b"]}, {}]}"#)
+        );
+        assert_eq!("", format_meta_plain(r#"{"teams": []}"#));
+    }
+
+    #[test]
+    fn test_deserialize_priority() {
+        let priority = |json| serde_json::from_str::<NixMeta>(json).unwrap().priority;
+        assert_eq!(Some(10), priority(r#"{"priority": 10}"#));
+        assert_eq!(Some(10), priority(r#"{"priority": "10"}"#));
+        assert_eq!(Some(-1), priority(r#"{"priority": "-1"}"#));
+        assert_eq!(None, priority(r#"{"priority": null}"#));
+        assert_eq!(None, priority("{}"));
+        assert!(serde_json::from_str::<NixMeta>(r#"{"priority": "high"}"#).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_tests() {
+        let meta: NixMeta = serde_json::from_str(r#"{"tests": {"simple": {}}}"#).unwrap();
+        assert!(meta.tests);
+        assert!(!NixMeta::default().tests);
+    }
+
+    #[test]
+    fn test_split_name_version() {
+        assert_eq!(("gzip", "1.10"), split_name_version("gzip-1.10"));
+        assert_eq!(
+            ("node_tern", "0.24.2"),
+            split_name_version("node_tern-0.24.2")
+        );
+        assert_eq!(
+            ("python3.8-requests", "2.22.0"),
+            split_name_version("python3.8-requests-2.22.0")
+        );
+        assert_eq!(("hello", ""), split_name_version("hello"));
+    }
+
+    #[test]
+    fn test_version_cmp() {
+        use Ordering::*;
+        for (a, b, ordering) in &[
+            ("1.9", "1.10", Less),
+            ("1.10", "1.9", Greater),
+            ("1.10", "1.10", Equal),
+            ("1.0", "1.0.1", Less),
+            ("2.3a", "2.3.1", Less),
+            ("2.3a", "2.3b", Less),
+            ("1.0pre1", "1.0", Less),
+            ("1.0-pre", "1.0-rc1", Less),
+            ("1.0-rc1", "1.0", Greater),
+            ("0.0.0.2", "0.0.0.10", Less),
+            ("unstable-2019-12-31", "unstable-2020-01-01", Less),
+            ("20200101", "99999999999999999999", Less),
+            ("1.01", "1.1", Less),
+            ("", "1", Less),
+        ] {
+            assert_eq!(*ordering, version_cmp(a, b), "{} vs. {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_format_attr_line() {
+        let line = [
+            "nixos.spotify",
+            "spotify-1.0.96",
+            "Play music",
+            "false",
+            "false",
+            "x86_64-linux",
+        ]
+        .join(FIELD_DELIMITER);
+        assert_eq!(
+            "attr: nixos.spotify\n\
+             name: spotify-1.0.96\n\
+             version: 1.0.96\n\
+             channel: nixos\n\
+             license: unfree\n\
+             platforms: x86_64-linux\n\
+             description: Play music\n",
+            AttrLine::parse(&line, true)
+                .console_fmt_with(Styling::Plain)
+                .to_string()
+        );
+        assert_eq!(
+            "attr: nixpkgs.gzip\n\
+             name: gzip-1.10\n\
+             version: 1.10\n\
+             channel: nixpkgs\n",
+            AttrLine::parse("nixpkgs.gzip    gzip-1.10", false)
+                .console_fmt_with(Styling::Plain)
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_attr_line() {
+        let rich = [
+            "nixos.spotify",
+            "spotify-1.0",
+            "Play music",
+            "false",
+            "false",
+            "x86_64-linux,aarch64-linux",
+        ]
+        .join(FIELD_DELIMITER);
+        let rich = AttrLine::parse(&rich, true);
+        assert_eq!("Play music", rich.description);
+        assert_eq!(Some(false), rich.broken);
+        assert_eq!(Some(false), rich.free);
+        assert!(rich.supports("aarch64-linux"));
+        assert!(!rich.supports("x86_64-darwin"));
+
+        let no_platforms = ["nixpkgs.a", "a-1", "", "true", "true", ""].join(FIELD_DELIMITER);
+        let no_platforms = AttrLine::parse(&no_platforms, true);
+        assert_eq!(Some(true), no_platforms.broken);
+        assert!(no_platforms.supports("x86_64-darwin"));
+        assert_eq!(None, no_platforms.tests);
+
+        let tests = ["nixpkgs.a", "a-1", "", "false", "true", "", "true"].join(FIELD_DELIMITER);
+        assert_eq!(Some(true), AttrLine::parse(&tests, true).tests);
+        assert_eq!(None, AttrLine::parse(&tests, true).available);
+
+        // Listed for Linux, but unavailable there (say, because it's broken
+        // on that system), or available though it doesn't list the system.
+        let line = |available: &str| {
+            [
+                "nixpkgs.a",
+                "a-1",
+                "",
+                "false",
+                "true",
+                "x86_64-linux",
+                "false",
+                available,
+            ]
+            .join(FIELD_DELIMITER)
+        };
+        let unavailable = line("false");
+        let unavailable = AttrLine::parse(&unavailable, true);
+        assert_eq!(Some(false), unavailable.available);
+        assert!(!unavailable.supports("x86_64-linux"));
+        let available = line("true");
+        assert!(AttrLine::parse(&available, true).supports("x86_64-darwin"));
+
+        let plain = AttrLine::parse("nixpkgs.gzip    gzip-1.10    GNU    zip", false);
+        assert_eq!("GNU    zip", plain.description);
+        assert_eq!(None, plain.broken);
+        assert!(plain.supports("x86_64-darwin"));
+    }
+
+    #[test]
+    fn test_line_filter() {
+        let lines = [
+            ["nixpkgs.ok", "ok-1", "", "false", "true", ""].join(FIELD_DELIMITER),
+            ["nixpkgs.broken", "broken-1", "", "true", "true", ""].join(FIELD_DELIMITER),
+            ["nixpkgs.unfree", "unfree-1", "", "false", "false", ""].join(FIELD_DELIMITER),
+        ]
+        .join("\n");
+        let attrs = |filter: LineFilter| {
+            filter
+                .apply(&lines)
+                .lines()
+                .map(|line| AttrLine::parse(line, true).attr.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec!["nixpkgs.ok", "nixpkgs.broken", "nixpkgs.unfree"],
+            attrs(LineFilter::default())
+        );
+        assert_eq!(
+            vec!["nixpkgs.ok", "nixpkgs.unfree"],
+            attrs(LineFilter {
+                hide_broken: true,
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            vec!["nixpkgs.ok"],
+            attrs(LineFilter {
+                hide_broken: true,
+                hide_unfree: true,
+            })
         );
     }
 
-    Ok(output)
-}
+    #[test]
+    fn test_format_info_plain() {
+        let info = nix_query_json(include_str!("../test_data/tern.json"));
+        assert_eq!(
+            "attr: nixpkgs.nodePackages.tern\n\
+             name: node_tern-0.24.2\n\
+             system: x86_64-linux\n\
+             homepage: https://github.com/ternjs/tern#readme\n\
+             description: A JavaScript code analyzer for deep, cross-editor language support\n\
+             license: MIT\n\
+             defined in: /nix/store/lybqxz1h84knafw4l9mh248lfiqrw35a-nixpkgs-20.03pre210712.d8cb4ed910c/nixpkgs/pkgs/development/node-packages/node-packages-v10.nix line 72689\n",
+            format_info_plain(&info)
+        );
+    }
 
-#[cfg(test)]
-mod test {
-    use pretty_assertions::assert_eq;
+    #[test]
+    fn test_info_fields_json_tsv() {
+        let info = nix_query_json(include_str!("../test_data/tern.json"));
+        let fields = [
+            InfoField::Attr,
+            InfoField::Broken,
+            InfoField::Priority,
+            InfoField::License,
+            InfoField::Maintainers,
+            InfoField::Position,
+        ];
+        assert_eq!(
+            serde_json::json!({
+                "attr": "nixpkgs.nodePackages.tern",
+                "broken": false,
+                "priority": null,
+                "license": "MIT",
+                "maintainers": [],
+                "position": "/nix/store/lybqxz1h84knafw4l9mh248lfiqrw35a-nixpkgs-20.03pre210712.d8cb4ed910c/nixpkgs/pkgs/development/node-packages/node-packages-v10.nix:72689",
+            }),
+            serde_json::Value::Object(info.json_fields(&fields))
+        );
+        assert_eq!(
+            "nixpkgs.nodePackages.tern\tfalse\t\tMIT\t\t/nix/store/lybqxz1h84knafw4l9mh248lfiqrw35a-nixpkgs-20.03pre210712.d8cb4ed910c/nixpkgs/pkgs/development/node-packages/node-packages-v10.nix:72689",
+            info.tsv_fields(&fields)
+        );
+        assert_eq!(
+            "A JavaScript code analyzer for deep, cross-editor language support",
+            InfoField::Description.tsv_value(&info)
+        );
+    }
 
-    use super::*;
+    #[test]
+    fn test_color_test_infos() {
+        let infos = color_test_infos();
+        assert_eq!(8, infos.len());
+        let everything = format_info_plain(&infos[0]);
+        for label in &[
+            "attr:",
+            "name:",
+            "system:",
+            "priority:",
+            "homepage:",
+            "download page:",
+            "description:",
+            "long desc.:",
+            "license:",
+            "maintainers:",
+            "teams:",
+            "tests:",
+            "defined in:",
+        ] {
+            assert!(everything.contains(label), "{} in:\n{}", label, everything);
+        }
+        assert!(format_info_plain(&infos[1]).contains("broken: true"));
+        assert!(format_info_plain(&infos[7]).contains("license: unknown"));
+    }
 
     #[test]
-    fn test_deserialize_tern() {
-        let tern = include_str!("../test_data/tern.json");
+    fn test_format_info_snapshots() {
+        let check = |json: &str, expected: &str| {
+            assert_eq!(expected, format_info_plain(&nix_query_json(json)));
+        };
+
+        check(
+            include_str!("../test_data/gcc.json"),
+            include_str!("../test_data/gcc.info.txt"),
+        );
+        check(
+            include_str!("../test_data/gzip.json"),
+            include_str!("../test_data/gzip.info.txt"),
+        );
+        check(
+            include_str!("../test_data/spotify.json"),
+            include_str!("../test_data/spotify.info.txt"),
+        );
+        check(
+            include_str!("../test_data/acpitool.json"),
+            include_str!("../test_data/acpitool.info.txt"),
+        );
+    }
+
+    /// Renders a package with the given `meta`, skipping the fields every
+    /// package has.
+    fn format_meta_plain(meta: &str) -> String {
+        let info = nix_query_json(&format!(
+            r#"{{
+                "nixpkgs.pkg": {{
+                    "name": "pkg-1.0",
+                    "pname": "pkg",
+                    "version": "1.0",
+                    "meta": {}
+                }}
+            }}"#,
+            meta
+        ));
+        info.console_fmt_fields(&InfoField::ALL[3..])
+            .with_styling(Styling::Plain)
+            .to_string()
+    }
+
+    #[test]
+    fn test_format_download_page() {
         assert_eq!(
-            &NixInfo {
-                name: "node_tern-0.24.2".to_string(),
-                pname: "node_tern".to_string(),
-                version: "0.24.2".to_string(),
-                system: "x86_64-linux".to_string(),
-                meta: NixMeta {
-                    available: true,
-                    description: Some("A JavaScript code analyzer for deep, cross-editor language support".to_string()),
-                    homepage: Some("https://github.com/ternjs/tern#readme".to_string()),
-                    license: Some(License::Id("MIT".to_string())),
-                    name: Some("node_tern-0.24.2".to_string()),
-                    outputs_to_install: vec!["out".to_string()],
-                    position: Some(NixPath {
-                        path: "/nix/store/lybqxz1h84knafw4l9mh248lfiqrw35a-nixpkgs-20.03pre210712.d8cb4ed910c/nixpkgs/pkgs/development/node-packages/node-packages-v10.nix".to_string(),
-                        line: 72689,
-                    }),
-                    broken: false,
-                    long_description: None,
-                    maintainers: vec![],
-                    platforms: vec![],
-                    priority: None,
-                },
-                attr: None,
-            },
-            serde_json::from_str::<AllNixInfo>(tern)
-                .unwrap()
-                .attrs
-                .get("nixpkgs.nodePackages.tern").unwrap()
+            "download page: https://www.gnu.org/software/gzip/\n",
+            format_meta_plain(
+                r#"{
+                    "downloadPage": "https://www.gnu.org/software/gzip/",
+                    "repology": "https://repology.org/project/gzip"
+                }"#
+            )
         );
     }
 
     #[test]
-    fn test_deserialize_ok() {
-        let check = |s: &str, label: &str| {
-            serde_json::from_str::<AllNixInfo>(s)
-                .expect(&format!("Can deserialize test data for {}.", label))
-                .attrs
-                .values()
-                .next()
-                .expect(&format!(
-                    "String -> NixInfo map for {} has at least one value.",
-                    label
-                ))
-                .clone()
+    fn test_format_info_missing_fields() {
+        assert_eq!("", format_meta_plain("{}"));
+        assert_eq!(
+            "attr: nixpkgs.pkg\nname: pkg-1.0\nsystem: x86_64-linux\n",
+            format_info_plain(&nix_query_json(
+                r#"{"nixpkgs.pkg": {
+                    "name": "pkg-1.0",
+                    "pname": "pkg",
+                    "version": "1.0",
+                    "system": "x86_64-linux"
+                }}"#
+            ))
+        );
+        // No `system`, as in some cross-system queries.
+        assert_eq!(
+            "attr: nixpkgs.pkg\nname: pkg-1.0\n",
+            format_info_plain(&nix_query_json(
+                r#"{"nixpkgs.pkg": {
+                    "name": "pkg-1.0",
+                    "pname": "pkg",
+                    "version": "1.0"
+                }}"#
+            ))
+        );
+        assert_eq!(
+            "broken: true\navailable: false\n",
+            format_meta_plain(r#"{"broken": true, "available": false}"#)
+        );
+    }
+
+    #[test]
+    fn test_format_info_licenses() {
+        // Free, without an SPDX ID or URL.
+        assert_eq!(
+            "license: bsd3 (BSD 3-clause)\n",
+            format_meta_plain(r#"{"license": {"fullName": "BSD 3-clause", "shortName": "bsd3"}}"#)
+        );
+        // Unfree, with a more specific name.
+        assert_eq!(
+            "license: Unfree redistributable (unfree; unfreeRedistributable) https://example.com/\n",
+            format_meta_plain(
+                r#"{"license": {
+                    "fullName": "Unfree redistributable",
+                    "shortName": "unfreeRedistributable",
+                    "free": false,
+                    "url": "https://example.com/"
+                }}"#
+            )
+        );
+        // Several licenses are aligned under each other.
+        assert_eq!(
+            "license: MIT https://spdx.org/licenses/MIT.html\n         unfree\n",
+            format_meta_plain(
+                r#"{"license": [
+                    {
+                        "fullName": "MIT License",
+                        "shortName": "mit",
+                        "spdxId": "MIT",
+                        "url": "https://spdx.org/licenses/MIT.html"
+                    },
+                    {"fullName": "Unfree", "shortName": "unfree", "free": false}
+                ]}"#
+            )
+        );
+        assert_eq!(
+            "license: Public Domain\n",
+            format_meta_plain(r#"{"license": {"fullName": "Public Domain"}}"#)
+        );
+        assert_eq!(
+            "license: https://example.com/LICENSE\n",
+            format_meta_plain(r#"{"license": {"url": "https://example.com/LICENSE"}}"#)
+        );
+        // Some older nixpkgs use `false` for unfree packages.
+        assert_eq!(
+            "license: unfree (no license specified)\n",
+            format_meta_plain(r#"{"license": false}"#)
+        );
+        // A `null` license is unknown, unlike a missing one.
+        assert_eq!(
+            "license: unknown\n",
+            format_meta_plain(r#"{"license": null}"#)
+        );
+        assert_eq!("", format_meta_plain("{}"));
+    }
+
+    #[test]
+    fn test_wrap_text() {
+        assert_eq!(
+            vec!["aaaa bbbb cccc dddd", "e"],
+            wrap_text("aaaa bbbb cccc dddd e", Some(32), 12)
+        );
+        assert_eq!(
+            vec![
+                "  • aaaa bbbb cccc",
+                "  dddd eeee",
+                "ffffffffffffffffffffffff"
+            ],
+            wrap_text(
+                "  • aaaa bbbb cccc dddd eeee\nffffffffffffffffffffffff",
+                Some(40),
+                20
+            )
+        );
+        assert_eq!(
+            vec!["one line", "another"],
+            wrap_text("one line\nanother", None, 12)
+        );
+        // Too narrow to bother.
+        assert_eq!(vec!["a b c"], wrap_text("a b c", Some(20), 12));
+    }
+
+    #[test]
+    fn test_format_info_narrow_width() {
+        let info = &color_test_infos()[0];
+        let formatted = |width| {
+            info.console_fmt()
+                .with_styling(Styling::Plain)
+                .with_markdown(true)
+                .with_width(width)
+                .to_string()
         };
+        assert_eq!(formatted(None), formatted(Some(0)));
+        assert_eq!(formatted(Some(MIN_WIDTH)), formatted(Some(1)));
+        assert_ne!(formatted(None), formatted(Some(MIN_WIDTH)));
+    }
 
-        let _ = check(include_str!("../test_data/gcc.json"), "test_data/gcc.json");
-        let _ = check(include_str!("../test_data/gzip.json"), "test_data/gcc.json");
-        let _ = check(
-            include_str!("../test_data/spotify.json"),
-            "test_data/spotify.json",
+    #[test]
+    fn test_render_markdown() {
+        assert_eq!(
+            "Fast tools:\n• Use fast mode\n  • Nested item\nA * B",
+            render_markdown(
+                "Fast tools:\n- Use **fast** `mode`\n  * __Nested__ item\nA * B",
+                Styling::Plain,
+                Charset::Unicode
+            )
         );
-        let _ = check(
-            include_str!("../test_data/tern.json"),
-            "test_data/tern.json",
+        assert_eq!(
+            "Tools:\n* One\n  * Two",
+            render_markdown("Tools:\n- One\n  + Two", Styling::Plain, Charset::Ascii)
         );
-        let _ = check(
-            include_str!("../test_data/acpitool.json"),
-            "test_data/acpitool.json",
+    }
+
+    #[test]
+    fn test_deserialize_license_false() {
+        let info = nix_query_json(
+            r#"{"nixpkgs.pkg": {
+                "name": "pkg-1.0",
+                "pname": "pkg",
+                "version": "1.0",
+                "meta": {"license": false}
+            }}"#,
         );
+        assert_eq!(Some(Some(License::Bool(false))), info.meta.license);
+        assert!(!License::Bool(false).is_free());
     }
 
     #[test]
-    fn test_rewrite_attr_lines() {
+    fn test_license_unfree_plain() {
+        let unfree: FullLicense =
+            serde_json::from_str(r#"{"fullName": "Unfree", "shortName": "unfree", "free": false}"#)
+                .unwrap();
         assert_eq!(
-            format!(
-                "{}\n{}\n",
-                "nixpkgs.all-cabal-hashes    10e6ea0c54a4aa41de51d1d7e2314115bb2e172a.tar.gz",
-                "unstable.all-cabal-hashes    10e6ea0c54a4aa41de51d1d7e2314115bb2e172a.tar.gz",
-            ),
-            rewrite_attr_lines(include_str!("../test_data/attrs_unfiltered.txt").to_string())
+            "unfree",
+            unfree.console_fmt_with(Styling::Plain).to_string()
+        );
+        assert_eq!(
+            "license: unfree\n",
+            format_meta_plain(
+                r#"{"license": {"fullName": "Unfree", "shortName": "unfree", "free": false}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_format_info_maintainers() {
+        assert_eq!(
+            "maintainers: someone, Jane Doe, jdoe@example.com @jdoe\n",
+            format_meta_plain(
+                r#"{"maintainers": [
+                    "someone",
+                    {"name": "Jane Doe", "email": "jane@example.com"},
+                    {"email": "jdoe@example.com", "github": "jdoe"}
+                ]}"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_format_maintainer_mailto() {
+        let maintainer = |json| serde_json::from_str::<Maintainer>(json).unwrap();
+        let email_only = maintainer(r#"{"email": "jdoe@example.com"}"#);
+        let name_and_email = maintainer(r#"{"name": "Jane Doe", "email": "jane@example.com"}"#);
+
+        assert_eq!(
+            "\x1b]8;;mailto:jdoe@example.com\x1b\\jdoe@example.com\x1b]8;;\x1b\\",
+            email_only.console_fmt_with(Styling::Always).to_string()
+        );
+        assert_eq!(
+            "\x1b]8;;mailto:jane@example.com\x1b\\Jane Doe\x1b]8;;\x1b\\",
+            name_and_email.console_fmt_with(Styling::Always).to_string()
+        );
+
+        assert_eq!(
+            "jdoe@example.com",
+            email_only.console_fmt_with(Styling::Plain).to_string()
+        );
+        assert_eq!(
+            "Jane Doe",
+            name_and_email.console_fmt_with(Styling::Plain).to_string()
+        );
+    }
+
+    #[test]
+    fn test_resolve_attr() {
+        let attrs = "nixpkgs.gzip    gzip-1.10\n\
+                     unstable.gzip    gzip-1.11\n\
+                     nixpkgs.firefox    firefox-72.0\n\
+                     nixpkgs.python3Packages.requests    python3.7-requests-2.22.0\n";
+        assert_eq!(
+            Resolved::Attr("unstable.gzip".to_string()),
+            resolve_attr(attrs, "unstable.gzip")
+        );
+        assert_eq!(
+            Resolved::Attr("nixpkgs.firefox".to_string()),
+            resolve_attr(attrs, "firefox")
+        );
+        assert_eq!(
+            Resolved::Attr("nixpkgs.python3Packages.requests".to_string()),
+            resolve_attr(attrs, "python3Packages.requests")
+        );
+        assert_eq!(
+            Resolved::Ambiguous(vec![
+                "nixpkgs.gzip".to_string(),
+                "unstable.gzip".to_string()
+            ]),
+            resolve_attr(attrs, "gzip")
+        );
+        assert_eq!(Resolved::NotFound, resolve_attr(attrs, "requests"));
+    }
+
+    #[test]
+    fn test_closure_size() {
+        assert_eq!(
+            Some("/nix/store/aaa-gzip-1.10"),
+            parse_out_path("gzip-1.10  /nix/store/aaa-gzip-1.10")
+        );
+        assert_eq!(
+            Some("/nix/store/ccc-curl-7.0"),
+            parse_out_path("curl-7.0  bin=/nix/store/bbb-curl-7.0-bin;out=/nix/store/ccc-curl-7.0")
+        );
+        assert_eq!(
+            None,
+            parse_out_path("curl-7.0  bin=/nix/store/bbb-curl-7.0-bin")
+        );
+        assert_eq!(
+            Some(1234567),
+            parse_closure_size("/nix/store/aaa-gzip-1.10\t   1234567\n")
+        );
+        assert_eq!(None, parse_closure_size(""));
+
+        assert_eq!("512 B", human_size(512));
+        assert_eq!("1.0 KiB", human_size(1024));
+        assert_eq!("1.5 MiB", human_size(1024 * 1536));
+        assert_eq!("2048.0 TiB", human_size(2048 << 40));
+        assert_eq!(
+            "size: 1.0 KiB\n",
+            ConsoleFormatSize::new(Some(1024), Styling::Plain).to_string()
+        );
+        assert_eq!(
+            "size: not in the Nix store\n",
+            ConsoleFormatSize::new(None, Styling::Plain).to_string()
+        );
+    }
+
+    #[test]
+    fn test_parse_priority_collision() {
+        assert_eq!(
+            Some(PriorityCollision {
+                names: vec!["gzip-1.10".to_string(), "gzip-1.11".to_string()],
+                priority: 5,
+            }),
+            parse_priority_collision(
+                "installing 'gzip-1.11'\n\
+                 building '/nix/store/xxx-user-environment.drv'...\n\
+                 error: packages '/nix/store/aaa-gzip-1.10/bin/gzip' and \
+                 '/nix/store/bbb-gzip-1.11/bin/gzip' have the same priority 5; use \
+                 'nix-env --set-flag priority NUMBER INSTALLED_PKGNAME' to change the \
+                 priority of one of the conflicting packages (0 being the highest priority)\n"
+            )
+        );
+        assert_eq!(
+            None,
+            parse_priority_collision("error: attribute 'gzipp' not found\n")
+        );
+    }
+
+    #[test]
+    fn test_reverse_deps() {
+        assert_eq!(
+            vec!["/nix/store/aaa-gzip-1.10"],
+            parse_out_paths("gzip-1.10  /nix/store/aaa-gzip-1.10")
+        );
+        assert_eq!(
+            vec!["/nix/store/bbb-curl-7.0-bin", "/nix/store/ccc-curl-7.0"],
+            parse_out_paths("curl-7.0  bin=/nix/store/bbb-curl-7.0-bin;/nix/store/ccc-curl-7.0")
+        );
+        assert_eq!("gzip-1.10", store_path_name("/nix/store/aaa-gzip-1.10"));
+
+        let cache =
+            "nixpkgs.curl    curl-7.0    A URL tool\nnixos.curl    curl-7.0    A URL tool\n";
+        assert_eq!(
+            vec![
+                Referrer {
+                    path: "/nix/store/bbb-curl-7.0-bin".to_string(),
+                    attrs: vec!["nixpkgs.curl".to_string(), "nixos.curl".to_string()],
+                },
+                Referrer {
+                    path: "/nix/store/ddd-unknown-1.0".to_string(),
+                    attrs: vec![],
+                },
+            ],
+            match_referrers(
+                cache,
+                vec![
+                    "/nix/store/bbb-curl-7.0-bin".to_string(),
+                    "/nix/store/ddd-unknown-1.0".to_string(),
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn test_channel_priority() {
+        let priority = DEFAULT_CHANNEL_PRIORITY;
+        assert_eq!(0, channel_priority("nixpkgs.gzip", priority));
+        assert_eq!(1, channel_priority("nixos.gzip", priority));
+        assert_eq!(2, channel_priority("unstable.gzip", priority));
+        assert_eq!(3, channel_priority("other.gzip", priority));
+
+        assert_eq!(
+            "nixpkgs.gzip    gzip-1.10\n\
+             nixos.gzip    gzip-1.10\n\
+             unstable.gzip    gzip-1.11\n\
+             nixos.hello    hello-2.10\n",
+            sort_attr_lines(
+                "unstable.gzip    gzip-1.11\n\
+                 nixos.hello    hello-2.10\n\
+                 nixos.gzip    gzip-1.10\n\
+                 nixpkgs.gzip    gzip-1.10\n",
+                priority
+            )
+        );
+        assert_eq!(
+            "unstable.gzip\nnixpkgs.gzip\n",
+            sort_attr_lines("nixpkgs.gzip\nunstable.gzip\n", &["unstable", "nixpkgs"])
+        );
+
+        assert_eq!(
+            "nixos.gzip    gzip-1.9\n\
+             nixpkgs.gzip    gzip-1.10\n\
+             unstable.gzip    gzip-1.10\n\
+             nixpkgs.gzip_1_11    gzip-1.11\n\
+             nixos.hello    hello-2.10\n",
+            sort_attr_lines_by_version(
+                "unstable.gzip    gzip-1.10\n\
+                 nixos.hello    hello-2.10\n\
+                 nixpkgs.gzip_1_11    gzip-1.11\n\
+                 nixpkgs.gzip    gzip-1.10\n\
+                 nixos.gzip    gzip-1.9\n",
+                priority
+            )
+        );
+    }
+
+    #[test]
+    fn test_package_status() {
+        let info = nix_query_json(
+            r#"{"nixpkgs.pkg": {
+            "name": "pkg-1.0",
+            "pname": "pkg",
+            "version": "1.0",
+            "meta": {"broken": true}
+        }}"#,
+        );
+        let status = PackageStatus::new("nixpkgs.pkg", Some(&info));
+        assert!(status.exists && status.available && status.broken);
+        assert!(!status.ok());
+        assert_eq!(
+            r#"{"attr":"nixpkgs.pkg","exists":true,"available":true,"broken":true,"version":"1.0"}"#,
+            serde_json::to_string(&status).unwrap()
+        );
+
+        let missing = PackageStatus::new("nixpkgs.nope", None);
+        assert!(!missing.exists && !missing.ok());
+        assert_eq!(None, missing.version);
+    }
+
+    #[test]
+    fn test_parse_query_all() {
+        let json = r#"{
+            "nixpkgs.pkg": {"name": "pkg-1.0", "pname": "pkg", "version": "1.0", "system": "x86_64-linux"},
+            "nixos.pkg": {"name": "pkg-1.0", "pname": "pkg", "version": "1.0", "system": "aarch64-linux"}
+        }"#;
+        let all = parse_query_all(json).unwrap();
+        assert_eq!(
+            vec![Some("nixos.pkg"), Some("nixpkgs.pkg")],
+            all.iter()
+                .map(|info| info.attr.as_deref())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some("aarch64-linux"),
+            first_info(parse_query_all(json).unwrap())
+                .unwrap()
+                .system
+                .as_deref()
+        );
+        assert!(parse_query_all("{}").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_batch_query() {
+        let batch = BatchQuery::parse(
+            &[
+                "nixpkgs.gzip",
+                "nixpkgs.nope",
+                "nixpkgs.gone",
+                "nixpkgs.hello",
+            ],
+            r#"{
+                "nixpkgs.hello": {"name": "hello-2.10", "pname": "hello", "version": "2.10"},
+                "nixpkgs.gzip": {"name": "gzip-1.10", "pname": "gzip", "version": "1.10"},
+                "nixpkgs.nope": null,
+                "nixpkgs.extra": {"name": "extra-1.0", "pname": "extra", "version": "1.0"}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                Some("nixpkgs.extra"),
+                Some("nixpkgs.gzip"),
+                Some("nixpkgs.hello")
+            ],
+            batch
+                .found
+                .iter()
+                .map(|info| info.attr.as_deref())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["nixpkgs.nope".to_string(), "nixpkgs.gone".to_string()],
+            batch.missing
+        );
+        match batch.into_first() {
+            Err(NixQueryError::Missing(missing)) => assert_eq!(2, missing.len()),
+            result => panic!("expected missing attributes, got {:?}", result),
+        }
+
+        let batch = BatchQuery::parse(
+            &["nixpkgs.gzip"],
+            r#"{"nixpkgs.gzip": {"name": "gzip-1.10", "pname": "gzip", "version": "1.10"}}"#,
+        )
+        .unwrap();
+        assert_eq!("gzip-1.10", batch.into_first().unwrap().name());
+    }
+
+    #[test]
+    fn test_nix_query_many_expr() {
+        let expr = nix_query_many_expr(
+            &["nixos.gzip", "nixpkgs.haskellPackages.a\"b", "nixos.hello"],
+            None,
+        );
+        assert!(expr.contains(
+            "  c0 = import (builtins.findFile builtins.nixPath \"nixos\") { };\n  \
+             c1 = import (builtins.findFile builtins.nixPath \"nixpkgs\") { };\n"
+        ));
+        assert!(expr.contains(
+            "in {\n  \
+             \"nixos.gzip\" = if c0 ? \"gzip\" then info c0.\"gzip\" else null;\n  \
+             \"nixpkgs.haskellPackages.a\\\"b\" = if c1 ? \"haskellPackages\".\"a\\\"b\" \
+             then info c1.\"haskellPackages\".\"a\\\"b\" else null;\n  \
+             \"nixos.hello\" = if c0 ? \"hello\" then info c0.\"hello\" else null;\n}"
+        ));
+        assert!(nix_query_many_expr(&["nixpkgs"], None).contains("  \"nixpkgs\" = info c0;\n"));
+        assert_eq!("\"\\${x} \\\\\"", nix_string("${x} \\"));
+    }
+
+    #[test]
+    fn test_channel_import() {
+        let defexpr =
+            env::temp_dir().join(format!("nix-query-test-defexpr-{}", std::process::id()));
+        fs::create_dir_all(defexpr.join("channels").join("nixpkgs")).unwrap();
+        fs::create_dir_all(defexpr.join("channels_root").join("nixpkgs")).unwrap();
+        fs::create_dir_all(defexpr.join("channels_root").join("nixos")).unwrap();
+        let user = defexpr.join("channels").join("nixpkgs");
+        let root = defexpr.join("channels_root").join("nixos");
+        assert_eq!(
+            format!("import {} {{ }}", nix_string(&user.to_string_lossy())),
+            channel_import("nixpkgs", Some(&defexpr))
+        );
+        assert_eq!(
+            format!("import {} {{ }}", nix_string(&root.to_string_lossy())),
+            channel_import("nixos", Some(&defexpr))
+        );
+        assert_eq!(
+            "import (builtins.findFile builtins.nixPath \"home-manager\") { }",
+            channel_import("home-manager", Some(&defexpr))
         );
+        fs::remove_dir_all(&defexpr).unwrap();
+    }
+
+    #[test]
+    fn test_nix_version() {
+        assert_eq!(Some("2.3.4"), parse_nix_version("nix-env (Nix) 2.3.4\n"));
+        assert!(!has_nix_command("2.3.16"));
+        assert!(has_nix_command("2.4"));
+        assert!(has_nix_command("2.18.1"));
+        assert!(has_nix_command("3.0pre20210101_abcdef"));
+        assert!(!has_nix_command(""));
     }
 }
@@ -1,7 +1,12 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
 use std::io;
-use std::io::BufRead;
-use std::process::{Command, ExitStatus};
+use std::io::{BufRead, Read, Write};
+use std::process::{Command, ExitStatus, Output, Stdio};
 use std::string::FromUtf8Error;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub enum CommandError {
@@ -10,6 +15,23 @@ pub enum CommandError {
     De(serde_json::Error),
     Encoding(FromUtf8Error),
     ExitStatus(ExitStatus),
+    /// The command was killed after running longer than this.
+    Timeout(Duration),
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Io(e) => write!(f, "Couldn't run the command: {}", e),
+            CommandError::Stderr(stderr) => write!(f, "{}", stderr.trim_end()),
+            CommandError::De(e) => write!(f, "Couldn't parse the command's output: {}", e),
+            CommandError::Encoding(e) => write!(f, "The command's output isn't UTF-8: {}", e),
+            CommandError::ExitStatus(status) => write!(f, "The command failed ({})", status),
+            CommandError::Timeout(timeout) => {
+                write!(f, "The command took longer than {}s", timeout.as_secs())
+            }
+        }
+    }
 }
 
 impl From<io::Error> for CommandError {
@@ -24,29 +46,114 @@ impl From<serde_json::Error> for CommandError {
     }
 }
 
-pub fn run_cmd<F, T>(c: &mut Command, f: F) -> Result<T, CommandError>
-where
-    F: FnOnce(Vec<u8>) -> T,
-{
+fn run_cmd_output(c: &mut Command) -> Result<Output, CommandError> {
     let output = c.output().map_err(Box::new).map_err(CommandError::Io)?;
 
     if !output.status.success() {
         return Err(CommandError::ExitStatus(output.status));
     }
 
+    Ok(output)
+}
+
+/// Reads all of a child's pipe on another thread, so a full pipe can't block
+/// the child while we wait for it.
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            // A read error just truncates the output.
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+/// Like `run_cmd_output`, but the command is killed if it runs for longer
+/// than `timeout`.
+fn run_cmd_output_timeout(c: &mut Command, timeout: Duration) -> Result<Output, CommandError> {
+    let mut child = c.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            // The child may have exited just now; either way, we're done.
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(CommandError::Timeout(timeout));
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    if !status.success() {
+        return Err(CommandError::ExitStatus(status));
+    }
+
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+/// A command's stdout, or an error if it wrote anything to stderr.
+fn stdout_if_quiet(output: Output) -> Result<Vec<u8>, CommandError> {
     if !output.stderr.is_empty() {
         return Err(CommandError::Stderr(
             String::from_utf8(output.stderr).map_err(CommandError::Encoding)?,
         ));
     }
+    Ok(output.stdout)
+}
+
+pub fn run_cmd<F, T>(c: &mut Command, f: F) -> Result<T, CommandError>
+where
+    F: FnOnce(Vec<u8>) -> T,
+{
+    Ok(f(stdout_if_quiet(run_cmd_output(c)?)?))
+}
 
-    Ok(f(output.stdout))
+/// Like `run_cmd`, but the exit codes in `accept` count as success too, for
+/// commands which exit with a nonzero code to mean "no results" rather than
+/// failure. Output on stderr is only an error if the command exits with 0.
+pub fn run_cmd_accept<F, T>(c: &mut Command, accept: &[i32], f: F) -> Result<T, CommandError>
+where
+    F: FnOnce(Vec<u8>) -> T,
+{
+    let output = c.output().map_err(Box::new).map_err(CommandError::Io)?;
+    match output.status.code() {
+        _ if output.status.success() => Ok(f(stdout_if_quiet(output)?)),
+        Some(code) if accept.contains(&code) => Ok(f(output.stdout)),
+        _ => Err(CommandError::ExitStatus(output.status)),
+    }
 }
 
 pub fn run_cmd_stdout(c: &mut Command) -> Result<String, CommandError> {
     run_cmd(c, String::from_utf8)?.map_err(CommandError::Encoding)
 }
 
+/// Like `run_cmd_stdout`, but fails with `CommandError::Timeout` if the
+/// command runs for longer than `timeout`.
+pub fn run_cmd_stdout_timeout(c: &mut Command, timeout: Duration) -> Result<String, CommandError> {
+    String::from_utf8(stdout_if_quiet(run_cmd_output_timeout(c, timeout)?)?)
+        .map_err(CommandError::Encoding)
+}
+
+/// Like `run_cmd_stdout`, but output on stderr is returned (as the second
+/// element) rather than treated as an error.
+pub fn run_cmd_stdout_stderr(c: &mut Command) -> Result<(String, String), CommandError> {
+    let output = run_cmd_output(c)?;
+    Ok((
+        String::from_utf8(output.stdout).map_err(CommandError::Encoding)?,
+        String::from_utf8(output.stderr).map_err(CommandError::Encoding)?,
+    ))
+}
+
 pub fn run_cmd_stdout_lines_capacity(
     c: &mut Command,
     lines_hint: usize,
@@ -64,3 +171,140 @@ pub fn run_cmd_stdout_lines_capacity(
 pub fn run_cmd_stdout_lines(c: &mut Command) -> Result<Vec<String>, CommandError> {
     run_cmd_stdout_lines_capacity(c, 64)
 }
+
+/// Runs a command attached to the terminal (e.g. an editor), waiting for it
+/// to exit.
+pub fn run_cmd_interactive(c: &mut Command) -> Result<(), CommandError> {
+    let status = c.status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(CommandError::ExitStatus(status))
+    }
+}
+
+/// Like `read_pipe`, but everything read is also copied to our stderr as it
+/// arrives, so progress is shown as it happens.
+fn tee_pipe_to_stderr<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let mut chunk = [0; 4096];
+            // A read error just truncates the output.
+            while let Ok(len) = pipe.read(&mut chunk) {
+                if len == 0 {
+                    break;
+                }
+                let _ = io::stderr().write_all(&chunk[..len]);
+                buf.extend_from_slice(&chunk[..len]);
+            }
+        }
+        buf
+    })
+}
+
+/// Like `run_cmd_interactive`, but stderr is captured as it's shown, so it
+/// can be inspected afterwards. If the command fails, its stderr is the
+/// error.
+pub fn run_cmd_interactive_stderr(c: &mut Command) -> Result<String, CommandError> {
+    let mut child = c.stderr(Stdio::piped()).spawn()?;
+    let stderr = tee_pipe_to_stderr(child.stderr.take());
+    let status = child.wait()?;
+    let stderr =
+        String::from_utf8(stderr.join().unwrap_or_default()).map_err(CommandError::Encoding)?;
+    if status.success() {
+        Ok(stderr)
+    } else {
+        Err(CommandError::Stderr(stderr))
+    }
+}
+
+/// Calls `f` on each item using at most `threads` threads, yielding each item
+/// and its result as soon as it's done. Results aren't in any particular
+/// order.
+pub fn parallel_map<T, R, F>(items: Vec<T>, threads: usize, f: F) -> impl Iterator<Item = (T, R)>
+where
+    T: Send + 'static,
+    R: Send + 'static,
+    F: Fn(&T) -> R + Send + Sync + 'static,
+{
+    let queue = Arc::new(Mutex::new(items.into_iter()));
+    let f = Arc::new(f);
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..threads.max(1) {
+        let queue = Arc::clone(&queue);
+        let f = Arc::clone(&f);
+        let tx = tx.clone();
+        thread::spawn(move || loop {
+            // Hold the lock just long enough to take the next item.
+            let item = match queue.lock().unwrap().next() {
+                Some(item) => item,
+                None => break,
+            };
+            let result = f(&item);
+            if tx.send((item, result)).is_err() {
+                break;
+            }
+        });
+    }
+    rx.into_iter()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_run_cmd_stdout_timeout() {
+        assert_eq!(
+            "hello\n",
+            run_cmd_stdout_timeout(Command::new("echo").arg("hello"), Duration::from_secs(10))
+                .unwrap()
+        );
+
+        let timeout = Duration::from_millis(100);
+        match run_cmd_stdout_timeout(Command::new("sleep").arg("10"), timeout) {
+            Err(CommandError::Timeout(t)) => assert_eq!(timeout, t),
+            other => panic!("Expected a timeout, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_run_cmd_accept() {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo partial; echo 'no results' >&2; exit 3"]);
+        assert_eq!(
+            b"partial\n".to_vec(),
+            run_cmd_accept(&mut cmd, &[1, 3], |stdout| stdout).unwrap()
+        );
+        match run_cmd_accept(&mut cmd, &[1], |stdout| stdout) {
+            Err(CommandError::ExitStatus(status)) => assert_eq!(Some(3), status.code()),
+            other => panic!("Expected an exit status error, got {:?}", other),
+        }
+        assert_eq!(
+            b"ok\n".to_vec(),
+            run_cmd_accept(Command::new("echo").arg("ok"), &[], |stdout| stdout).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_run_cmd_interactive_stderr() {
+        assert_eq!(
+            "warning\n",
+            run_cmd_interactive_stderr(Command::new("sh").args(["-c", "echo warning >&2"]))
+                .unwrap()
+        );
+        match run_cmd_interactive_stderr(Command::new("sh").args(["-c", "echo bad >&2; exit 1"])) {
+            Err(CommandError::Stderr(stderr)) => assert_eq!("bad\n", stderr),
+            other => panic!("Expected a stderr error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parallel_map() {
+        let mut results: Vec<_> = parallel_map((1..=20).collect(), 4, |n| n * 2).collect();
+        results.sort();
+        assert_eq!((1..=20).map(|n| (n, n * 2)).collect::<Vec<_>>(), results);
+        assert_eq!(0, parallel_map(Vec::<u8>::new(), 4, |n| *n).count());
+    }
+}
@@ -0,0 +1,105 @@
+//! The regexes used to parse Nix's output and render package information,
+//! compiled once on first use.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    /// A Markdown list item's marker, like `- ` or `  * `, capturing the
+    /// indentation.
+    pub static ref MARKDOWN_LIST_ITEM: Regex = Regex::new(r"(?m)^(\s*)[-*+]\s+").unwrap();
+
+    /// Markdown `` `code` ``, capturing the code.
+    pub static ref MARKDOWN_CODE: Regex = Regex::new(r"`([^`]+)`").unwrap();
+
+    /// Markdown `**bold**` or `__bold__`, capturing the text in the first or
+    /// second group, respectively.
+    pub static ref MARKDOWN_BOLD: Regex = Regex::new(r"\*\*([^*]+)\*\*|__([^_]+)__").unwrap();
+
+    /// The gap between columns of `nix-env --query` output.
+    pub static ref COLUMN_GAP: Regex = Regex::new(" {2,}").unwrap();
+
+    /// A line of `nix-env --query` output: an attribute, then a column gap and
+    /// a value.
+    pub static ref ATTR_LINE: Regex =
+        Regex::new(r#"^[A-Za-z_][\w'+-]*(\.[\w'+"-]+)* {2,}\S"#).unwrap();
+
+    /// nix-env's warning that it skipped a channel because of a name
+    /// collision, capturing the skipped path.
+    pub static ref NAME_COLLISION: Regex = Regex::new(
+        r"(?m)^warning: name collision in input Nix expressions, skipping '([^']*)'"
+    )
+    .unwrap();
+
+    /// `nix-env --install`'s error when two packages provide the same file,
+    /// capturing both files' paths and the packages' priority.
+    pub static ref PRIORITY_COLLISION: Regex =
+        Regex::new(r"packages '([^']*)' and '([^']*)' have the same priority (\d+)").unwrap();
+
+    /// A run of whitespace, including newlines.
+    pub static ref WHITESPACE: Regex = Regex::new(r"\s+").unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_markdown() {
+        assert_eq!(
+            Some("  "),
+            MARKDOWN_LIST_ITEM
+                .captures("intro\n  * item")
+                .map(|c| c.get(1).unwrap().as_str())
+        );
+        assert!(!MARKDOWN_LIST_ITEM.is_match("**not a list**"));
+
+        assert_eq!(
+            "ls -l",
+            &MARKDOWN_CODE.captures("run `ls -l` now").unwrap()[1]
+        );
+        assert!(!MARKDOWN_CODE.is_match("a `b"));
+
+        assert_eq!(
+            "bold",
+            &MARKDOWN_BOLD.captures("a **bold** word").unwrap()[1]
+        );
+        assert_eq!(
+            "bold",
+            &MARKDOWN_BOLD.captures("a __bold__ word").unwrap()[2]
+        );
+        assert!(!MARKDOWN_BOLD.is_match("a *light* word"));
+    }
+
+    #[test]
+    fn test_attr_lines() {
+        assert_eq!("a\tb\tc d", COLUMN_GAP.replace_all("a   b  c d", "\t"));
+
+        assert!(ATTR_LINE.is_match("nixpkgs.gzip    gzip-1.10    GNU zip"));
+        assert!(ATTR_LINE.is_match("hello  hello-2.10"));
+        assert!(!ATTR_LINE.is_match("(1/3) querying available packages..."));
+        assert!(!ATTR_LINE.is_match("nixpkgs.gzip gzip-1.10"));
+        assert!(!ATTR_LINE.is_match("nixpkgs.gzip    "));
+
+        assert_eq!("a b c", WHITESPACE.replace_all("a\n  b\tc", " "));
+    }
+
+    #[test]
+    fn test_nix_env_errors() {
+        assert_eq!(
+            "/home/user/.nix-defexpr/channels/nixpkgs",
+            &NAME_COLLISION
+                .captures("warning: name collision in input Nix expressions, skipping '/home/user/.nix-defexpr/channels/nixpkgs'")
+                .unwrap()[1]
+        );
+        assert!(!NAME_COLLISION.is_match("warning: something else entirely"));
+
+        let captures = PRIORITY_COLLISION
+            .captures("error: packages '/nix/store/a-gzip-1.10/bin/gzip' and '/nix/store/b-gzip-1.11/bin/gzip' have the same priority 5; use ...")
+            .unwrap();
+        assert_eq!("/nix/store/a-gzip-1.10/bin/gzip", &captures[1]);
+        assert_eq!("/nix/store/b-gzip-1.11/bin/gzip", &captures[2]);
+        assert_eq!("5", &captures[3]);
+        assert!(!PRIORITY_COLLISION.is_match("error: attribute 'gzip' missing"));
+    }
+}